@@ -0,0 +1,188 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
+use tokio::time::Instant;
+
+// Minimum time until a scheduled key update, so a clock on an epoch boundary can not
+// schedule back to back updates
+pub const MIN_KEY_UPDATE_DELAY: Seconds = Seconds(1);
+
+// Start of a key's validity, in seconds since the unix epoch
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Epoch(pub u64);
+
+// A duration in seconds, e.g. the key lifetime at runtime
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Seconds(pub u64);
+
+// A duration in minutes, e.g. the key lifetime in the config
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Minutes(pub u64);
+
+impl Epoch {
+    pub fn as_secs(self) -> u64 {
+        self.0
+    }
+
+    // The epoch following this one. The last representable epoch has no successor
+    pub fn next(self, key_lifetime: Seconds) -> Epoch {
+        Epoch(self.0.saturating_add(key_lifetime.0))
+    }
+}
+
+impl Seconds {
+    pub fn as_secs(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_duration(self) -> Duration {
+        Duration::from_secs(self.0)
+    }
+
+    // Rounded down
+    pub fn to_minutes(self) -> Minutes {
+        Minutes(self.0 / 60)
+    }
+}
+
+impl Minutes {
+    // None if the seconds do not fit into a u64
+    pub fn to_seconds(self) -> Option<Seconds> {
+        self.0.checked_mul(60).map(Seconds)
+    }
+}
+
+impl fmt::Display for Epoch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for Minutes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Seconds since the unix epoch. A clock set before 1970 is an error rather than a panic
+pub fn now() -> Result<u64, SystemTimeError> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+}
+
+// Start of the epoch containing now, with boundaries shifted by the offset
+pub fn current_epoch(now: u64, key_lifetime: Seconds, epoch_offset: Seconds) -> Epoch {
+    let key_lifetime = key_lifetime.as_secs();
+    let epoch_offset = epoch_offset.as_secs() % key_lifetime;
+
+    // Reduced first, so a clock near u64::MAX can not overflow
+    let since_epoch_start = (now % key_lifetime + (key_lifetime - epoch_offset)) % key_lifetime;
+
+    Epoch(now.saturating_sub(since_epoch_start))
+}
+
+// The current and the next epoch at now
+pub fn key_epochs(now: u64, key_lifetime: Seconds, epoch_offset: Seconds) -> (Epoch, Epoch) {
+    let current_epoch = current_epoch(now, key_lifetime, epoch_offset);
+
+    (current_epoch, current_epoch.next(key_lifetime))
+}
+
+// Instant of the next epoch boundary after now, at least MIN_KEY_UPDATE_DELAY away
+pub fn next_key_update_at(
+    now: u64,
+    now_instant: Instant,
+    key_lifetime: Seconds,
+    epoch_offset: Seconds,
+) -> Instant {
+    let (_, next_epoch) = key_epochs(now, key_lifetime, epoch_offset);
+
+    let min_delay = MIN_KEY_UPDATE_DELAY.as_duration();
+    let time_until_next_epoch =
+        Duration::from_secs(next_epoch.as_secs().saturating_sub(now)).max(min_delay);
+
+    now_instant
+        .checked_add(time_until_next_epoch)
+        .unwrap_or(now_instant + min_delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minutes_convert_to_seconds() {
+        assert_eq!(Minutes(0).to_seconds(), Some(Seconds(0)));
+        assert_eq!(Minutes(10).to_seconds(), Some(Seconds(600)));
+        assert_eq!(Minutes(u64::MAX).to_seconds(), None);
+    }
+
+    #[test]
+    fn seconds_convert_to_whole_minutes() {
+        assert_eq!(Seconds(600).to_minutes(), Minutes(10));
+        assert_eq!(Seconds(659).to_minutes(), Minutes(10));
+        assert_eq!(Seconds(59).to_minutes(), Minutes(0));
+        assert_eq!(Seconds(600).as_duration(), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn next_epoch_is_one_key_lifetime_later() {
+        assert_eq!(Epoch(1_200).next(Seconds(600)), Epoch(1_800));
+    }
+
+    fn current(now: u64, key_lifetime: u64, epoch_offset: u64) -> Epoch {
+        current_epoch(now, Seconds(key_lifetime), Seconds(epoch_offset))
+    }
+
+    #[test]
+    fn current_epoch_is_aligned_to_key_lifetime() {
+        assert_eq!(current(1_000, 600, 0), Epoch(600));
+        assert_eq!(current(1_200, 600, 0), Epoch(1_200));
+        assert_eq!(current(1_799, 600, 0), Epoch(1_200));
+    }
+
+    #[test]
+    fn current_epoch_is_shifted_by_offset() {
+        assert_eq!(current(1_000, 600, 100), Epoch(700));
+        assert_eq!(current(1_299, 600, 100), Epoch(700));
+        assert_eq!(current(1_300, 600, 100), Epoch(1_300));
+        assert_eq!(current(1_000, 600, 400), Epoch(1_000));
+        assert_eq!(current(999, 600, 400), Epoch(400));
+    }
+
+    #[test]
+    fn offset_wraps_around_key_lifetime() {
+        for now in [1_000, 1_299, 1_300, 12_345] {
+            assert_eq!(current(now, 600, 100), current(now, 600, 700));
+        }
+    }
+
+    #[test]
+    fn next_key_update_is_a_full_key_lifetime_away_on_a_boundary() {
+        let now_instant = Instant::now();
+        let next_key_update = next_key_update_at(1_200, now_instant, Seconds(600), Seconds(0));
+
+        assert_eq!(next_key_update, now_instant + Duration::from_secs(600));
+    }
+
+    #[test]
+    fn next_key_update_is_in_the_future_near_a_boundary() {
+        let now_instant = Instant::now();
+
+        let next_key_update = next_key_update_at(1_799, now_instant, Seconds(600), Seconds(0));
+        assert_eq!(next_key_update, now_instant + Duration::from_secs(1));
+
+        let next_key_update = next_key_update_at(1_299, now_instant, Seconds(600), Seconds(100));
+        assert_eq!(next_key_update, now_instant + Duration::from_secs(1));
+
+        // No later boundary is representable
+        let next_key_update = next_key_update_at(u64::MAX, now_instant, Seconds(600), Seconds(0));
+        assert!(next_key_update >= now_instant + MIN_KEY_UPDATE_DELAY.as_duration());
+    }
+}
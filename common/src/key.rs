@@ -0,0 +1,36 @@
+use ps_signatures::keys::{PsParams, PsPublicKey, PsSigningKey};
+use sha2::{Digest, Sha256};
+
+// Identifies the signature scheme and parameter generation of the keys. Must change
+// whenever new keys can not be used by older issuers or verified by older verifiers.
+// Keys stored without a scheme were generated with this scheme
+pub const KEY_SCHEME: &str = "ps-bls12_381-v1";
+
+// Bytes of the public key digest kept in a key fingerprint
+const KEY_FINGERPRINT_BYTES: usize = 8;
+
+// Serialization format of the ps_signatures keys. Must change whenever a ps_signatures
+// upgrade serializes keys that older versions can not deserialize, so incompatible keys are
+// rejected instead of misread
+pub const KEY_FORMAT_VERSION: u8 = 1;
+
+// Format of the keys stored or sent without a format version
+pub const LEGACY_KEY_FORMAT_VERSION: u8 = 1;
+
+// Key material of an epoch, as generated by the key manager and used by the issuers
+pub struct KeyMaterial {
+    pub params: PsParams,
+
+    pub signing_key: PsSigningKey,
+
+    pub public_key: PsPublicKey,
+
+    // Number of messages the signing key can sign
+    pub message_count: usize,
+}
+
+// Short identifier of a serialized public key, for confirming that services use the same
+// key without comparing the key bytes
+pub fn key_fingerprint(public_key: &[u8]) -> String {
+    hex::encode(&Sha256::digest(public_key)[..KEY_FINGERPRINT_BYTES])
+}
@@ -0,0 +1,6 @@
+#[macro_use]
+extern crate log;
+
+pub mod epoch;
+pub mod key;
+pub mod scheduler;
@@ -0,0 +1,42 @@
+use std::future::Future;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+// Handle on a background task, e.g. the key updates
+pub struct Scheduler {
+    // Reported if the task failed
+    name: &'static str,
+
+    shutdown: oneshot::Sender<()>,
+
+    handle: JoinHandle<()>,
+}
+
+impl Scheduler {
+    // Spawns the task, which is expected to return once the shutdown receiver resolves
+    pub fn spawn<F, T>(name: &'static str, task: F) -> Self
+    where
+        F: FnOnce(oneshot::Receiver<()>) -> T,
+        T: Future<Output = ()> + Send + 'static,
+    {
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+
+        let handle = tokio::spawn(task(shutdown_receiver));
+
+        Self {
+            name,
+            shutdown,
+            handle,
+        }
+    }
+
+    // Stop the task once it has completed its in-flight work
+    pub async fn shutdown(self) {
+        // The task may have already exited
+        let _ = self.shutdown.send(());
+
+        if let Err(e) = self.handle.await {
+            error!("{} failed. {:?}", self.name, e);
+        }
+    }
+}
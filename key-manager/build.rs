@@ -1,7 +1,35 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
 use tonic_build;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("./proto/key_manager_service.proto")?;
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
+    // The descriptor set is served by the reflection service
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("key_manager_descriptor.bin"))
+        .compile(
+            &[
+                "./proto/key_manager_service.proto",
+                "./proto/key_manager_admin_service.proto",
+            ],
+            &["./proto"],
+        )?;
+
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash());
 
     Ok(())
 }
+
+// Short hash of the built commit, "unknown" outside of a git checkout
+fn git_hash() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
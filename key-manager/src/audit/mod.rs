@@ -0,0 +1,66 @@
+use crate::error::KeyManagerError;
+use crate::error::KeyManagerError::ConfigError;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Tracing target of the audit events, e.g. RUST_LOG=info,audit=info
+pub const AUDIT_TARGET: &str = "audit";
+
+// Records which client retrieved which key. Key material must never be recorded
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    // Events are appended to the audit log file if a path is set
+    pub fn create(audit_log_path: &Option<String>) -> Result<Self, KeyManagerError> {
+        let file = match audit_log_path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| {
+                        ConfigError(format!("Could not open audit log '{}'. {}", path, e))
+                    })?;
+
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+
+        Ok(Self { file })
+    }
+
+    pub fn key_retrieval(&self, client: Option<&str>, realm: &str, epoch: u64, success: bool) {
+        let client = client.unwrap_or("unknown");
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+
+        tracing::info!(
+            target: AUDIT_TARGET,
+            client,
+            realm,
+            epoch,
+            timestamp,
+            success,
+            "Key retrieval"
+        );
+
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+
+            if let Err(e) = writeln!(
+                file,
+                "timestamp={} client={:?} realm={:?} epoch={} success={}",
+                timestamp, client, realm, epoch, success
+            ) {
+                error!("Could not write the audit log. {}", e);
+            }
+        }
+    }
+}
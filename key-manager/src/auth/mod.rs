@@ -0,0 +1,49 @@
+use tonic::{Request, Status};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+// Client certificates are optional during the TLS handshake so that clients without one
+// get a clear status instead of a connection reset
+pub fn intercept(request: Request<()>) -> Result<Request<()>, Status> {
+    let peer_certs = match request.peer_certs() {
+        Some(peer_certs) if !peer_certs.is_empty() => peer_certs,
+        _ => {
+            debug!("Rejected a request without a client certificate.");
+            return Err(Status::unauthenticated("client certificate required"));
+        }
+    };
+
+    // Audit which clients are connecting
+    match X509Certificate::from_der(peer_certs[0].get_ref()) {
+        Ok((_, cert)) => debug!("Accepted client certificate: {}", cert.subject()),
+        Err(e) => debug!("Could not parse client certificate. {:?}", e),
+    }
+
+    Ok(request)
+}
+
+// Client certificates are only checked if client authentication is enabled
+pub fn interceptor(
+    client_auth: bool,
+) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |request| {
+        if client_auth {
+            intercept(request)
+        } else {
+            Ok(request)
+        }
+    }
+}
+
+// Subject of the client certificate, e.g. "CN=token-issuer"
+pub fn client_subject<T>(request: &Request<T>) -> Option<String> {
+    let peer_certs = request.peer_certs()?;
+    let cert = peer_certs.first()?;
+
+    match X509Certificate::from_der(cert.get_ref()) {
+        Ok((_, cert)) => Some(cert.subject().to_string()),
+        Err(e) => {
+            debug!("Could not parse client certificate. {:?}", e);
+            None
+        }
+    }
+}
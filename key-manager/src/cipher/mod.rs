@@ -0,0 +1,120 @@
+use crate::error::KeyManagerError;
+use crate::error::KeyManagerError::{ConfigError, EncryptionError};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::fs;
+
+const ENCRYPTION_KEY_LENGTH: usize = 32;
+
+// Encrypted values are "<key id><nonce><ciphertext>". The key id is a prefix of the digest
+// of the encryption key, so the values of a previous key can be found and re-encrypted
+const KEY_ID_LENGTH: usize = 4;
+const NONCE_LENGTH: usize = 24;
+
+// Encrypts the key material at rest with a symmetric key read from a file
+pub struct KeyCipher {
+    key_id: [u8; KEY_ID_LENGTH],
+
+    cipher: XChaCha20Poly1305,
+}
+
+impl KeyCipher {
+    // The file holds the hex encoded 32 byte key
+    pub fn load(path: &str) -> Result<Self, KeyManagerError> {
+        let encoded = fs::read_to_string(path).map_err(|e| {
+            ConfigError(format!(
+                "Could not read the DB encryption key '{}'. {}",
+                path, e
+            ))
+        })?;
+
+        let key = hex::decode(encoded.trim()).map_err(|e| {
+            ConfigError(format!(
+                "DB encryption key '{}' is not hex encoded. {}",
+                path, e
+            ))
+        })?;
+
+        Self::new(&key)
+    }
+
+    pub fn new(key: &[u8]) -> Result<Self, KeyManagerError> {
+        if key.len() != ENCRYPTION_KEY_LENGTH {
+            return Err(ConfigError(format!(
+                "The DB encryption key must be {} bytes.",
+                ENCRYPTION_KEY_LENGTH
+            )));
+        }
+
+        let mut key_id = [0; KEY_ID_LENGTH];
+        key_id.copy_from_slice(&Sha256::digest(key)[..KEY_ID_LENGTH]);
+
+        Ok(Self {
+            key_id,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+        })
+    }
+
+    // The value is bound to its id in the key store, so it can not be swapped with the value
+    // of another entry
+    pub fn encrypt(&self, store_id: &[u8], value: &[u8]) -> Result<Vec<u8>, KeyManagerError> {
+        let mut nonce = [0; NONCE_LENGTH];
+        thread_rng().fill_bytes(&mut nonce);
+
+        let payload = Payload {
+            msg: value,
+            aad: store_id,
+        };
+
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| {
+                EncryptionError(format!(
+                    "Could not encrypt {}.",
+                    String::from_utf8_lossy(store_id)
+                ))
+            })?;
+
+        let mut encrypted = Vec::with_capacity(KEY_ID_LENGTH + NONCE_LENGTH + ciphertext.len());
+        encrypted.extend_from_slice(&self.key_id);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+
+        Ok(encrypted)
+    }
+
+    pub fn decrypt(&self, store_id: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, KeyManagerError> {
+        if !self.encrypted_with(encrypted) {
+            return Err(EncryptionError(format!(
+                "{} is not encrypted with the DB encryption key. Run --reencrypt-keys to \
+                 encrypt the stored keys.",
+                String::from_utf8_lossy(store_id)
+            )));
+        }
+
+        let (nonce, ciphertext) = encrypted[KEY_ID_LENGTH..].split_at(NONCE_LENGTH);
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: store_id,
+        };
+
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                EncryptionError(format!(
+                    "Could not decrypt {}.",
+                    String::from_utf8_lossy(store_id)
+                ))
+            })
+    }
+
+    // Whether the value carries the id of this key. The decryption still fails if it was not
+    // actually encrypted with it
+    pub fn encrypted_with(&self, value: &[u8]) -> bool {
+        value.len() > KEY_ID_LENGTH + NONCE_LENGTH && value[..KEY_ID_LENGTH] == self.key_id
+    }
+}
@@ -1,13 +1,34 @@
 use crate::error::KeyManagerError;
 use crate::error::KeyManagerError::ConfigError;
-use config::{Config, File};
-use serde::Deserialize;
+use config::{Config, Environment, File};
+use serde::{Deserialize, Serialize};
+use std::fs::File as FsFile;
 use std::net::IpAddr;
+use std::path::PathBuf;
+use tonic::codec::CompressionEncoding;
+use vt_common::epoch::{Minutes, Seconds};
+
+mod sample;
+
+pub use sample::sample_config;
 
 const CONFIG_ENV_VAR: &str = "VERONYMOUS_KEY_MANAGER_CONFIG";
 const DEFAULT_CONFIG_LOCATION: &str = "veronymous_key_manager_config.yml";
 
-#[derive(Clone, Debug, Deserialize)]
+// Searched for the config file, after the env var and before the working directory
+const USER_CONFIG_DIR: &str = "veronymous";
+const SYSTEM_CONFIG_DIR: &str = "/etc/veronymous";
+
+// Overrides the config file fields, e.g. VERONYMOUS_KEY_MANAGER_PORT. Nested fields are
+// separated with '__', e.g. VERONYMOUS_KEY_MANAGER_DB_OPTIONS__COMPRESSION
+const ENV_PREFIX: &str = "VERONYMOUS_KEY_MANAGER";
+const ENV_SEPARATOR: &str = "__";
+
+// Bounds of the key lifetime in minutes
+const MIN_KEY_LIFETIME: u64 = 1;
+const MAX_KEY_LIFETIME: u64 = 43200; // 30 days
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct KeyManagerConfig {
     pub host: IpAddr,
 
@@ -15,21 +36,250 @@ pub struct KeyManagerConfig {
 
     pub tls_key: String,
 
+    // Encoding of the TLS private key
+    #[serde(default)]
+    pub tls_key_format: TlsKeyFormat,
+
     pub tls_cert: String,
 
-    // Client ca for tls authentication
-    pub client_ca: String,
+    // Client ca for tls authentication. Client authentication is disabled if not set
+    pub client_ca: Option<String>,
+
+    // Set to false to accept clients without a certificate, e.g. for local development
+    #[serde(default = "default_require_client_auth")]
+    pub require_client_auth: bool,
+
+    // Address of the admin listener. Defaults to the host
+    pub admin_host: Option<IpAddr>,
+
+    // Port of the admin listener, serving the admin service and health checks. The admin
+    // listener is disabled if not set
+    pub admin_port: Option<u16>,
+
+    // CA of the admin client certificates, required by the admin service
+    pub admin_client_ca: Option<String>,
+
+    // Maximum size in bytes of a received gRPC message
+    #[serde(default = "default_max_decoding_message_size")]
+    pub max_decoding_message_size: usize,
+
+    // Maximum size in bytes of a sent gRPC message, e.g. a batch of keys
+    #[serde(default = "default_max_encoding_message_size")]
+    pub max_encoding_message_size: usize,
+
+    // Compression of the gRPC messages, for bandwidth-constrained links
+    #[serde(default)]
+    pub grpc_compression: GrpcCompression,
 
     pub key_file: String,
 
     pub key_lifetime: u64,
+
+    // Shift of the epoch boundaries in seconds
+    #[serde(default)]
+    pub epoch_offset: u64,
+
+    // Number of epochs after the current one to provision keys for
+    #[serde(default = "default_prefetch_epochs")]
+    pub prefetch_epochs: u64,
+
+    // Seconds before the epoch boundary at which the keys of the next epochs are
+    // provisioned. Does not change which epoch is current
+    #[serde(default = "default_provision_lead_seconds")]
+    pub provision_lead_seconds: u64,
+
+    // Number of past epochs to keep keys for. Keys are kept forever if not set
+    pub retention_epochs: Option<u64>,
+
+    // Number of messages the issuing keys can sign
+    #[serde(default = "default_message_count")]
+    pub message_count: usize,
+
+    // Hex encoded key generation seed (at least 32 bytes). Makes the keys of every
+    // epoch reproducible: anyone holding the seed can derive all signing keys, so it
+    // must be protected like the keys themselves. Keys are random if not set
+    pub seed: Option<String>,
+
+    // Issue and verify a token with every generated key before storing it
+    #[serde(default = "default_verify_on_provision")]
+    pub verify_on_provision: bool,
+
+    // Keys generated per epoch until one passes the self-test
+    #[serde(default = "default_key_generation_attempts")]
+    pub key_generation_attempts: u8,
+
+    // Directory of pre-generated keys, imported instead of generating keys
+    pub imported_keys_dir: Option<String>,
+
+    // Named realms served in addition to the default realm, each with its own keys
+    #[serde(default)]
+    pub realms: Vec<String>,
+
+    // Epochs whose keys must no longer be served
+    #[serde(default)]
+    pub revoked_epochs: Vec<RevokedEpoch>,
+
+    // Receives a POST request whenever the keys rotate to a new epoch
+    pub rotation_webhook_url: Option<String>,
+
+    // Warn when the TLS certificate expires within this many days
+    #[serde(default = "default_cert_expiry_warn_days")]
+    pub cert_expiry_warn_days: u64,
+
+    #[serde(default)]
+    pub log_format: LogFormat,
+
+    // Log filter directives, e.g. "info". Defaults to the RUST_LOG environment variable
+    pub log_level: Option<String>,
+
+    // Consecutive key update failures before reporting the service as not serving
+    #[serde(default = "default_health_failure_threshold")]
+    pub health_failure_threshold: u64,
+
+    // Number of async runtime worker threads
+    #[serde(default = "default_worker_threads")]
+    pub worker_threads: usize,
+
+    // Seconds a request may take before it is cancelled
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+
+    // Seconds between HTTP/2 pings on idle connections. No pings if not set
+    pub http2_keepalive_interval: Option<u64>,
+
+    // Seconds to wait for a ping acknowledgement before closing the connection
+    #[serde(default = "default_http2_keepalive_timeout")]
+    pub http2_keepalive_timeout: u64,
+
+    // Seconds of idleness before TCP keepalive probes are sent. Disabled if not set
+    pub tcp_keepalive: Option<u64>,
+
+    #[serde(default)]
+    pub db_options: DbOptions,
+
+    // Sync every key store write to disk before it completes, so keys survive a power
+    // failure. Slows down writes, which only happen on provisioning and revocation
+    #[serde(default = "default_sync_writes")]
+    pub sync_writes: bool,
+
+    // File of the key encrypting the key material before it is stored. Stored unencrypted
+    // if not set
+    pub db_encryption_key: Option<String>,
+
+    // Directory of the scheduled key store backups. Backups are disabled if not set
+    pub backup_dir: Option<String>,
+
+    // Minutes between scheduled backups
+    #[serde(default = "default_backup_interval")]
+    pub backup_interval: u64,
+
+    // Number of backups kept in the backup directory
+    #[serde(default = "default_backups_to_keep")]
+    pub backups_to_keep: usize,
+
+    // Append-only file recording every key retrieval. Retrievals are always logged to the
+    // 'audit' tracing target
+    pub audit_log_path: Option<String>,
+
+    // Serve and connect without TLS, for CI only. Ignored unless built with the 'insecure'
+    // feature
+    #[serde(default)]
+    pub insecure: bool,
+
+    // Path the config was loaded from
+    #[serde(skip)]
+    pub config_location: String,
+}
+
+fn default_require_client_auth() -> bool {
+    true
+}
+
+fn default_sync_writes() -> bool {
+    true
+}
+
+fn default_backup_interval() -> u64 {
+    60
+}
+
+fn default_backups_to_keep() -> usize {
+    24
+}
+
+fn default_max_decoding_message_size() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_max_encoding_message_size() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_prefetch_epochs() -> u64 {
+    1
+}
+
+fn default_provision_lead_seconds() -> u64 {
+    60
+}
+
+fn default_message_count() -> usize {
+    1
+}
+
+fn default_health_failure_threshold() -> u64 {
+    3
+}
+
+fn default_worker_threads() -> usize {
+    2
+}
+
+fn default_request_timeout() -> u64 {
+    30
+}
+
+fn default_http2_keepalive_timeout() -> u64 {
+    20
+}
+
+fn default_cert_expiry_warn_days() -> u64 {
+    30
+}
+
+fn default_verify_on_provision() -> bool {
+    true
+}
+
+fn default_key_generation_attempts() -> u8 {
+    3
+}
+
+// RocksDB tuning
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DbOptions {
+    pub max_open_files: Option<i32>,
+
+    pub write_buffer_size: Option<usize>,
+
+    pub compression: Option<DbCompression>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DbCompression {
+    None,
+    Snappy,
+    Zlib,
+    Bz2,
+    Lz4,
+    Lz4hc,
+    Zstd,
 }
 
 impl KeyManagerConfig {
     pub fn load() -> Result<Self, KeyManagerError> {
-        // Get the config location
-        let config_location =
-            std::env::var(CONFIG_ENV_VAR).unwrap_or_else(|_| DEFAULT_CONFIG_LOCATION.into());
+        let config_location = config_location();
 
         // Load the config
         let mut config = Config::new();
@@ -37,8 +287,356 @@ impl KeyManagerConfig {
             .merge(File::with_name(&config_location))
             .map_err(|e| ConfigError(format!("{:?}", e)))?;
 
-        Ok(config
-            .try_into()
-            .map_err(|e| ConfigError(format!("{:?}", e)))?)
+        // Environment variables take precedence over the file
+        config
+            .merge(Environment::with_prefix(ENV_PREFIX).separator(ENV_SEPARATOR))
+            .map_err(|e| ConfigError(format!("{:?}", e)))?;
+
+        let mut config: Self = config.try_into().map_err(|e| ConfigError(format!("{}", e)))?;
+        config.config_location = config_location;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // Key lifetime in seconds
+    pub fn key_lifetime_seconds(&self) -> Result<Seconds, KeyManagerError> {
+        if !(MIN_KEY_LIFETIME..=MAX_KEY_LIFETIME).contains(&self.key_lifetime) {
+            return Err(ConfigError(format!(
+                "'key_lifetime' must be between {} and {} minutes.",
+                MIN_KEY_LIFETIME, MAX_KEY_LIFETIME
+            )));
+        }
+
+        Minutes(self.key_lifetime)
+            .to_seconds()
+            .ok_or_else(|| ConfigError(format!("'key_lifetime' is too large.")))
+    }
+
+    // Release builds never run without TLS, whatever the config says
+    #[cfg(feature = "insecure")]
+    pub fn is_insecure(&self) -> bool {
+        self.insecure
+    }
+
+    #[cfg(not(feature = "insecure"))]
+    pub fn is_insecure(&self) -> bool {
+        false
+    }
+
+    // CA of the client certificates, if client authentication is enabled
+    pub fn client_auth_ca(&self) -> Option<&str> {
+        match &self.client_ca {
+            Some(client_ca) if self.require_client_auth => Some(client_ca),
+            _ => None,
+        }
+    }
+
+    // Logs the settings that matter when diagnosing an incident. Secrets are never logged,
+    // only the paths they are read from or whether they are set
+    pub fn log_effective_config(&self) {
+        tracing::info!(
+            host = %self.host,
+            port = self.port,
+            admin_host = %self.admin_host.unwrap_or(self.host),
+            admin_port = ?self.admin_port,
+            tls_cert = %self.tls_cert,
+            tls_key = %self.tls_key,
+            client_ca = ?self.client_ca,
+            client_auth_required = self.client_auth_ca().is_some(),
+            insecure = self.is_insecure(),
+            key_file = %self.key_file,
+            db_encryption_key = ?self.db_encryption_key,
+            key_lifetime_minutes = self.key_lifetime,
+            epoch_offset = self.epoch_offset,
+            prefetch_epochs = self.prefetch_epochs,
+            retention_epochs = ?self.retention_epochs,
+            realms = ?self.realms,
+            seed_set = self.seed.is_some(),
+            imported_keys_dir = ?self.imported_keys_dir,
+            rotation_webhook_set = self.rotation_webhook_url.is_some(),
+            backup_dir = ?self.backup_dir,
+            audit_log_path = ?self.audit_log_path,
+            "Effective config"
+        );
+    }
+
+    // Fields that can not be changed without a restart
+    pub fn changed_static_fields(&self, other: &Self) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+
+        if self.host != other.host {
+            changed.push("host");
+        }
+        if self.port != other.port {
+            changed.push("port");
+        }
+        if self.tls_key != other.tls_key {
+            changed.push("tls_key");
+        }
+        if self.tls_key_format != other.tls_key_format {
+            changed.push("tls_key_format");
+        }
+        if self.tls_cert != other.tls_cert {
+            changed.push("tls_cert");
+        }
+        if self.client_ca != other.client_ca {
+            changed.push("client_ca");
+        }
+        if self.require_client_auth != other.require_client_auth {
+            changed.push("require_client_auth");
+        }
+        if self.admin_host != other.admin_host {
+            changed.push("admin_host");
+        }
+        if self.admin_port != other.admin_port {
+            changed.push("admin_port");
+        }
+        if self.admin_client_ca != other.admin_client_ca {
+            changed.push("admin_client_ca");
+        }
+        if self.max_decoding_message_size != other.max_decoding_message_size {
+            changed.push("max_decoding_message_size");
+        }
+        if self.max_encoding_message_size != other.max_encoding_message_size {
+            changed.push("max_encoding_message_size");
+        }
+        if self.grpc_compression != other.grpc_compression {
+            changed.push("grpc_compression");
+        }
+        if self.key_file != other.key_file {
+            changed.push("key_file");
+        }
+        if self.message_count != other.message_count {
+            changed.push("message_count");
+        }
+        if self.seed != other.seed {
+            changed.push("seed");
+        }
+        if self.realms != other.realms {
+            changed.push("realms");
+        }
+        if self.cert_expiry_warn_days != other.cert_expiry_warn_days {
+            changed.push("cert_expiry_warn_days");
+        }
+        if self.key_lifetime != other.key_lifetime {
+            changed.push("key_lifetime");
+        }
+        if self.worker_threads != other.worker_threads {
+            changed.push("worker_threads");
+        }
+        if self.request_timeout != other.request_timeout {
+            changed.push("request_timeout");
+        }
+        if self.http2_keepalive_interval != other.http2_keepalive_interval {
+            changed.push("http2_keepalive_interval");
+        }
+        if self.http2_keepalive_timeout != other.http2_keepalive_timeout {
+            changed.push("http2_keepalive_timeout");
+        }
+        if self.tcp_keepalive != other.tcp_keepalive {
+            changed.push("tcp_keepalive");
+        }
+        if self.log_format != other.log_format {
+            changed.push("log_format");
+        }
+        if self.sync_writes != other.sync_writes {
+            changed.push("sync_writes");
+        }
+        if self.db_encryption_key != other.db_encryption_key {
+            changed.push("db_encryption_key");
+        }
+        if self.backup_dir != other.backup_dir {
+            changed.push("backup_dir");
+        }
+        if self.backup_interval != other.backup_interval {
+            changed.push("backup_interval");
+        }
+        if self.backups_to_keep != other.backups_to_keep {
+            changed.push("backups_to_keep");
+        }
+        if self.provision_lead_seconds != other.provision_lead_seconds {
+            changed.push("provision_lead_seconds");
+        }
+        if self.audit_log_path != other.audit_log_path {
+            changed.push("audit_log_path");
+        }
+
+        changed
     }
+
+    fn validate_realms(&self) -> Result<(), KeyManagerError> {
+        for (i, realm) in self.realms.iter().enumerate() {
+            if !is_valid_realm(realm) {
+                return Err(ConfigError(format!(
+                    "Realm '{}' must only contain letters, digits, '-' and '_'.",
+                    realm
+                )));
+            }
+
+            if self.realms[..i].contains(realm) {
+                return Err(ConfigError(format!("Realm '{}' is listed twice.", realm)));
+            }
+        }
+
+        for revoked_epoch in &self.revoked_epochs {
+            if !revoked_epoch.realm.is_empty() && !self.realms.contains(&revoked_epoch.realm) {
+                return Err(ConfigError(format!(
+                    "Revoked epoch {} is of the unknown realm '{}'.",
+                    revoked_epoch.epoch, revoked_epoch.realm
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), KeyManagerError> {
+        if self.port == 0 {
+            return Err(ConfigError(format!("'port' must not be 0.")));
+        }
+
+        if self.worker_threads == 0 {
+            return Err(ConfigError(format!("'worker_threads' must be positive.")));
+        }
+
+        if self.key_generation_attempts == 0 {
+            return Err(ConfigError(format!("'key_generation_attempts' must be positive.")));
+        }
+
+        self.validate_realms()?;
+
+        if self.request_timeout == 0 || self.http2_keepalive_timeout == 0 {
+            return Err(ConfigError(format!("Server timeouts must be positive.")));
+        }
+
+        let key_lifetime = self.key_lifetime_seconds()?;
+
+        if Seconds(self.provision_lead_seconds) >= key_lifetime {
+            return Err(ConfigError(format!(
+                "'provision_lead_seconds' must be shorter than the key lifetime."
+            )));
+        }
+
+        if self.max_decoding_message_size == 0 || self.max_encoding_message_size == 0 {
+            return Err(ConfigError(format!("Message size limits must be positive.")));
+        }
+
+        if self.backup_dir.is_some() && (self.backup_interval == 0 || self.backups_to_keep == 0) {
+            return Err(ConfigError(format!(
+                "'backup_interval' and 'backups_to_keep' must be positive."
+            )));
+        }
+
+        if let Some(db_encryption_key) = &self.db_encryption_key {
+            validate_file("db_encryption_key", db_encryption_key)?;
+        }
+
+        // No certificates are read without TLS
+        if self.is_insecure() {
+            return Ok(());
+        }
+
+        validate_file("tls_key", &self.tls_key)?;
+        validate_file("tls_cert", &self.tls_cert)?;
+        if let Some(client_ca) = &self.client_ca {
+            validate_file("client_ca", client_ca)?;
+        }
+
+        if self.admin_port.is_some() {
+            match &self.admin_client_ca {
+                Some(admin_client_ca) => validate_file("admin_client_ca", admin_client_ca)?,
+                None => {
+                    return Err(ConfigError(format!(
+                        "'admin_client_ca' is required by the admin service."
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RevokedEpoch {
+    // The default realm if not set
+    #[serde(default)]
+    pub realm: String,
+
+    pub epoch: u64,
+
+    pub reason: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GrpcCompression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl GrpcCompression {
+    pub fn encoding(self) -> Option<CompressionEncoding> {
+        match self {
+            GrpcCompression::None => None,
+            GrpcCompression::Gzip => Some(CompressionEncoding::Gzip),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Plain,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsKeyFormat {
+    // Detect PEM or DER from the contents
+    #[default]
+    Auto,
+    Pem,
+    // DER encoded PKCS#8
+    Der,
+}
+
+// The env var, then the user and system config dirs, then the working directory
+fn config_location() -> String {
+    if let Ok(config_location) = std::env::var(CONFIG_ENV_VAR) {
+        return config_location;
+    }
+
+    let user_config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    user_config_dir
+        .map(|user_config_dir| user_config_dir.join(USER_CONFIG_DIR))
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(SYSTEM_CONFIG_DIR)))
+        .map(|config_dir| config_dir.join(DEFAULT_CONFIG_LOCATION))
+        .find(|config_location| config_location.is_file())
+        .map(|config_location| config_location.to_string_lossy().into_owned())
+        .unwrap_or_else(|| DEFAULT_CONFIG_LOCATION.into())
+}
+
+// Realm names prefix the key ids, so they are limited to a safe character set
+fn is_valid_realm(realm: &str) -> bool {
+    !realm.is_empty()
+        && realm
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Make sure the file at the path exists and is readable
+fn validate_file(field: &str, path: &str) -> Result<(), KeyManagerError> {
+    FsFile::open(path)
+        .map(|_| ())
+        .map_err(|e| ConfigError(format!("'{}' file '{}' is not readable. {}", field, path, e)))
 }
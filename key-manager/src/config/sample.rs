@@ -0,0 +1,301 @@
+use super::KeyManagerConfig;
+use crate::error::KeyManagerError;
+use crate::error::KeyManagerError::ConfigError;
+use config::{Config, File, FileFormat};
+use serde_yaml::{Mapping, Value};
+
+const HEADER: &str = "\
+# Sample key manager config. Save it as veronymous_key_manager_config.yml or point
+# VERONYMOUS_KEY_MANAGER_CONFIG at it. Commented out fields are not set by default
+";
+
+// Placeholders of the fields without a default. Every other field gets its default
+const REQUIRED_FIELDS: &str = "
+host: 127.0.0.1
+port: 30051
+tls_key: ./certs/tls/server.key
+tls_cert: ./certs/tls/server.pem
+key_file: keys.db
+key_lifetime: 10
+";
+
+// Placeholders of the fields that are not set by default, printed commented out
+const OPTIONAL_FIELDS: &[(&str, &str)] = &[
+    ("client_ca", "./certs/auth/auth_ca.pem"),
+    ("admin_host", "10.0.0.1"),
+    ("admin_port", "30052"),
+    ("admin_client_ca", "./certs/admin/admin_ca.pem"),
+    ("retention_epochs", "144"),
+    ("seed", "<64 hex characters>"),
+    ("imported_keys_dir", "./imported_keys"),
+    ("realms", "\n  - vpn\n  - mail"),
+    ("revoked_epochs", "\n  - epoch: 1672531200\n    reason: Key compromise"),
+    ("rotation_webhook_url", "https://example.com/key-rotation"),
+    ("log_level", "info"),
+    ("http2_keepalive_interval", "60"),
+    ("tcp_keepalive", "60"),
+    (
+        "db_options",
+        "\n  max_open_files: 512\n  write_buffer_size: 67108864\n  compression: lz4",
+    ),
+    ("db_encryption_key", "./certs/db/db_encryption.key"),
+    ("backup_dir", "./backups"),
+    ("audit_log_path", "./audit.log"),
+];
+
+// Printed above the fields
+const DESCRIPTIONS: &[(&str, &str)] = &[
+    ("host", "Address to listen on"),
+    ("port", "Port of the key manager service"),
+    ("tls_key", "Private key of the TLS certificate"),
+    ("tls_key_format", "Private key encoding: auto, pem or der (PKCS#8)"),
+    (
+        "tls_cert",
+        "TLS certificate. The TLS files are reloaded when they change",
+    ),
+    (
+        "client_ca",
+        "CA of the client certificates. Client authentication is disabled if not set",
+    ),
+    (
+        "require_client_auth",
+        "Set to false to accept clients without a certificate, for local development only",
+    ),
+    ("admin_host", "Address of the admin listener. Defaults to the host"),
+    (
+        "admin_port",
+        "Port of the admin listener, serving the admin service and health checks.\n\
+         Disabled if not set",
+    ),
+    (
+        "admin_client_ca",
+        "CA of the admin client certificates, required by the admin listener",
+    ),
+    ("max_decoding_message_size", "Maximum size in bytes of a received gRPC message"),
+    ("max_encoding_message_size", "Maximum size in bytes of a sent gRPC message"),
+    ("grpc_compression", "Compression of the gRPC messages: none or gzip"),
+    ("key_file", "Path of the key store"),
+    (
+        "key_lifetime",
+        "Key lifetime in MINUTES, between 1 and 43200 (30 days). Run --migrate-lifetime\n\
+         <minutes> with the key manager stopped to change it",
+    ),
+    ("epoch_offset", "Shift of the epoch boundaries in seconds"),
+    ("prefetch_epochs", "Number of upcoming epochs to provision keys for"),
+    (
+        "provision_lead_seconds",
+        "Seconds before the epoch boundary to provision the keys of the next epochs.\n\
+         Must be shorter than the key lifetime",
+    ),
+    (
+        "retention_epochs",
+        "Number of past epochs to keep keys for. Kept forever if not set",
+    ),
+    ("message_count", "Number of messages the issuing keys can sign"),
+    (
+        "seed",
+        "Hex encoded key generation seed (at least 32 bytes) for reproducible keys.\n\
+         HIGHLY SENSITIVE: the seed derives every signing key. Keys are random if not set",
+    ),
+    (
+        "verify_on_provision",
+        "Issue and verify a token with every generated key before storing it",
+    ),
+    (
+        "key_generation_attempts",
+        "Keys generated per epoch until one passes the verification",
+    ),
+    (
+        "imported_keys_dir",
+        "Directory of pre-generated keys, one <epoch>.keys file per epoch",
+    ),
+    (
+        "realms",
+        "Named realms served in addition to the default realm, each with its own keys",
+    ),
+    (
+        "revoked_epochs",
+        "Epochs whose keys must no longer be served. The default realm if no realm is set",
+    ),
+    (
+        "rotation_webhook_url",
+        "Notified with the new epoch and public key when the keys rotate",
+    ),
+    (
+        "cert_expiry_warn_days",
+        "Warn when the TLS certificate expires within this many days",
+    ),
+    ("log_format", "Log format: plain or json"),
+    ("log_level", "Log filter directives. Defaults to RUST_LOG"),
+    (
+        "health_failure_threshold",
+        "Consecutive key update failures before reporting not serving",
+    ),
+    ("worker_threads", "Number of async runtime worker threads"),
+    ("request_timeout", "Seconds a request may take before it is cancelled"),
+    (
+        "http2_keepalive_interval",
+        "Seconds between HTTP/2 pings on idle connections. No pings if not set",
+    ),
+    (
+        "http2_keepalive_timeout",
+        "Seconds to wait for a ping acknowledgement before closing the connection",
+    ),
+    (
+        "tcp_keepalive",
+        "Seconds of idleness before TCP keepalive probes are sent. Disabled if not set",
+    ),
+    ("db_options", "RocksDB tuning"),
+    (
+        "sync_writes",
+        "Sync every key store write to disk before it completes",
+    ),
+    (
+        "db_encryption_key",
+        "File of the hex encoded 32 byte key encrypting the stored key material. Run\n\
+         --reencrypt-keys [<previous key file>] after changing it. Unencrypted if not set",
+    ),
+    (
+        "backup_dir",
+        "Directory of the scheduled key store backups. Disabled if not set",
+    ),
+    ("backup_interval", "Minutes between scheduled backups"),
+    ("backups_to_keep", "Number of backups kept in the backup directory"),
+    (
+        "audit_log_path",
+        "Append-only file recording every key retrieval",
+    ),
+    (
+        "insecure",
+        "Serve without TLS, for CI only. Ignored unless built with the 'insecure' feature",
+    ),
+];
+
+// Every field of the config with its description, the defaults filled in by serde
+pub fn sample_config() -> Result<String, KeyManagerError> {
+    let mut config = Config::new();
+    config
+        .merge(File::from_str(REQUIRED_FIELDS, FileFormat::Yaml))
+        .map_err(|e| ConfigError(format!("{:?}", e)))?;
+
+    let config: KeyManagerConfig = config
+        .try_into()
+        .map_err(|e| ConfigError(format!("{}", e)))?;
+
+    let fields = match serde_yaml::to_value(&config) {
+        Ok(Value::Mapping(fields)) => fields,
+        Ok(_) => return Err(ConfigError(format!("The config is not a mapping."))),
+        Err(e) => return Err(ConfigError(format!("Could not serialize the config. {}", e))),
+    };
+
+    let mut sample = HEADER.to_string();
+
+    for (field, value) in fields {
+        let field = field
+            .as_str()
+            .ok_or_else(|| ConfigError(format!("Config field {:?} is not a string.", field)))?;
+
+        sample.push('\n');
+        write_field(&mut sample, field, value)?;
+    }
+
+    Ok(sample)
+}
+
+fn write_field(sample: &mut String, field: &str, value: Value) -> Result<(), KeyManagerError> {
+    if let Some(description) = lookup(DESCRIPTIONS, field) {
+        for line in description.lines() {
+            sample.push_str(&format!("# {}\n", line.trim()));
+        }
+    }
+
+    if is_unset(&value) {
+        let placeholder = lookup(OPTIONAL_FIELDS, field).unwrap_or_default();
+
+        for line in format!("{}: {}", field, placeholder).lines() {
+            sample.push_str(&format!("#{}\n", line.trim_end()));
+        }
+
+        return Ok(());
+    }
+
+    let mut mapping = Mapping::new();
+    mapping.insert(Value::String(field.to_string()), value);
+
+    let yaml = serde_yaml::to_string(&mapping)
+        .map_err(|e| ConfigError(format!("Could not serialize '{}'. {}", field, e)))?;
+    sample.push_str(&yaml);
+
+    Ok(())
+}
+
+// Not set, empty, or only made of fields that are not set
+fn is_unset(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(value) => value.is_empty(),
+        Value::Sequence(values) => values.is_empty(),
+        Value::Mapping(values) => values.values().all(is_unset),
+        _ => false,
+    }
+}
+
+fn lookup(entries: &[(&str, &'static str)], field: &str) -> Option<&'static str> {
+    entries
+        .iter()
+        .find(|(name, _)| *name == field)
+        .map(|(_, entry)| *entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(sample: &str) -> KeyManagerConfig {
+        let mut config = Config::new();
+        config
+            .merge(File::from_str(sample, FileFormat::Yaml))
+            .unwrap();
+
+        config.try_into().unwrap()
+    }
+
+    #[test]
+    fn sample_config_describes_every_field() {
+        let sample = sample_config().unwrap();
+
+        for line in sample.lines() {
+            let field = match line.trim_start_matches('#').split_once(':') {
+                Some((field, _)) if !line.starts_with("# ") && !field.starts_with(' ') => field,
+                _ => continue,
+            };
+
+            assert!(lookup(DESCRIPTIONS, field).is_some(), "'{}' is not described", field);
+        }
+
+        assert!(sample.contains("\nkey_lifetime: 10\n"));
+        assert!(sample.contains("\n#seed: <64 hex characters>\n"));
+    }
+
+    #[test]
+    fn sample_config_parses() {
+        let sample = sample_config().unwrap();
+
+        let config = parse(&sample);
+        assert_eq!(config.key_lifetime, 10);
+        assert_eq!(config.seed, None);
+        assert!(config.realms.is_empty());
+
+        // With the placeholders of the optional fields set
+        let uncommented: Vec<&str> = sample
+            .lines()
+            .filter(|line| !line.starts_with("# "))
+            .map(|line| line.trim_start_matches('#'))
+            .collect();
+
+        let config = parse(&uncommented.join("\n"));
+        assert_eq!(config.realms, vec!["vpn", "mail"]);
+        assert_eq!(config.revoked_epochs[0].epoch, 1672531200);
+        assert_eq!(config.db_options.max_open_files, Some(512));
+    }
+}
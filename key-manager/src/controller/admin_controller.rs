@@ -0,0 +1,96 @@
+use crate::grpc::key_manager_admin_service::key_manager_admin_service_server::KeyManagerAdminService;
+use crate::grpc::key_manager_admin_service::{
+    ForceRotateRequest, ForceRotateResponse, PurgeExpiredKeysRequest, PurgeExpiredKeysResponse,
+    RevokeEpochRequest, RevokeEpochResponse,
+};
+use crate::manager::{self, KeyManager, Realms};
+use crate::request_id;
+use ps_signatures::serde::Serializable;
+use std::sync::{Arc, RwLock};
+use tonic::{Request, Response, Status};
+
+pub struct KeyManagerAdminController {
+    realms: Arc<Realms>,
+}
+
+impl KeyManagerAdminController {
+    pub fn new(realms: Arc<Realms>) -> Self {
+        Self { realms }
+    }
+
+    fn key_manager(&self, realm: &str) -> Result<&Arc<RwLock<KeyManager>>, Status> {
+        self.realms
+            .get(realm)
+            .map_err(|e| Status::not_found(e.to_string()))
+    }
+}
+
+#[tonic::async_trait]
+impl KeyManagerAdminService for KeyManagerAdminController {
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn purge_expired_keys(
+        &self,
+        request: Request<PurgeExpiredKeysRequest>,
+    ) -> Result<Response<PurgeExpiredKeysResponse>, Status> {
+        let request = request.into_inner();
+
+        info!("Got 'purge_expired_keys' request: {:?}", request);
+
+        let mut key_manager = manager::write_lock(self.key_manager(&request.realm)?);
+
+        let purged = key_manager
+            .purge_now()
+            .map_err(|e| Status::aborted(e.to_string()))?;
+
+        Ok(Response::new(PurgeExpiredKeysResponse {
+            purged: purged as u64,
+        }))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn force_rotate(
+        &self,
+        request: Request<ForceRotateRequest>,
+    ) -> Result<Response<ForceRotateResponse>, Status> {
+        let request = request.into_inner();
+
+        warn!("Got 'force_rotate' request: {:?}", request);
+
+        // The key is generated without holding the lock, readers are not blocked
+        let key_profile =
+            KeyManager::rotate_now(self.key_manager(&request.realm)?, request.advance)
+                .await
+                .map_err(|e| Status::aborted(e.to_string()))?;
+
+        let public_key = key_profile
+            .material
+            .public_key
+            .serialize()
+            .map_err(|_| Status::aborted("Could not serialize public key"))?;
+
+        Ok(Response::new(ForceRotateResponse {
+            epoch: key_profile.epoch.as_secs(),
+            public_key,
+        }))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn revoke_epoch(
+        &self,
+        request: Request<RevokeEpochRequest>,
+    ) -> Result<Response<RevokeEpochResponse>, Status> {
+        let request = request.into_inner();
+
+        warn!("Got 'revoke_epoch' request: {:?}", request);
+
+        if request.reason.is_empty() {
+            return Err(Status::invalid_argument("A revocation reason is required."));
+        }
+
+        manager::write_lock(self.key_manager(&request.realm)?)
+            .revoke_epoch(request.epoch, request.reason)
+            .map_err(|e| Status::aborted(e.to_string()))?;
+
+        Ok(Response::new(RevokeEpochResponse {}))
+    }
+}
@@ -1,68 +1,390 @@
+use crate::audit::AuditLog;
+use crate::auth;
+use crate::deadline;
 use crate::error::KeyManagerError;
 use crate::grpc::key_manager_service::key_manager_service_server::KeyManagerService;
-use crate::grpc::key_manager_service::{GetIssuingKeyRequest, GetIssuingKeyResponse};
-use crate::manager::{KeyManager, KeyProfile};
+use crate::grpc::key_manager_service::{
+    EpochEntry, GetIssuingKeyRequest, GetIssuingKeyResponse, GetIssuingKeysRequest,
+    GetIssuingKeysResponse, GetPublicKeyHistoryRequest, GetPublicKeyHistoryResponse,
+    HealthRequest, HealthResponse, ListEpochsRequest, ListEpochsResponse, PublicKeyHistoryEntry,
+    VersionRequest, VersionResponse, WatchIssuingKeysRequest,
+};
+use crate::manager::{self, KeyManager, KeyProfile, Realms};
+use crate::request_id;
 use ps_signatures::serde::Serializable;
-use std::sync::{Arc, Mutex};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
 use tonic::{Request, Response, Status};
+use vt_common::key;
+
+// Pending key messages per watcher
+const WATCH_CHANNEL_CAPACITY: usize = 4;
+
+// Maximum number of epochs per public key history response
+const MAX_PUBLIC_KEY_HISTORY: usize = 1000;
+
+// Maximum number of epochs per list epochs response
+const MAX_LISTED_EPOCHS: usize = 1000;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Set by the build script
+const GIT_HASH: &str = env!("GIT_HASH");
+
+pub mod admin_controller;
 
 pub struct KeyManagerController {
-    key_manager: Arc<Mutex<KeyManager>>,
+    realms: Arc<Realms>,
+
+    // Updated when the certificate is reloaded
+    tls_cert_not_after: Arc<AtomicI64>,
+
+    audit_log: Arc<AuditLog>,
+
+    // Changes on shutdown, ending the key watches
+    shutdown: watch::Receiver<bool>,
 }
 
 impl KeyManagerController {
-    pub fn new(key_manager: Arc<Mutex<KeyManager>>) -> Self {
-        Self { key_manager }
+    pub fn new(
+        realms: Arc<Realms>,
+        tls_cert_not_after: Arc<AtomicI64>,
+        audit_log: Arc<AuditLog>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        Self {
+            realms,
+            tls_cert_not_after,
+            audit_log,
+            shutdown,
+        }
+    }
+
+    fn key_manager(&self, realm: &str) -> Result<&Arc<RwLock<KeyManager>>, Status> {
+        self.realms
+            .get(realm)
+            .map_err(|e| Status::not_found(e.to_string()))
+    }
+
+    // Records every requested epoch, whether its key was retrieved or not
+    fn audit_keys(
+        audit_log: &AuditLog,
+        client: Option<&str>,
+        realm: &str,
+        epochs: &[u64],
+        keys: &Result<GetIssuingKeysResponse, Status>,
+    ) {
+        for epoch in epochs {
+            let success = match keys {
+                Ok(keys) => keys.keys.iter().any(|key| key.epoch == *epoch),
+                Err(_) => false,
+            };
+
+            audit_log.key_retrieval(client, realm, *epoch, success);
+        }
+    }
+
+    // Keys of the requested epochs, including revoked ones
+    fn collect_keys(
+        key_manager: &KeyManager,
+        epochs: &[u64],
+    ) -> Result<GetIssuingKeysResponse, Status> {
+        let mut keys: Vec<GetIssuingKeyResponse> = Vec::with_capacity(epochs.len());
+
+        for epoch in epochs {
+            match key_manager.get_key_profile_and_revocation(*epoch) {
+                Ok((key_profile, revocation_reason)) => {
+                    let mut key: GetIssuingKeyResponse = key_profile.as_ref().try_into()?;
+                    key.revocation_reason = revocation_reason.unwrap_or_default();
+
+                    keys.push(key);
+                }
+                Err(KeyManagerError::NotFoundError(_)) => continue,
+                Err(e) => return Err(Status::aborted(e.to_string())),
+            }
+        }
+
+        if keys.is_empty() {
+            return Err(Status::not_found("Keys not found"));
+        }
+
+        Ok(GetIssuingKeysResponse { keys })
+    }
+
+    fn collect_current_keys(
+        key_manager: &RwLock<KeyManager>,
+    ) -> Result<GetIssuingKeysResponse, Status> {
+        let key_manager = manager::read_lock(key_manager);
+
+        let epochs: Vec<u64> = [key_manager.get_current_epoch(), key_manager.get_next_epoch()]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        Self::collect_keys(&key_manager, &epochs)
     }
 }
 
 #[tonic::async_trait]
 impl KeyManagerService for KeyManagerController {
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
     async fn get_issuing_key(
         &self,
         request: Request<GetIssuingKeyRequest>,
     ) -> Result<Response<GetIssuingKeyResponse>, Status> {
+        let client = auth::client_subject(&request);
+        let deadline = deadline::get(&request);
         let request = request.into_inner();
 
-        let key_manager = self.key_manager.lock().unwrap();
+        let key_manager = manager::read_lock(self.key_manager(&request.realm)?);
+        deadline::check(deadline)?;
 
-        let key_profile = match key_manager.get_key_profile(request.epoch) {
+        let result = key_manager.get_key_profile(request.epoch);
+        self.audit_log.key_retrieval(
+            client.as_deref(),
+            &request.realm,
+            request.epoch,
+            result.is_ok(),
+        );
+
+        let key_profile = match result {
             Ok(key_profile) => key_profile,
             Err(err) => {
                 return match err {
                     KeyManagerError::NotFoundError(e) => Err(Status::not_found(e.to_string())),
+                    KeyManagerError::RevokedError(e) => {
+                        Err(Status::failed_precondition(e.to_string()))
+                    }
                     e => Err(Status::aborted(e.to_string())),
                 }
             }
         };
 
-        Ok(Response::new(key_profile.try_into()?))
+        Ok(Response::new(key_profile.as_ref().try_into()?))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn get_issuing_keys(
+        &self,
+        request: Request<GetIssuingKeysRequest>,
+    ) -> Result<Response<GetIssuingKeysResponse>, Status> {
+        let client = auth::client_subject(&request);
+        let deadline = deadline::get(&request);
+        let request = request.into_inner();
+
+        let key_manager = manager::read_lock(self.key_manager(&request.realm)?);
+        deadline::check(deadline)?;
+
+        let keys = Self::collect_keys(&key_manager, &request.epochs);
+        Self::audit_keys(
+            &self.audit_log,
+            client.as_deref(),
+            &request.realm,
+            &request.epochs,
+            &keys,
+        );
+
+        Ok(Response::new(keys?))
+    }
+
+    type WatchIssuingKeysStream =
+        Pin<Box<dyn Stream<Item = Result<GetIssuingKeysResponse, Status>> + Send>>;
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn watch_issuing_keys(
+        &self,
+        request: Request<WatchIssuingKeysRequest>,
+    ) -> Result<Response<Self::WatchIssuingKeysStream>, Status> {
+        debug!("Got 'watch_issuing_keys' request.");
+
+        let client = auth::client_subject(&request);
+        let realm = request.into_inner().realm;
+        let audit_log = self.audit_log.clone();
+        let key_manager = self.key_manager(&realm)?.clone();
+        let mut key_updates = manager::read_lock(&key_manager).subscribe();
+        let mut shutdown = self.shutdown.clone();
+
+        let (sender, receiver) = mpsc::channel(WATCH_CHANNEL_CAPACITY);
+
+        tokio::spawn(async move {
+            let mut keys = Self::collect_current_keys(&key_manager);
+
+            loop {
+                if let Ok(keys) = &keys {
+                    for key in &keys.keys {
+                        audit_log.key_retrieval(client.as_deref(), &realm, key.epoch, true);
+                    }
+                }
+
+                if sender.send(keys).await.is_err() {
+                    // The watcher disconnected
+                    break;
+                }
+
+                tokio::select! {
+                    update = key_updates.recv() => match update {
+                        // Missed updates are covered by sending the latest keys
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
+                    },
+                    // Stop watching on shutdown, so the server does not wait for the watchers
+                    _ = shutdown.changed() => break,
+                }
+
+                keys = Self::collect_current_keys(&key_manager);
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn get_public_key_history(
+        &self,
+        request: Request<GetPublicKeyHistoryRequest>,
+    ) -> Result<Response<GetPublicKeyHistoryResponse>, Status> {
+        debug!("Got 'get_public_key_history' request: {:?}", request);
+
+        let deadline = deadline::get(&request);
+        let request = request.into_inner();
+
+        let limit = match request.limit as usize {
+            0 => MAX_PUBLIC_KEY_HISTORY,
+            limit => limit.min(MAX_PUBLIC_KEY_HISTORY),
+        };
+
+        let key_manager = manager::read_lock(self.key_manager(&request.realm)?);
+        deadline::check(deadline)?;
+
+        let (entries, truncated) = key_manager
+            .get_public_key_history(request.since_epoch, limit)
+            .map_err(|e| Status::aborted(e.to_string()))?;
+
+        let keys = entries
+            .into_iter()
+            .map(|entry| PublicKeyHistoryEntry {
+                epoch: entry.epoch,
+                public_key: entry.public_key,
+                params: entry.params,
+                revocation_reason: entry.revocation_reason.unwrap_or_default(),
+                key_scheme: entry.key_scheme,
+            })
+            .collect();
+
+        Ok(Response::new(GetPublicKeyHistoryResponse { keys, truncated }))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn list_epochs(
+        &self,
+        request: Request<ListEpochsRequest>,
+    ) -> Result<Response<ListEpochsResponse>, Status> {
+        debug!("Got 'list_epochs' request: {:?}", request);
+
+        let deadline = deadline::get(&request);
+        let request = request.into_inner();
+
+        let limit = match request.limit as usize {
+            0 => MAX_LISTED_EPOCHS,
+            limit => limit.min(MAX_LISTED_EPOCHS),
+        };
+
+        let key_manager = manager::read_lock(self.key_manager(&request.realm)?);
+        deadline::check(deadline)?;
+
+        let (epochs, truncated) = key_manager
+            .list_epochs(request.since_epoch, limit)
+            .map_err(|e| Status::aborted(e.to_string()))?;
+
+        let current_epoch = key_manager.get_current_epoch();
+        let next_epoch = key_manager.get_next_epoch();
+
+        let epochs = epochs
+            .into_iter()
+            .map(|epoch| EpochEntry {
+                epoch,
+                current: current_epoch == Some(epoch),
+                next: next_epoch == Some(epoch),
+            })
+            .collect();
+
+        Ok(Response::new(ListEpochsResponse { epochs, truncated }))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn health(
+        &self,
+        request: Request<HealthRequest>,
+    ) -> Result<Response<HealthResponse>, Status> {
+        let key_manager = manager::read_lock(self.key_manager(&request.get_ref().realm)?);
+        deadline::check(deadline::get(&request))?;
+
+        let ready = key_manager
+            .is_ready()
+            .map_err(|e| Status::aborted(e.to_string()))?;
+
+        Ok(Response::new(HealthResponse {
+            current_epoch: key_manager.get_current_epoch().unwrap_or_default(),
+            next_epoch: key_manager.get_next_epoch().unwrap_or_default(),
+            key_lifetime: key_manager.get_key_lifetime().as_secs(),
+            ready,
+            tls_cert_not_after: self.tls_cert_not_after.load(Ordering::Relaxed),
+            key_scheme: key::KEY_SCHEME.to_string(),
+        }))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn get_version(
+        &self,
+        request: Request<VersionRequest>,
+    ) -> Result<Response<VersionResponse>, Status> {
+        let key_manager = manager::read_lock(self.realms.default_realm());
+
+        Ok(Response::new(VersionResponse {
+            version: VERSION.to_string(),
+            git_hash: GIT_HASH.to_string(),
+            key_lifetime: key_manager.get_key_lifetime().as_secs(),
+        }))
     }
 }
 
-impl TryInto<GetIssuingKeyResponse> for KeyProfile {
+impl TryInto<GetIssuingKeyResponse> for &KeyProfile {
     type Error = Status;
 
     fn try_into(self) -> Result<GetIssuingKeyResponse, Self::Error> {
         let signing_key = self
+            .material
             .signing_key
             .serialize()
             .map_err(|_| Status::aborted("Could not serialize signing key"))?;
 
         let public_key = self
+            .material
             .public_key
             .serialize()
             .map_err(|_| Status::aborted("Could not serialize public key"))?;
 
         let params = self
+            .material
             .params
             .serialize()
             .map_err(|_| Status::aborted("Could not serialize params"))?;
 
         Ok(GetIssuingKeyResponse {
+            key_fingerprint: key::key_fingerprint(&public_key),
             signing_key,
             public_key,
             params,
+            epoch: self.epoch.as_secs(),
+            message_count: self.material.message_count as u64,
+            revocation_reason: String::new(),
+            key_scheme: self.key_scheme.clone(),
+            key_format_version: self.key_format_version as u32,
         })
     }
 }
@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+use tonic::{Request, Status};
+
+// Relative deadline set by gRPC clients, e.g. "10S"
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+// Point in time after which the client no longer waits for the response
+#[derive(Clone, Copy)]
+struct Deadline(Instant);
+
+// Turns the relative deadline of the client into an absolute one on arrival
+pub fn intercept(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let timeout = request
+        .metadata()
+        .get(GRPC_TIMEOUT_HEADER)
+        .and_then(|timeout| timeout.to_str().ok())
+        .and_then(parse_timeout);
+
+    if let Some(timeout) = timeout {
+        request
+            .extensions_mut()
+            .insert(Deadline(Instant::now() + timeout));
+    }
+
+    Ok(request)
+}
+
+pub fn get<T>(request: &Request<T>) -> Option<Instant> {
+    request
+        .extensions()
+        .get::<Deadline>()
+        .map(|deadline| deadline.0)
+}
+
+// Called before doing work for the request, e.g. after waiting for a key manager lock
+pub fn check(deadline: Option<Instant>) -> Result<(), Status> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Err(Status::deadline_exceeded(
+            "The request deadline has passed.",
+        )),
+        _ => Ok(()),
+    }
+}
+
+// At most 8 digits followed by the unit
+fn parse_timeout(timeout: &str) -> Option<Duration> {
+    if timeout.len() < 2 || timeout.len() > 9 {
+        return None;
+    }
+
+    let (value, unit) = timeout.split_at(timeout.len() - 1);
+    let value: u64 = value.parse().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(value * 60 * 60)),
+        "M" => Some(Duration::from_secs(value * 60)),
+        "S" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_millis(value)),
+        "u" => Some(Duration::from_micros(value)),
+        "n" => Some(Duration::from_nanos(value)),
+        _ => None,
+    }
+}
@@ -16,4 +16,22 @@ pub enum KeyManagerError {
 
     #[error("Config error. {0}")]
     ConfigError(String),
+
+    #[error("TLS error. {0}")]
+    TlsError(String),
+
+    #[error("Self-test error. {0}")]
+    SelfTestError(String),
+
+    #[error("Revoked. {0}")]
+    RevokedError(String),
+
+    #[error("Provision error. {0}")]
+    ProvisionError(String),
+
+    #[error("Clock error. {0}")]
+    ClockError(String),
+
+    #[error("Encryption error. {0}")]
+    EncryptionError(String),
 }
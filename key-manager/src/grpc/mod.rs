@@ -1,3 +1,10 @@
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    tonic::include_file_descriptor_set!("key_manager_descriptor");
+
+pub mod key_manager_admin_service {
+    tonic::include_proto!("key_manager_admin_service");
+}
+
 pub mod key_manager_service {
     tonic::include_proto!("key_manager_service");
 }
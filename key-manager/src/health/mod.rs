@@ -0,0 +1,16 @@
+use crate::controller::KeyManagerController;
+use crate::grpc::key_manager_service::key_manager_service_server::KeyManagerServiceServer;
+use tonic_health::server::HealthReporter;
+
+// Report the serving status of the key manager services
+pub async fn set_serving_status(health_reporter: &mut HealthReporter, serving: bool) {
+    if serving {
+        health_reporter
+            .set_serving::<KeyManagerServiceServer<KeyManagerController>>()
+            .await;
+    } else {
+        health_reporter
+            .set_not_serving::<KeyManagerServiceServer<KeyManagerController>>()
+            .await;
+    }
+}
@@ -0,0 +1,20 @@
+#[macro_use]
+extern crate log;
+
+pub mod audit;
+pub mod auth;
+pub mod cipher;
+pub mod config;
+pub mod controller;
+pub mod deadline;
+pub mod error;
+pub mod grpc;
+pub mod health;
+pub mod listener;
+pub mod logging;
+pub mod manager;
+pub mod request_id;
+pub mod server;
+pub mod store;
+pub mod tls;
+pub mod webhook;
@@ -0,0 +1,39 @@
+use crate::config::LogFormat;
+use crate::error::KeyManagerError;
+use crate::error::KeyManagerError::ConfigError;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+// Changes the log level at runtime
+pub struct LogLevelHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogLevelHandle {
+    pub fn set_level(&self, log_level: &str) -> Result<(), KeyManagerError> {
+        let filter = EnvFilter::try_new(log_level)
+            .map_err(|e| ConfigError(format!("Invalid log level. {}", e)))?;
+
+        self.handle
+            .reload(filter)
+            .map_err(|e| ConfigError(format!("Could not set log level. {}", e)))
+    }
+}
+
+// Log level defaults to the RUST_LOG environment variable
+pub fn init(log_format: LogFormat, log_level: Option<&str>) -> LogLevelHandle {
+    let filter = match log_level {
+        Some(log_level) => EnvFilter::new(log_level),
+        None => EnvFilter::from_default_env(),
+    };
+
+    let (filter, handle) = reload::Layer::new(filter);
+    let subscriber = tracing_subscriber::registry().with(filter);
+
+    match log_format {
+        LogFormat::Plain => subscriber.with(fmt::layer()).init(),
+        LogFormat::Json => subscriber.with(fmt::layer().json()).init(),
+    }
+
+    LogLevelHandle { handle }
+}
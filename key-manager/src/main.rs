@@ -1,58 +1,416 @@
 #[macro_use]
 extern crate log;
 
-mod config;
-mod controller;
-mod error;
-mod grpc;
-mod manager;
-
-use std::fs;
-use crate::config::KeyManagerConfig;
-use crate::controller::KeyManagerController;
-use crate::grpc::key_manager_service::key_manager_service_server::KeyManagerServiceServer;
-use crate::manager::KeyManager;
+use ps_signatures::serde::Serializable;
 use std::net::SocketAddr;
-use tonic::transport::Server;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tonic_health::server::HealthReporter;
+use vt_common::key;
+use vt_common::scheduler::Scheduler;
+use vt_key_manager::audit::AuditLog;
+use vt_key_manager::config::KeyManagerConfig;
+use vt_key_manager::controller::admin_controller::KeyManagerAdminController;
+use vt_key_manager::error::KeyManagerError::{DBError, NotFoundError, SerializationError};
+use vt_key_manager::grpc::key_manager_admin_service::key_manager_admin_service_server::KeyManagerAdminServiceServer;
+use vt_key_manager::logging::LogLevelHandle;
+use vt_key_manager::manager::{KeyManager, Realms};
+use vt_key_manager::server::server_builder;
+use vt_key_manager::store::KeyStore;
+use vt_key_manager::{auth, config, health, logging, manager, request_id, server, store, tls};
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+// Prints the current public key and exits
+const DUMP_CURRENT_KEY_ARG: &str = "--dump-current-key";
 
-    info!("Loading Key Manager...");
+// Back up the stopped key manager's keys to the given directory and exit
+const BACKUP_ARG: &str = "--backup";
+
+// Restore the latest backup from the given directory and exit
+const RESTORE_ARG: &str = "--restore";
+
+// Switch the stopped key manager's keys to the given key lifetime in minutes and exit
+const MIGRATE_LIFETIME_ARG: &str = "--migrate-lifetime";
+
+// Encrypt the stopped key manager's keys with the configured DB encryption key and exit.
+// Takes the file of the previous encryption key if the keys are already encrypted
+const REENCRYPT_KEYS_ARG: &str = "--reencrypt-keys";
+
+// Prints a commented sample config and exits, without loading the config
+const PRINT_SAMPLE_CONFIG_ARG: &str = "--print-sample-config";
+
+// Prefix of the runtime thread names
+const THREAD_NAME: &str = "vt-key-manager";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::args().any(|arg| arg == PRINT_SAMPLE_CONFIG_ARG) {
+        print!("{}", config::sample_config()?);
+        return Ok(());
+    }
 
     // Configuration
     let config = KeyManagerConfig::load().unwrap();
 
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.worker_threads)
+        .thread_name(THREAD_NAME)
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(run(config))
+}
+
+async fn run(config: KeyManagerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    // Logging
+    let log_level_handle = logging::init(config.log_format, config.log_level.as_deref());
+
+    info!("Loading Key Manager...");
+    info!("Config loaded from {}", config.config_location);
+    config.log_effective_config();
+
+    let insecure = config.is_insecure();
+    if insecure {
+        warn!("TLS is disabled. Never run an 'insecure' build in production.");
+    } else if config.insecure {
+        warn!("'insecure' is ignored, the key manager is not built with the 'insecure' feature.");
+    }
+
+    if let Some(backup_dir) = arg_value(BACKUP_ARG) {
+        let db = store::connect_to_db(&config)?;
+        db.backup(Path::new(&backup_dir), config.backups_to_keep)
+            .map_err(|e| DBError(format!("Could not back up the keys. {}", e)))?;
+
+        info!("Backed up the keys to '{}'.", backup_dir);
+        return Ok(());
+    }
+
+    if let Some(backup_dir) = arg_value(RESTORE_ARG) {
+        let epochs = Realms::restore(&config, Path::new(&backup_dir))?;
+
+        info!("Restored the keys of {} epochs from '{}'.", epochs, backup_dir);
+        return Ok(());
+    }
+
+    if let Some(key_lifetime) = arg_value(MIGRATE_LIFETIME_ARG) {
+        let key_lifetime = KeyManagerConfig {
+            key_lifetime: key_lifetime.parse()?,
+            ..config.clone()
+        }
+        .key_lifetime_seconds()?;
+
+        let epochs = Realms::migrate_lifetime(&config, key_lifetime)?;
+
+        info!(
+            "Migrated the keys of {} epochs. Set 'key_lifetime' to {} before starting.",
+            epochs,
+            key_lifetime.to_minutes()
+        );
+        return Ok(());
+    }
+
+    if std::env::args().any(|arg| arg == REENCRYPT_KEYS_ARG) {
+        let previous_key = arg_value(REENCRYPT_KEYS_ARG);
+        let reencrypted = Realms::reencrypt_keys(&config, previous_key.as_deref())?;
+
+        info!("Re-encrypted {} stored values.", reencrypted);
+        return Ok(());
+    }
+
+    // Health
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health::set_serving_status(&mut health_reporter, false).await;
+
     // Services
-    let key_manager = KeyManager::create(&config).unwrap();
+    let realms = Realms::create(&config)?;
+
+    if std::env::args().any(|arg| arg == DUMP_CURRENT_KEY_ARG) {
+        realms.update_keys()?;
+        return dump_current_keys(&realms);
+    }
+
+    let mut key_update_scheduler =
+        KeyManager::schedule_key_updates(realms.clone(), &config, health_reporter.clone());
+
+    let backup_scheduler = config.backup_dir.as_ref().map(|backup_dir| {
+        KeyManager::schedule_backups(realms.clone(), PathBuf::from(backup_dir), &config)
+    });
+
+    let client_auth = !insecure && config.client_auth_ca().is_some();
+    if !client_auth {
+        warn!("Client authentication is disabled. Any client can retrieve the issuing keys.");
+    }
+
+    let tls_cert_not_after = Arc::new(AtomicI64::new(0));
+    if !insecure {
+        tls_cert_not_after.store(
+            tls::certificate_not_after("tls_cert", &config.tls_cert)?,
+            Ordering::Relaxed,
+        );
+        tls::schedule_expiry_check(tls_cert_not_after.clone(), config.cert_expiry_warn_days);
+    }
+
+    let audit_log = Arc::new(AuditLog::create(&config.audit_log_path)?);
+
+    // Ends the key watches, which would otherwise keep the servers from shutting down
+    let (watch_shutdown, watch_shutdown_receiver) = watch::channel(false);
 
     // Controller
-    let key_manager_controller =
-        KeyManagerServiceServer::new(KeyManagerController::new(key_manager));
+    let key_manager_service = server::key_manager_service(
+        &config,
+        realms.clone(),
+        tls_cert_not_after.clone(),
+        audit_log,
+        watch_shutdown_receiver,
+    );
+
+    // Admin service. Only served with client authentication
+    if let (Some(admin_port), Some(admin_client_ca), false) =
+        (config.admin_port, &config.admin_client_ca, insecure)
+    {
+        let admin_tls_config = tls::build_admin_tls_config(&config, admin_client_ca)?;
+        let mut admin_controller =
+            KeyManagerAdminServiceServer::new(KeyManagerAdminController::new(realms.clone()))
+                .max_decoding_message_size(config.max_decoding_message_size)
+                .max_encoding_message_size(config.max_encoding_message_size);
+
+        if let Some(encoding) = config.grpc_compression.encoding() {
+            admin_controller = admin_controller
+                .accept_compressed(encoding)
+                .send_compressed(encoding);
+        }
+        let admin_address = SocketAddr::new(config.admin_host.unwrap_or(config.host), admin_port);
+
+        info!("Starting admin server on {}", admin_address);
+
+        let admin_server = server_builder(&config)
+            .tls_config(admin_tls_config)?
+            .layer(tonic::service::interceptor(auth::intercept))
+            .layer(tonic::service::interceptor(request_id::intercept))
+            .add_service(health_service.clone())
+            .add_service(admin_controller)
+            .serve_with_shutdown(admin_address, shutdown_signal());
+
+        tokio::spawn(async move {
+            if let Err(e) = admin_server.await {
+                error!("Admin server error. {}", e);
+            }
+        });
+    }
+
+    info!("Starting server on {}:{}", config.host, config.port);
+
+    // Shared by the servers, so a server with a reloaded certificate can take over the port
+    let listener = Arc::new(TcpListener::bind(SocketAddr::new(config.host, config.port)).await?);
 
     // TLS Config
+    let (mut tls_config, mut tls_watcher) = if insecure {
+        (None, None)
+    } else {
+        (
+            Some(tls::build_tls_config(&config)?),
+            Some(tls::TlsFileWatcher::new(&config)?),
+        )
+    };
+
+    // Servers of replaced certificates, finishing their requests
+    let mut draining_servers = Vec::new();
+
+    // Reload the config on SIGHUP
+    let mut reload_signal = signal(SignalKind::hangup())?;
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    'serve: loop {
+        let (drain, drain_signal) = oneshot::channel::<()>();
+
+        let mut server = Box::pin(server::serve(
+            &config,
+            tls_config.clone(),
+            listener.clone(),
+            health_service.clone(),
+            key_manager_service.clone(),
+            async {
+                let _ = drain_signal.await;
+            },
+        )?);
+
+        loop {
+            tokio::select! {
+                result = &mut server => {
+                    result?;
+                    break 'serve;
+                }
+                _ = &mut shutdown => {
+                    let _ = watch_shutdown.send(true);
+                    let _ = drain.send(());
+                    server.await?;
+                    break 'serve;
+                }
+                _ = reload_signal.recv() => {
+                    key_update_scheduler = reload_config(
+                        &config,
+                        &realms,
+                        key_update_scheduler,
+                        &health_reporter,
+                        &log_level_handle,
+                    )
+                    .await;
+                }
+                Some(()) = async { Some(tls_watcher.as_mut()?.changed().await) } => {
+                    // tonic can not swap the certificate of a running server. New connections
+                    // go to a new server while the current one drains its connections
+                    match tls::reload_tls_config(&config, &tls_cert_not_after) {
+                        Ok(reloaded) => {
+                            info!("TLS files changed, serving new connections with them.");
+                            tls_config = Some(reloaded);
+
+                            let _ = drain.send(());
+                            draining_servers.retain(|server: &JoinHandle<_>| !server.is_finished());
+                            draining_servers.push(tokio::spawn(server));
+                            continue 'serve;
+                        }
+                        Err(e) => error!("Could not reload the TLS files, keeping them. {}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    // Long lived streams end on shutdown too
+    for server in draining_servers {
+        match server.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Server error. {}", e),
+            Err(e) => error!("Server failed. {:?}", e),
+        }
+    }
+
+    // Let any in-flight key update complete
+    key_update_scheduler.shutdown().await;
+
+    if let Some(backup_scheduler) = backup_scheduler {
+        backup_scheduler.shutdown().await;
+    }
+
+    // No more writes once the updates have stopped
+    realms.close()?;
+
+    info!("Key Manager stopped.");
+
+    Ok(())
+}
 
-    // Encryption
-    let cert = fs::read(&config.tls_cert).unwrap();
-    let key = fs::read(&config.tls_key).unwrap();
+// Value following the argument, e.g. the directory of '--backup <dir>'
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    args.next()?;
 
-    let id = tonic::transport::Identity::from_pem(cert, key);
-    let tls_config = tonic::transport::ServerTlsConfig::new().identity(id);
+    args.next()
+}
 
-    // Auth
-    let ca = fs::read(&config.client_ca).unwrap();
-    let ca = tonic::transport::Certificate::from_pem(ca);
-    let tls_config = tls_config.client_ca_root(ca);
+// The current key of every realm, the default realm first
+fn dump_current_keys(realms: &Realms) -> Result<(), Box<dyn std::error::Error>> {
+    for (realm, key_manager) in realms.iter() {
+        let key_manager = manager::read_lock(key_manager);
 
-    info!("Staring server on {}:{}", config.host, config.port);
+        let epoch = key_manager
+            .get_current_epoch()
+            .ok_or_else(|| NotFoundError("No current epoch".to_string()))?;
+        let key_profile = key_manager.get_key_profile(epoch)?;
 
-    Server::builder()
-        .tls_config(tls_config)
-        .unwrap()
-        .add_service(key_manager_controller)
-        .serve(SocketAddr::new(config.host, config.port))
-        .await?;
+        let public_key = key_profile
+            .material
+            .public_key
+            .serialize()
+            .map_err(|e| SerializationError(format!("Could not serialize public key. {:?}", e)))?;
+        let params = key_profile
+            .material
+            .params
+            .serialize()
+            .map_err(|e| SerializationError(format!("Could not serialize params. {:?}", e)))?;
+
+        if !realm.is_empty() {
+            println!();
+            println!("realm: {}", realm);
+        }
+        println!("epoch: {}", epoch);
+        println!("fingerprint: {}", key::key_fingerprint(&public_key));
+        println!("public_key: {}", base64::encode(public_key));
+        println!("params: {}", base64::encode(params));
+    }
 
     Ok(())
 }
+
+// Applies the hot reloadable fields and reschedules the key updates
+async fn reload_config(
+    config: &KeyManagerConfig,
+    realms: &Arc<Realms>,
+    key_update_scheduler: Scheduler,
+    health_reporter: &HealthReporter,
+    log_level_handle: &LogLevelHandle,
+) -> Scheduler {
+    info!("Reloading config...");
+
+    let new_config = match KeyManagerConfig::load() {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            error!("Could not reload config. {}", e);
+            return key_update_scheduler;
+        }
+    };
+
+    for field in config.changed_static_fields(&new_config) {
+        warn!("'{}' can not be changed without a restart, ignoring.", field);
+    }
+
+    // Stop updates with the old schedule
+    key_update_scheduler.shutdown().await;
+
+    for (realm, key_manager) in realms.iter() {
+        if let Err(e) = KeyManager::reload(key_manager, &new_config).await {
+            error!("Could not apply the reloaded config to realm '{}'. {}", realm, e);
+        }
+    }
+
+    if let Some(log_level) = &new_config.log_level {
+        if let Err(e) = log_level_handle.set_level(log_level) {
+            error!("{}", e);
+        }
+    }
+
+    info!("Config reloaded.");
+
+    KeyManager::schedule_key_updates(realms.clone(), &new_config, health_reporter.clone())
+}
+
+// Ctrl-C or SIGTERM, e.g. from the container runtime
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate_signal) => {
+                terminate_signal.recv().await;
+            }
+            Err(e) => {
+                error!("Could not listen for SIGTERM. {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                error!("Could not listen for the shutdown signal. {:?}", e);
+            }
+        }
+        _ = terminate => {}
+    }
+
+    info!("Shutting down...");
+}
@@ -1,110 +1,1178 @@
+use crate::cipher::KeyCipher;
 use crate::config::KeyManagerConfig;
 use crate::error::KeyManagerError;
 use crate::error::KeyManagerError::{
-    DBError, DeserializationError, NotFoundError, SerializationError,
+    ClockError, ConfigError, DBError, DeserializationError, EncryptionError, NotFoundError,
+    ProvisionError, RevokedError, SelfTestError, SerializationError,
 };
+use crate::health;
+use crate::store::KeyStore;
+use crate::webhook::RotationWebhook;
+use ff_zeroize::Field;
+use futures::future::join_all;
+use pairing_plus::bls12_381::Fr;
 use ps_signatures::keys::{PsParams, PsPublicKey, PsSigningKey};
 use ps_signatures::serde::Serializable;
-use rand::thread_rng;
-use rocksdb::{Options, DB};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::{thread_rng, CryptoRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+use tokio::sync::broadcast;
 use tokio::time::Instant;
-
-const SUFFIX_PARAMS: &str = "-key_params";
-const SUFFIX_SIGNING_KEY: &str = "-signing_key";
-const SUFFIX_PUBLIC_KEY: &str = "-public_key";
+use tonic_health::server::HealthReporter;
+use veronymous_token::root_exchange::{
+    complete_root_token, create_root_token_request, issue_root_token,
+};
+use vt_common::epoch::{self, Epoch, Minutes, Seconds};
+use vt_common::key::{
+    key_fingerprint, KeyMaterial, KEY_FORMAT_VERSION, KEY_SCHEME, LEGACY_KEY_FORMAT_VERSION,
+};
+use vt_common::scheduler::Scheduler;
+
+mod realm;
+
+pub use realm::Realms;
+
+// Key ids are "<kind>:<epoch>", e.g. "params:1700000000", prefixed by "<realm>:" for the
+// keys of a named realm
+const KEY_ID_DELIMITER: char = ':';
+
+// Realm of the keys requested without a realm, stored without a realm prefix
+pub const DEFAULT_REALM: &str = "";
+
+const KIND_PARAMS: &str = "params";
+const KIND_SIGNING_KEY: &str = "signing_key";
+const KIND_PUBLIC_KEY: &str = "public_key";
+const KIND_MESSAGE_COUNT: &str = "message_count";
+const KIND_REVOCATION: &str = "revocation";
+const KIND_KEY_SCHEME: &str = "key_scheme";
+const KIND_KEY_FORMAT: &str = "key_format";
+// Lifetime of a key served before a key lifetime migration
+const KIND_KEY_LIFETIME: &str = "key_lifetime";
+
+// Older versions stored "<epoch>-<suffix>", e.g. "1700000000--key_params"
+const LEGACY_SUFFIXES: [(&str, &str); 6] = [
+    ("-key_params", KIND_PARAMS),
+    ("-signing_key", KIND_SIGNING_KEY),
+    ("-public_key", KIND_PUBLIC_KEY),
+    ("-message_count", KIND_MESSAGE_COUNT),
+    ("-revocation", KIND_REVOCATION),
+    ("-key_scheme", KIND_KEY_SCHEME),
+];
+
+// Kinds deleted with the keys of expired epochs. Revocations are kept, so a purged epoch
+// stays revoked if its keys are ever restored
+const PURGED_KINDS: [&str; 7] = [
+    KIND_PARAMS,
+    KIND_SIGNING_KEY,
+    KIND_PUBLIC_KEY,
+    KIND_MESSAGE_COUNT,
+    KIND_KEY_SCHEME,
+    KIND_KEY_FORMAT,
+    KIND_KEY_LIFETIME,
+];
+
+// Kinds encrypted with the DB encryption key
+const ENCRYPTED_KINDS: [&str; 3] = [KIND_PARAMS, KIND_SIGNING_KEY, KIND_PUBLIC_KEY];
+
+// Last known epochs and key lifetime, for detecting discontinuities on restart
+const MARKER_CURRENT_EPOCH: &str = "marker-current_epoch";
+const MARKER_NEXT_EPOCH: &str = "marker-next_epoch";
+const MARKER_KEY_LIFETIME: &str = "marker-key_lifetime";
+
+// Message count of keys provisioned before it was persisted
+const LEGACY_MESSAGE_COUNT: usize = 1;
+
+// Minimum key generation seed length in bytes
+const MIN_SEED_LENGTH: usize = 32;
+
+// Seconds to wait before retrying a failed key update
+const KEY_UPDATE_RETRY_INTERVAL: u64 = 5;
+
+// Pending key update notifications per subscriber
+const KEY_UPDATE_CHANNEL_CAPACITY: usize = 16;
+
+const IMPORTED_KEYS_EXTENSION: &str = "keys";
+
+// Params, signing key and public key of a generated key
+type GeneratedKey = (PsParams, PsSigningKey, PsPublicKey);
 
 pub struct KeyManager {
-    db: DB,
+    // Shared by the key managers of all realms
+    key_store: Arc<dyn KeyStore>,
+
+    realm: String,
+
+    key_lifetime: Seconds,
+
+    // Offset of the epoch boundaries
+    epoch_offset: Seconds,
+
+    prefetch_epochs: u64,
+
+    message_count: usize,
+
+    retention_epochs: Option<u64>,
+
+    // Key generation seed. Keys are random if not set
+    seed: Option<Vec<u8>>,
+
+    // Encrypts the stored key material. Stored unencrypted if not set
+    cipher: Option<KeyCipher>,
 
-    key_lifetime: u64,
+    verify_on_provision: bool,
+
+    key_generation_attempts: u8,
+
+    // Pre-generated keys are imported from here before generating keys
+    imported_keys_dir: Option<PathBuf>,
+
+    rotation_webhook: Option<RotationWebhook>,
 
     current_epoch: Option<u64>,
 
     next_epoch: Option<u64>,
+
+    // Epoch made current by a forced rotation before the clock reached it
+    advanced_epoch: Option<u64>,
+
+    // Revocation reasons of revoked epochs
+    revocations: HashMap<u64, String>,
+
+    // Notifies the subscribers whenever the served keys change
+    key_updates: broadcast::Sender<()>,
+
+    // Deserialized key profiles of the provisioned epochs
+    key_profiles: HashMap<u64, Arc<KeyProfile>>,
+
+    consecutive_failures: u64,
 }
 
 impl KeyManager {
-    pub fn create(config: &KeyManagerConfig) -> Result<Arc<Mutex<Self>>, KeyManagerError> {
-        let db = Self::connect_to_db(config)?;
+    fn create(
+        key_store: Arc<dyn KeyStore>,
+        config: &KeyManagerConfig,
+        realm: &str,
+    ) -> Result<Arc<RwLock<Self>>, KeyManagerError> {
+        let mut key_manager = Self::new(key_store, config, realm)?;
+
+        key_manager.migrate_legacy_key_ids()?;
+        key_manager.check_epoch_markers()?;
+        key_manager.load_revocations(config)?;
+
+        // The keys are provisioned by the key update scheduler, once the servers are up
 
-        let mut key_manager = KeyManager {
-            db,
-            key_lifetime: config.key_lifetime * 60, // To seconds
+        // Reads share the lock, only key updates need exclusive access
+        let key_manager = Arc::new(RwLock::new(key_manager));
+
+        Ok(key_manager)
+    }
+
+    fn new(
+        key_store: Arc<dyn KeyStore>,
+        config: &KeyManagerConfig,
+        realm: &str,
+    ) -> Result<Self, KeyManagerError> {
+        if config.message_count == 0 {
+            return Err(ConfigError(format!("Message count must be at least 1.")));
+        }
+
+        let seed = match &config.seed {
+            Some(seed) => Some(Self::decode_seed(seed)?),
+            None => None,
+        };
+
+        let cipher = match &config.db_encryption_key {
+            Some(db_encryption_key) => Some(KeyCipher::load(db_encryption_key)?),
+            None => None,
+        };
+
+        Ok(KeyManager {
+            key_store,
+            realm: realm.to_string(),
+            key_lifetime: config.key_lifetime_seconds()?,
+            epoch_offset: Seconds(config.epoch_offset),
+            prefetch_epochs: config.prefetch_epochs,
+            message_count: config.message_count,
+            retention_epochs: config.retention_epochs,
+            seed,
+            cipher,
+            verify_on_provision: config.verify_on_provision,
+            key_generation_attempts: config.key_generation_attempts,
+            imported_keys_dir: config.imported_keys_dir.as_ref().map(PathBuf::from),
+            rotation_webhook: Self::create_rotation_webhook(config)?,
             current_epoch: None,
             next_epoch: None,
-        };
+            advanced_epoch: None,
+            revocations: HashMap::new(),
+            key_updates: broadcast::channel(KEY_UPDATE_CHANNEL_CAPACITY).0,
+            key_profiles: HashMap::new(),
+            consecutive_failures: 0,
+        })
+    }
 
-        // Initialize
-        key_manager.update_keys()?;
+    pub fn get_key_profile(&self, epoch: u64) -> Result<Arc<KeyProfile>, KeyManagerError> {
+        if let Some(reason) = self.revocations.get(&epoch) {
+            return Err(RevokedError(format!("Epoch {} is revoked. {}", epoch, reason)));
+        }
 
-        // Put wrap with mutex
-        let key_manager = Arc::new(Mutex::new(key_manager));
+        self.find_key_profile(epoch)
+    }
 
-        // Schedule key refresh
-        Self::schedule_key_updates(key_manager.clone(), config);
+    // Revoked keys are still returned to the issuers, so they can refuse to issue with them
+    pub fn get_key_profile_and_revocation(
+        &self,
+        epoch: u64,
+    ) -> Result<(Arc<KeyProfile>, Option<String>), KeyManagerError> {
+        let key_profile = self.find_key_profile(epoch)?;
 
-        Ok(key_manager)
+        Ok((key_profile, self.revocations.get(&epoch).cloned()))
     }
 
-    pub fn get_key_profile(&self, epoch: u64) -> Result<KeyProfile, KeyManagerError> {
+    pub fn subscribe(&self) -> broadcast::Receiver<()> {
+        self.key_updates.subscribe()
+    }
+
+    fn publish_key_update(&self) {
+        // Fails only without subscribers
+        let _ = self.key_updates.send(());
+    }
+
+    pub fn revoke_epoch(&mut self, epoch: u64, reason: String) -> Result<(), KeyManagerError> {
+        self.key_store
+            .put(self.create_revocation_id(epoch).as_bytes(), reason.as_bytes())
+            .map_err(|e| DBError(format!("Could not store revocation. {}", e)))?;
+
+        tracing::warn!(realm = %self.realm, epoch, reason = %reason, "Revoked epoch");
+
+        self.revocations.insert(epoch, reason);
+        self.publish_key_update();
+
+        Ok(())
+    }
+
+    fn find_key_profile(&self, epoch: u64) -> Result<Arc<KeyProfile>, KeyManagerError> {
+        if let Some(key_profile) = self.key_profiles.get(&epoch) {
+            return Ok(key_profile.clone());
+        }
+
+        Ok(Arc::new(self.load_key_profile(epoch)?))
+    }
+
+    fn load_key_profile(&self, epoch: u64) -> Result<KeyProfile, KeyManagerError> {
         if !self.key_exists(epoch) {
             return Err(NotFoundError("Key not found".to_string()));
         }
 
+        // Keys of another serialization format would be misread
+        let key_format_version = self.get_key_format_version(epoch)?;
+
+        if key_format_version != KEY_FORMAT_VERSION {
+            return Err(DeserializationError(format!(
+                "Key of epoch {} has format version {}. Supported version is {}.",
+                epoch, key_format_version, KEY_FORMAT_VERSION
+            )));
+        }
+
         let key_profile = KeyProfile {
-            params: self.get_key_params(&Self::create_key_params_id(epoch))?,
-            signing_key: self.get_signing_key(&Self::create_signing_key_id(epoch))?,
-            public_key: self.get_public_key(&Self::create_public_key_id(epoch))?,
-            key_lifetime: self.key_lifetime,
+            epoch: Epoch(epoch),
+            material: KeyMaterial {
+                params: self.get_key_params(epoch)?,
+                signing_key: self.get_signing_key(epoch)?,
+                public_key: self.get_public_key(epoch)?,
+                message_count: self.get_message_count(epoch)?,
+            },
+            key_lifetime: self.get_key_lifetime_of(epoch)?,
+            key_scheme: self.get_key_scheme(epoch)?,
+            key_format_version,
         };
 
         Ok(key_profile)
     }
 
-    fn schedule_key_updates(key_manager: Arc<Mutex<KeyManager>>, config: &KeyManagerConfig) {
-        // Convert minutes to seconds
-        let key_lifetime = config.key_lifetime * 60;
+    pub fn get_current_epoch(&self) -> Option<u64> {
+        self.current_epoch
+    }
+
+    pub fn get_next_epoch(&self) -> Option<u64> {
+        self.next_epoch
+    }
+
+    pub fn get_key_lifetime(&self) -> Seconds {
+        self.key_lifetime
+    }
+
+    // Whether the current and next keys are provisioned
+    pub fn is_ready(&self) -> Result<bool, KeyManagerError> {
+        match (self.current_epoch, self.next_epoch) {
+            (Some(current_epoch), Some(next_epoch)) => {
+                Ok(self.key_exists(current_epoch) && self.key_exists(next_epoch))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // Number of scheduled key updates that failed in a row
+    pub fn get_consecutive_failures(&self) -> u64 {
+        self.consecutive_failures
+    }
+
+    // Apply the runtime changeable config fields
+    pub async fn reload(
+        key_manager: &Arc<RwLock<KeyManager>>,
+        config: &KeyManagerConfig,
+    ) -> Result<(), KeyManagerError> {
+        {
+            let mut key_manager = write_lock(key_manager);
+
+            key_manager.epoch_offset = Seconds(config.epoch_offset);
+            key_manager.prefetch_epochs = config.prefetch_epochs;
+            key_manager.retention_epochs = config.retention_epochs;
+            key_manager.key_generation_attempts = config.key_generation_attempts;
+            key_manager.imported_keys_dir = config.imported_keys_dir.as_ref().map(PathBuf::from);
+            key_manager.rotation_webhook = Self::create_rotation_webhook(config)?;
+
+            // Cached profiles hold the old epochs
+            key_manager.key_profiles.clear();
+        }
 
-        let next_key_update = Self::calculate_next_key_update(key_lifetime);
-        let key_lifetime = Duration::from_secs(key_lifetime);
+        // Provision keys for the new schedule, e.g. the additional prefetched epochs
+        Self::provision_and_update_keys(key_manager).await
+    }
 
-        tokio::spawn(async move {
+    // Updates the keys of every realm. The realms share the epochs
+    pub fn schedule_key_updates(
+        realms: Arc<Realms>,
+        config: &KeyManagerConfig,
+        mut health_reporter: HealthReporter,
+    ) -> Scheduler {
+        // Validated when the key manager was created or reloaded
+        let key_lifetime = read_lock(realms.default_realm()).key_lifetime;
+
+        let epoch_offset = Seconds(config.epoch_offset);
+        let health_failure_threshold = config.health_failure_threshold;
+        let provision_lead = config.provision_lead_seconds;
+
+        Scheduler::spawn("Key update scheduler", move |mut shutdown_receiver| async move {
+            let next_key_update = tokio::select! {
+                next_key_update = Self::wait_for_next_key_update(key_lifetime, epoch_offset) => {
+                    next_key_update
+                }
+                _ = &mut shutdown_receiver => return,
+            };
+
+            let key_lifetime = key_lifetime.as_duration();
             let mut interval_timer = tokio::time::interval_at(next_key_update, key_lifetime);
 
+            // Keys of the next epochs are provisioned ahead of the epoch boundary, so they
+            // exist before any issuer crosses it. The epochs are still switched on the boundary
+            let next_provision = next_key_update
+                .checked_sub(Duration::from_secs(provision_lead))
+                .unwrap_or(next_key_update);
+            let mut provision_timer = tokio::time::interval_at(next_provision, key_lifetime);
+
+            let retry_interval = Duration::from_secs(KEY_UPDATE_RETRY_INTERVAL);
+            let mut retry = false;
+
+            // The keys are updated right away, so they are provisioned after the servers
+            // started and the health service reports not serving until then
+            let mut initial_update = true;
+
             debug!("Scheduled key updates...");
             loop {
-                interval_timer.tick().await;
+                // Retry a failed update once before the next scheduled update
+                tokio::select! {
+                    _ = std::future::ready(()), if initial_update => {}
+                    _ = interval_timer.tick(), if !retry => {}
+                    _ = tokio::time::sleep(retry_interval), if retry => {}
+                    _ = provision_timer.tick(), if provision_lead > 0 => {
+                        debug!("Provisioning keys ahead of the next epoch...");
+
+                        for (realm, key_manager) in realms.iter() {
+                            if let Err(e) =
+                                Self::provision_pending_keys(key_manager, provision_lead).await
+                            {
+                                error!("Could not provision the '{}' keys. {:?}", realm, e);
+                            }
+                        }
+                        continue;
+                    }
+                    _ = &mut shutdown_receiver => break,
+                }
 
                 debug!("Updating keys...");
 
-                // TODO: Catch or panic?
-                let mut key_manager = key_manager.lock().unwrap();
-                key_manager.update_keys().unwrap();
+                initial_update = false;
+                retry = false;
+                let mut serving = true;
+
+                for (realm, key_manager) in realms.iter() {
+                    let provisioned = Self::provision_pending_keys(key_manager, 0).await;
+
+                    let mut key_manager = write_lock(key_manager);
+
+                    match provisioned.and_then(|_| key_manager.update_keys()) {
+                        Ok(()) => key_manager.consecutive_failures = 0,
+                        Err(e) => {
+                            key_manager.consecutive_failures += 1;
+                            error!(
+                                "Could not update the '{}' keys ({} consecutive failures). {:?}",
+                                realm, key_manager.consecutive_failures, e
+                            );
+                            retry |= key_manager.consecutive_failures == 1;
+                        }
+                    }
+
+                    // Retried until the keys of the current epoch are loaded
+                    let has_keys = key_manager.get_current_epoch().is_some();
+                    retry |= !has_keys;
+
+                    serving &= has_keys
+                        && key_manager.consecutive_failures <= health_failure_threshold;
+                }
+
+                health::set_serving_status(&mut health_reporter, serving).await;
+            }
+
+            debug!("Stopped key updates.");
+        })
+    }
+
+    // Public keys of the stored epochs starting at since_epoch, oldest first. Also returns
+    // whether epochs were left out because of the limit
+    pub fn get_public_key_history(
+        &self,
+        since_epoch: u64,
+        limit: usize,
+    ) -> Result<(Vec<PublicKeyEntry>, bool), KeyManagerError> {
+        let keys = self
+            .key_store
+            .keys_with_prefix(self.create_key_id_prefix(KIND_PUBLIC_KEY).as_bytes())
+            .map_err(|e| DBError(format!("Could not read keys. {}", e)))?;
+
+        let mut epochs: Vec<u64> = keys
+            .iter()
+            .filter_map(|key| self.parse_realm_key_id(key))
+            .filter(|(kind, epoch)| *kind == KIND_PUBLIC_KEY && *epoch >= since_epoch)
+            .map(|(_, epoch)| epoch)
+            .collect();
+
+        epochs.sort_unstable();
+
+        let truncated = epochs.len() > limit;
+        epochs.truncate(limit);
+
+        let entries = epochs
+            .into_iter()
+            .map(|epoch| {
+                Ok(PublicKeyEntry {
+                    epoch,
+                    public_key: self
+                        .get_stored(&self.create_public_key_id(epoch), "public key")?,
+                    params: self.get_stored(&self.create_key_params_id(epoch), "key params")?,
+                    revocation_reason: self.revocations.get(&epoch).cloned(),
+                    key_scheme: self.get_key_scheme(epoch)?,
+                })
+            })
+            .collect::<Result<Vec<_>, KeyManagerError>>()?;
+
+        Ok((entries, truncated))
+    }
+
+    // Epochs with stored key params starting at since_epoch, oldest first. Also returns
+    // whether epochs were left out because of the limit
+    pub fn list_epochs(
+        &self,
+        since_epoch: u64,
+        limit: usize,
+    ) -> Result<(Vec<u64>, bool), KeyManagerError> {
+        let keys = self
+            .key_store
+            .keys_with_prefix(self.create_key_id_prefix(KIND_PARAMS).as_bytes())
+            .map_err(|e| DBError(format!("Could not read keys. {}", e)))?;
+
+        // Ordered by the epoch digits, not numerically
+        let mut epochs: Vec<u64> = keys
+            .iter()
+            .filter_map(|key| self.parse_realm_key_id(key))
+            .filter(|(kind, epoch)| *kind == KIND_PARAMS && *epoch >= since_epoch)
+            .map(|(_, epoch)| epoch)
+            .collect();
+
+        epochs.sort_unstable();
+
+        let truncated = epochs.len() > limit;
+        epochs.truncate(limit);
+
+        Ok((epochs, truncated))
+    }
+
+    // Periodically back up the key store
+    pub fn schedule_backups(
+        realms: Arc<Realms>,
+        backup_dir: PathBuf,
+        config: &KeyManagerConfig,
+    ) -> Scheduler {
+        let backup_interval = Minutes(config.backup_interval)
+            .to_seconds()
+            .map_or(Duration::MAX, Seconds::as_duration);
+        let backups_to_keep = config.backups_to_keep;
+
+        Scheduler::spawn("Backup scheduler", move |mut shutdown_receiver| async move {
+            let mut interval_timer =
+                tokio::time::interval_at(Instant::now() + backup_interval, backup_interval);
+
+            debug!("Scheduled backups...");
+            loop {
+                tokio::select! {
+                    _ = interval_timer.tick() => {}
+                    _ = &mut shutdown_receiver => break,
+                }
+
+                let realms = realms.clone();
+                let backup_dir = backup_dir.clone();
+
+                let result = tokio::task::spawn_blocking(move || {
+                    realms.backup(&backup_dir, backups_to_keep)
+                })
+                .await;
+
+                match result {
+                    Ok(Ok(())) => info!("Backed up the key store."),
+                    Ok(Err(e)) => error!("Could not back up the key store. {}", e),
+                    Err(e) => error!("Backup task failed. {:?}", e),
+                }
+            }
+
+            debug!("Stopped backups.");
+        })
+    }
+
+    // Key updates need the write lock, so a backup taken while holding the read lock
+    // never contains a partially provisioned epoch
+    fn backup(&self, backup_dir: &Path, backups_to_keep: usize) -> Result<(), KeyManagerError> {
+        self.key_store
+            .backup(backup_dir, backups_to_keep)
+            .map_err(|e| DBError(format!("Could not back up the keys. {}", e)))
+    }
+
+    // Durably persist the stored keys before exiting
+    fn close(&self) -> Result<(), KeyManagerError> {
+        self.key_store
+            .flush()
+            .map_err(|e| DBError(format!("Could not flush the key store. {}", e)))
+    }
+
+    // Deserialize the keys of every stored epoch, returning the number of epochs
+    fn verify_stored_keys(&self) -> Result<usize, KeyManagerError> {
+        let keys = self
+            .key_store
+            .keys()
+            .map_err(|e| DBError(format!("Could not read keys. {}", e)))?;
+
+        let epochs: BTreeSet<u64> = keys
+            .iter()
+            .filter_map(|key| self.parse_realm_key_id(key))
+            .map(|(_, epoch)| epoch)
+            .collect();
+
+        let mut verified = 0;
+
+        for epoch in epochs {
+            // Revocations outlive the purged keys
+            if !self.key_exists(epoch) {
+                continue;
+            }
+
+            self.load_key_profile(epoch)?;
+            verified += 1;
+        }
+
+        Ok(verified)
+    }
+
+    // Stored keys keep their epochs, so they stay queryable, and the lifetime they were
+    // served with. Keys on the new epoch boundaries are served again under the new lifetime
+    fn pin_key_lifetimes(&mut self, key_lifetime: Seconds) -> Result<usize, KeyManagerError> {
+        let previous_key_lifetime = self
+            .get_marker(MARKER_KEY_LIFETIME)?
+            .map(Seconds)
+            .unwrap_or(self.key_lifetime);
+        let current_epoch =
+            epoch::current_epoch(Self::now()?, key_lifetime, self.epoch_offset).as_secs();
+
+        let keys = self
+            .key_store
+            .keys()
+            .map_err(|e| DBError(format!("Could not read keys. {}", e)))?;
+
+        let epochs: BTreeSet<u64> = keys
+            .iter()
+            .filter_map(|key| self.parse_realm_key_id(key))
+            .map(|(_, epoch)| epoch)
+            .filter(|epoch| self.key_exists(*epoch))
+            .collect();
+
+        for epoch in &epochs {
+            let lifetime_id = self.create_key_lifetime_id(*epoch);
+
+            if *epoch >= current_epoch && (*epoch - current_epoch) % key_lifetime.as_secs() == 0 {
+                self.key_store
+                    .delete(lifetime_id.as_bytes())
+                    .map_err(|e| DBError(format!("Could not delete key lifetime. {}", e)))?;
+            } else if self.get_stored_key_lifetime(*epoch)?.is_none() {
+                self.key_store
+                    .put(lifetime_id.as_bytes(), &previous_key_lifetime.as_secs().to_be_bytes())
+                    .map_err(|e| DBError(format!("Could not store key lifetime. {}", e)))?;
+            }
+        }
+
+        self.store_marker(MARKER_KEY_LIFETIME, key_lifetime.as_secs())?;
+
+        info!(
+            "Migrated the key lifetime from {} to {} seconds.",
+            previous_key_lifetime, key_lifetime
+        );
+
+        Ok(epochs.len())
+    }
+
+    // Encrypt the stopped key manager's key material with the configured DB encryption key,
+    // or store it unencrypted without one. Values encrypted with the previous key are
+    // decrypted first. Values already encrypted with the configured key are skipped, so an
+    // interrupted migration can be run again. Returns the number of re-encrypted values
+    fn reencrypt_keys(&self, previous: Option<&KeyCipher>) -> Result<usize, KeyManagerError> {
+        let keys = self
+            .key_store
+            .keys()
+            .map_err(|e| DBError(format!("Could not read keys. {}", e)))?;
+
+        let mut reencrypted = 0;
+
+        for key in keys {
+            let kind = match self.parse_realm_key_id(&key) {
+                Some((kind, _)) if ENCRYPTED_KINDS.contains(&kind) => kind,
+                _ => continue,
+            };
+
+            let value = match self
+                .key_store
+                .get(&key)
+                .map_err(|e| DBError(format!("Could not read key. {}", e)))?
+            {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if matches!(&self.cipher, Some(cipher) if cipher.decrypt(&key, &value).is_ok()) {
+                continue;
+            }
+
+            let value = match previous {
+                Some(previous) if previous.encrypted_with(&value) => {
+                    previous.decrypt(&key, &value)?
+                }
+                _ => value,
+            };
+
+            // Never encrypt a value that was encrypted with an unknown key
+            let key_id = String::from_utf8_lossy(&key).to_string();
+            Self::check_key_material(kind, &value).map_err(|e| {
+                EncryptionError(format!(
+                    "{} is encrypted with an unknown key or corrupted. {}",
+                    key_id, e
+                ))
+            })?;
+
+            let value = self.encrypt(&key_id, value)?;
+
+            self.key_store
+                .put(&key, &value)
+                .map_err(|e| DBError(format!("Could not store re-encrypted key. {}", e)))?;
+
+            reencrypted += 1;
+        }
+
+        info!("Re-encrypted {} values of realm '{}'.", reencrypted, self.realm);
+
+        Ok(reencrypted)
+    }
+
+    // Whether the serialized key material of the kind deserializes
+    fn check_key_material(kind: &str, value: &[u8]) -> Result<(), String> {
+        let result = match kind {
+            KIND_PARAMS => PsParams::deserialize(value).map(|_| ()),
+            KIND_SIGNING_KEY => PsSigningKey::deserialize(value).map(|_| ()),
+            _ => PsPublicKey::deserialize(value).map(|_| ()),
+        };
+
+        result.map_err(|e| format!("{:?}", e))
+    }
+
+    // Replace the next key immediately, optionally making it the current key. The key is
+    // generated without holding the lock
+    pub async fn rotate_now(
+        key_manager: &Arc<RwLock<KeyManager>>,
+        advance: bool,
+    ) -> Result<Arc<KeyProfile>, KeyManagerError> {
+        let next_epoch = read_lock(key_manager)
+            .next_epoch
+            .ok_or_else(|| NotFoundError(format!("No next epoch.")))?;
+
+        let (_, key) = Self::generate_keys(key_manager, vec![next_epoch])
+            .await
+            .remove(0);
+        let key = key?;
+
+        {
+            let mut key_manager = write_lock(key_manager);
+
+            // The generated key must not replace the key of another epoch
+            if key_manager.next_epoch != Some(next_epoch) {
+                return Err(ProvisionError(format!(
+                    "The next epoch changed during the rotation of epoch {}.",
+                    next_epoch
+                )));
+            }
+
+            key_manager.store_provisioned_key(next_epoch, &key)?;
+            key_manager.key_profiles.remove(&next_epoch);
+
+            tracing::warn!(
+                realm = %key_manager.realm,
+                epoch = next_epoch,
+                advance,
+                "Force rotated key"
+            );
+
+            if advance {
+                key_manager.advanced_epoch = Some(next_epoch);
             }
+        }
+
+        // Advancing needs the keys of the following epochs
+        Self::provision_and_update_keys(key_manager).await?;
+
+        let key_manager = read_lock(key_manager);
+        key_manager.publish_key_update();
+
+        key_manager.get_key_profile(next_epoch)
+    }
+
+    // Generate the missing keys without holding the lock, then switch the epochs under a
+    // brief write lock
+    async fn provision_and_update_keys(
+        key_manager: &Arc<RwLock<KeyManager>>,
+    ) -> Result<(), KeyManagerError> {
+        Self::provision_pending_keys(key_manager, 0).await?;
+
+        write_lock(key_manager).update_keys()
+    }
+
+    // Generate the missing keys on the blocking pool without holding the lock, then
+    // store them under a brief write lock
+    async fn provision_pending_keys(
+        key_manager: &Arc<RwLock<KeyManager>>,
+        lead: u64,
+    ) -> Result<(), KeyManagerError> {
+        let epochs = read_lock(key_manager).get_pending_epochs(lead)?;
+
+        if epochs.is_empty() {
+            return Ok(());
+        }
+
+        let generated = Self::generate_keys(key_manager, epochs).await;
+
+        let mut key_manager = write_lock(key_manager);
+
+        for (epoch, key) in generated {
+            let key = key?;
+
+            // Provisioned in the meantime, e.g. by a forced rotation
+            if key_manager.key_exists(epoch) {
+                continue;
+            }
+
+            key_manager.store_provisioned_key(epoch, &key)?;
+        }
+
+        Ok(())
+    }
+
+    // Generate the keys of the epochs on the blocking pool, in the order of the epochs
+    async fn generate_keys(
+        key_manager: &RwLock<KeyManager>,
+        epochs: Vec<u64>,
+    ) -> Vec<(u64, Result<GeneratedKey, KeyManagerError>)> {
+        let (realm, message_count, seed, verify_on_provision, attempts) = {
+            let key_manager = read_lock(key_manager);
+
+            (
+                key_manager.realm.clone(),
+                key_manager.message_count,
+                key_manager.seed.clone(),
+                key_manager.verify_on_provision,
+                key_manager.key_generation_attempts,
+            )
+        };
+
+        let tasks = epochs.iter().map(|&epoch| {
+            let realm = realm.clone();
+            let seed = seed.clone();
+
+            tokio::task::spawn_blocking(move || {
+                Self::generate_verified_key(
+                    &realm,
+                    epoch,
+                    message_count,
+                    seed.as_deref(),
+                    verify_on_provision,
+                    attempts,
+                )
+            })
         });
+
+        let generated = join_all(tasks).await;
+
+        epochs
+            .into_iter()
+            .zip(generated)
+            .map(|(epoch, result)| {
+                let key = result
+                    .map_err(|e| ProvisionError(format!("Key generation failed. {:?}", e)))
+                    .and_then(|key| key);
+
+                (epoch, key)
+            })
+            .collect()
+    }
+
+    // (current, next), honouring a forced rotation. The keys are stored under the seconds
+    // of their epoch
+    fn get_serving_epochs(&self) -> Result<(u64, u64), KeyManagerError> {
+        let (mut current_epoch, mut next_epoch) = self.get_key_epochs()?;
+
+        if let Some(advanced_epoch) = self.advanced_epoch.map(Epoch) {
+            if current_epoch < advanced_epoch {
+                current_epoch = advanced_epoch;
+                next_epoch = advanced_epoch.next(self.key_lifetime);
+            }
+        }
+
+        Ok((current_epoch.as_secs(), next_epoch.as_secs()))
+    }
+
+    // The current epoch and upcoming epochs (at least the next one)
+    fn get_provisioned_epochs(&self, current_epoch: u64) -> Vec<u64> {
+        (0..=self.prefetch_epochs.max(1))
+            .map(|i| current_epoch + i * self.key_lifetime.as_secs())
+            .collect()
+    }
+
+    // Epochs without a stored or importable key, lead seconds from now
+    fn get_pending_epochs(&self, lead: u64) -> Result<Vec<u64>, KeyManagerError> {
+        let (current_epoch, _) = self.get_serving_epochs()?;
+        let upcoming_epoch = epoch::current_epoch(
+            Self::now()? + lead,
+            self.key_lifetime,
+            self.epoch_offset,
+        )
+        .as_secs();
+
+        Ok(self
+            .get_provisioned_epochs(current_epoch.max(upcoming_epoch))
+            .into_iter()
+            .filter(|epoch| !self.key_exists(*epoch))
+            .filter(|epoch| !matches!(self.imported_key_path(*epoch), Some(path) if path.exists()))
+            .collect())
     }
 
     fn update_keys(&mut self) -> Result<(), KeyManagerError> {
-        let (current_epoch, next_epoch) = self.get_key_epochs();
+        let (current_epoch, next_epoch) = self.get_serving_epochs()?;
 
-        debug!("Current epoch: {}", current_epoch);
-        debug!("Next epoch: {}", next_epoch);
+        // Keep a forced rotation until the clock reaches its epoch
+        if matches!(self.advanced_epoch, Some(advanced_epoch) if advanced_epoch != current_epoch)
+        {
+            self.advanced_epoch = None;
+        }
 
-        // Provision current key
-        self.update_key(current_epoch)?;
-        // Provision the next key
-        self.update_key(next_epoch)?;
+        tracing::debug!(epoch = current_epoch, "Current epoch");
+        tracing::debug!(epoch = next_epoch, "Next epoch");
+
+        // Provision the current key and the keys for upcoming epochs
+        let epochs = self.get_provisioned_epochs(current_epoch);
+
+        for epoch in &epochs {
+            self.update_key(*epoch)?;
+        }
+
+        self.cache_key_profiles(&epochs)?;
+        self.store_epoch_markers(current_epoch, next_epoch)?;
+
+        let rotated = matches!(self.current_epoch, Some(epoch) if epoch != current_epoch);
 
         self.current_epoch = Some(current_epoch);
         self.next_epoch = Some(next_epoch);
 
+        if rotated {
+            self.notify_rotation(current_epoch);
+            self.publish_key_update();
+        }
+
+        self.purge_expired_keys(current_epoch)?;
+
         Ok(())
     }
 
+    // Rewrite the key ids stored by older versions. An interrupted migration is resumed
+    // on the next start, since a legacy entry is only deleted once its copy is stored
+    fn migrate_legacy_key_ids(&mut self) -> Result<(), KeyManagerError> {
+        let keys = self
+            .key_store
+            .keys()
+            .map_err(|e| DBError(format!("Could not read keys. {}", e)))?;
+
+        let mut migrated = 0;
+
+        for key in keys {
+            let key_id = match Self::parse_legacy_key_id(&key) {
+                Some(key_id) => key_id,
+                None => continue,
+            };
+
+            let value = self
+                .key_store
+                .get(&key)
+                .map_err(|e| DBError(format!("Could not read legacy key. {}", e)))?;
+
+            if let Some(value) = value {
+                self.key_store
+                    .put(key_id.as_bytes(), &value)
+                    .map_err(|e| DBError(format!("Could not migrate legacy key. {}", e)))?;
+            }
+
+            self.key_store
+                .delete(&key)
+                .map_err(|e| DBError(format!("Could not delete legacy key. {}", e)))?;
+
+            migrated += 1;
+        }
+
+        if migrated > 0 {
+            info!("Migrated {} entries to the current key ids.", migrated);
+        }
+
+        Ok(())
+    }
+
+    // Persist the configured revocations and load all the stored ones
+    fn load_revocations(&mut self, config: &KeyManagerConfig) -> Result<(), KeyManagerError> {
+        let keys = self
+            .key_store
+            .keys()
+            .map_err(|e| DBError(format!("Could not read keys. {}", e)))?;
+
+        for key in keys {
+            let epoch = match self.parse_realm_key_id(&key) {
+                Some((KIND_REVOCATION, epoch)) => epoch,
+                _ => continue,
+            };
+
+            let reason = self
+                .key_store
+                .get(&key)
+                .map_err(|e| DBError(format!("Could not get revocation. {}", e)))?
+                .unwrap_or_default();
+
+            self.revocations
+                .insert(epoch, String::from_utf8_lossy(&reason).to_string());
+        }
+
+        for revoked_epoch in &config.revoked_epochs {
+            if revoked_epoch.realm != self.realm {
+                continue;
+            }
+
+            if !self.revocations.contains_key(&revoked_epoch.epoch) {
+                self.revoke_epoch(revoked_epoch.epoch, revoked_epoch.reason.clone())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Compare the persisted epochs with the clock
+    fn check_epoch_markers(&self) -> Result<(), KeyManagerError> {
+        let (current_epoch, _) = self.get_key_epochs()?;
+
+        // The stored keys would no longer line up with the epochs
+        if let Some(key_lifetime) = self.get_marker(MARKER_KEY_LIFETIME)? {
+            if key_lifetime != self.key_lifetime.as_secs() {
+                return Err(ConfigError(format!(
+                    "Key lifetime changed from {} to {} seconds. Run --migrate-lifetime {} first.",
+                    key_lifetime,
+                    self.key_lifetime,
+                    self.key_lifetime.to_minutes()
+                )));
+            }
+        }
+
+        let last_epoch = match self.get_marker(MARKER_CURRENT_EPOCH)? {
+            Some(last_epoch) => Epoch(last_epoch),
+            None => return Ok(()),
+        };
+
+        if current_epoch < last_epoch {
+            warn!(
+                "Clock moved backwards. Current epoch {} is before the last known epoch {}.",
+                current_epoch, last_epoch
+            );
+        } else if current_epoch > last_epoch {
+            info!("Resuming at epoch {}, last known epoch {}.", current_epoch, last_epoch);
+        }
+
+        Ok(())
+    }
+
+    fn store_epoch_markers(
+        &mut self,
+        current_epoch: u64,
+        next_epoch: u64,
+    ) -> Result<(), KeyManagerError> {
+        self.store_marker(MARKER_CURRENT_EPOCH, current_epoch)?;
+        self.store_marker(MARKER_NEXT_EPOCH, next_epoch)?;
+        self.store_marker(MARKER_KEY_LIFETIME, self.key_lifetime.as_secs())
+    }
+
+    fn store_marker(&mut self, marker: &str, value: u64) -> Result<(), KeyManagerError> {
+        self.key_store
+            .put(self.create_marker_id(marker).as_bytes(), &value.to_be_bytes())
+            .map_err(|e| DBError(format!("Could not store {}. {}", marker, e)))
+    }
+
+    fn get_marker(&self, marker: &str) -> Result<Option<u64>, KeyManagerError> {
+        let result = self
+            .key_store
+            .get(self.create_marker_id(marker).as_bytes())
+            .map_err(|e| DBError(format!("Could not get {}. {}", marker, e)))?;
+
+        let value = match result {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let value: [u8; 8] = value
+            .as_slice()
+            .try_into()
+            .map_err(|_| DeserializationError(format!("Could not deserialize {}.", marker)))?;
+
+        Ok(Some(u64::from_be_bytes(value)))
+    }
+
+    // Keep only the profiles of the provisioned epochs in memory
+    fn cache_key_profiles(&mut self, epochs: &[u64]) -> Result<(), KeyManagerError> {
+        let mut key_profiles = HashMap::with_capacity(epochs.len());
+
+        for epoch in epochs {
+            let key_profile = match self.key_profiles.remove(epoch) {
+                Some(key_profile) => key_profile,
+                None => Arc::new(self.load_key_profile(*epoch)?),
+            };
+
+            key_profiles.insert(*epoch, key_profile);
+        }
+
+        self.key_profiles = key_profiles;
+
+        Ok(())
+    }
+
+    fn notify_rotation(&self, epoch: u64) {
+        let rotation_webhook = match &self.rotation_webhook {
+            Some(rotation_webhook) => rotation_webhook,
+            None => return,
+        };
+
+        let public_key = self.get_public_key(epoch).and_then(|public_key| {
+            public_key
+                .serialize()
+                .map_err(|e| SerializationError(format!("Could not serialize public key. {:?}", e)))
+        });
+
+        match public_key {
+            Ok(public_key) => rotation_webhook.notify(&self.realm, epoch, &public_key),
+            Err(e) => error!("Could not notify rotation. {}", e),
+        }
+    }
+
+    fn create_rotation_webhook(
+        config: &KeyManagerConfig,
+    ) -> Result<Option<RotationWebhook>, KeyManagerError> {
+        match &config.rotation_webhook_url {
+            Some(url) => Ok(Some(RotationWebhook::new(url.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    // Purge with the retention window of the current epoch
+    pub fn purge_now(&mut self) -> Result<usize, KeyManagerError> {
+        let current_epoch = self
+            .current_epoch
+            .ok_or_else(|| NotFoundError(format!("No current epoch.")))?;
+
+        self.purge_expired_keys(current_epoch)
+    }
+
+    // Returns the number of deleted entries
+    fn purge_expired_keys(&mut self, current_epoch: u64) -> Result<usize, KeyManagerError> {
+        let retention_epochs = match self.retention_epochs {
+            Some(retention_epochs) => retention_epochs,
+            None => return Ok(0),
+        };
+
+        let retention = retention_epochs.saturating_mul(self.key_lifetime.as_secs());
+        let oldest_epoch = current_epoch.saturating_sub(retention);
+
+        let mut purged = 0;
+
+        for kind in PURGED_KINDS {
+            let keys = self
+                .key_store
+                .keys_with_prefix(self.create_key_id_prefix(kind).as_bytes())
+                .map_err(|e| DBError(format!("Could not read keys. {}", e)))?;
+
+            for key in keys {
+                let epoch = match self.parse_realm_key_id(&key) {
+                    Some((key_kind, epoch)) if key_kind == kind => epoch,
+                    // Purged by the key manager of its realm
+                    _ => continue,
+                };
+
+                if epoch < oldest_epoch {
+                    self.key_store
+                        .delete(&key)
+                        .map_err(|e| DBError(format!("Could not purge expired key. {}", e)))?;
+
+                    purged += 1;
+                }
+            }
+        }
+
+        if purged == 0 {
+            return Ok(0);
+        }
+
+        tracing::info!(realm = %self.realm, epoch = oldest_epoch, purged, "Purged expired keys");
+
+        Ok(purged)
+    }
+
     fn update_key(&mut self, epoch: u64) -> Result<(), KeyManagerError> {
-        if !self.key_exists(epoch) {
+        if !self.key_exists(epoch) && !self.import_key(epoch)? {
             // Provision key
             self.provision_key(epoch)?;
         }
@@ -112,19 +1180,259 @@ impl KeyManager {
         Ok(())
     }
 
-    fn provision_key(&mut self, epoch: u64) -> Result<(), KeyManagerError> {
-        let mut rng = thread_rng();
+    fn provision_key(&mut self, epoch: u64) -> Result<(), KeyManagerError> {
+        let key = Self::generate_verified_key(
+            &self.realm,
+            epoch,
+            self.message_count,
+            self.seed.as_deref(),
+            self.verify_on_provision,
+            self.key_generation_attempts,
+        )?;
+
+        self.store_provisioned_key(epoch, &key)
+    }
+
+    fn store_provisioned_key(
+        &mut self,
+        epoch: u64,
+        (params, signing_key, public_key): &GeneratedKey,
+    ) -> Result<(), KeyManagerError> {
+        self.store_key(epoch, params, signing_key, public_key)?;
+
+        let fingerprint = Self::public_key_fingerprint(public_key)?;
+        tracing::info!(
+            realm = %self.realm,
+            epoch,
+            fingerprint = %fingerprint,
+            "Provisioned key"
+        );
+
+        Ok(())
+    }
+
+    // CPU bound, runs without access to the key store
+    fn generate_verified_key(
+        realm: &str,
+        epoch: u64,
+        message_count: usize,
+        seed: Option<&[u8]>,
+        verify_on_provision: bool,
+        attempts: u8,
+    ) -> Result<GeneratedKey, KeyManagerError> {
+        // Retries draw further from the same rng, so seeded keys stay reproducible
+        match seed {
+            Some(seed) => Self::generate_key_attempts(
+                epoch,
+                message_count,
+                verify_on_provision,
+                attempts,
+                &mut Self::create_seeded_rng(seed, realm, epoch),
+            ),
+            None => Self::generate_key_attempts(
+                epoch,
+                message_count,
+                verify_on_provision,
+                attempts,
+                &mut thread_rng(),
+            ),
+        }
+    }
+
+    // A key failing the self-test is discarded and generated again
+    fn generate_key_attempts<R: RngCore + CryptoRng>(
+        epoch: u64,
+        message_count: usize,
+        verify_on_provision: bool,
+        attempts: u8,
+        rng: &mut R,
+    ) -> Result<GeneratedKey, KeyManagerError> {
+        let mut attempt = 1;
+
+        loop {
+            // Generate the params and keys
+            let (params, signing_key, public_key) = Self::generate_key(message_count, rng);
+
+            // Never store a key that can not issue valid tokens
+            if !verify_on_provision {
+                return Ok((params, signing_key, public_key));
+            }
+
+            match Self::self_test(&params, &signing_key, &public_key) {
+                Ok(()) => return Ok((params, signing_key, public_key)),
+                Err(e) if attempt < attempts => {
+                    tracing::debug!(epoch, attempt, "Generated key failed the self-test. {}", e);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(SelfTestError(format!(
+                        "Key for epoch {} failed after {} attempts. {}",
+                        epoch, attempts, e
+                    )));
+                }
+            }
+        }
+    }
+
+    fn store_key(
+        &mut self,
+        epoch: u64,
+        params: &PsParams,
+        signing_key: &PsSigningKey,
+        public_key: &PsPublicKey,
+    ) -> Result<(), KeyManagerError> {
+        self.store_key_params(params, &self.create_key_params_id(epoch))?;
+        self.store_signing_key(signing_key, &self.create_signing_key_id(epoch))?;
+        self.store_public_key(public_key, &self.create_public_key_id(epoch))?;
+        self.store_message_count(self.message_count, &self.create_message_count_id(epoch))?;
+        self.store_key_scheme(KEY_SCHEME, &self.create_key_scheme_id(epoch))?;
+        self.store_key_format_version(KEY_FORMAT_VERSION, &self.create_key_format_id(epoch))
+    }
+
+    fn public_key_fingerprint(public_key: &PsPublicKey) -> Result<String, KeyManagerError> {
+        let public_key = public_key
+            .serialize()
+            .map_err(|e| SerializationError(format!("Could not serialize public key. {:?}", e)))?;
+
+        Ok(key_fingerprint(&public_key))
+    }
+
+    // Keys of a named realm are imported from the subdirectory of the realm
+    fn imported_key_path(&self, epoch: u64) -> Option<PathBuf> {
+        self.imported_keys_dir.as_ref().map(|imported_keys_dir| {
+            imported_keys_dir
+                .join(&self.realm)
+                .join(epoch.to_string())
+                .with_extension(IMPORTED_KEYS_EXTENSION)
+        })
+    }
+
+    // Returns false if there is no key to import for the epoch
+    fn import_key(&mut self, epoch: u64) -> Result<bool, KeyManagerError> {
+        let path = match self.imported_key_path(epoch) {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        let contents = fs::read_to_string(&path).map_err(|e| {
+            ConfigError(format!("Could not read imported key {:?}. {}", path, e))
+        })?;
+
+        let mut lines = contents.lines().map(str::trim).filter(|line| !line.is_empty());
+        let mut next_line = |name: &str| -> Result<Vec<u8>, KeyManagerError> {
+            let line = lines.next().ok_or_else(|| {
+                DeserializationError(format!("Imported key {:?} is missing the {}.", path, name))
+            })?;
+
+            hex::decode(line).map_err(|e| {
+                DeserializationError(format!("Could not decode imported {}. {:?}", name, e))
+            })
+        };
+
+        let params = PsParams::deserialize(&next_line("params")?).map_err(|e| {
+            DeserializationError(format!("Could not deserialize imported params. {:?}", e))
+        })?;
+        let signing_key = PsSigningKey::deserialize(&next_line("signing key")?).map_err(|e| {
+            DeserializationError(format!("Could not deserialize imported signing key. {:?}", e))
+        })?;
+        let public_key_serialized = next_line("public key")?;
+        let public_key = PsPublicKey::deserialize(&public_key_serialized).map_err(|e| {
+            DeserializationError(format!("Could not deserialize imported public key. {:?}", e))
+        })?;
+
+        // The public key must belong to the signing key
+        let derived_public_key = signing_key
+            .derive_public_key(&params)
+            .serialize()
+            .map_err(|e| SerializationError(format!("Could not serialize public key. {:?}", e)))?;
+
+        if derived_public_key != public_key_serialized {
+            return Err(ConfigError(format!(
+                "Imported public key {:?} does not match its signing key.",
+                path
+            )));
+        }
+
+        self.store_key(epoch, &params, &signing_key, &public_key)?;
+
+        let fingerprint = key_fingerprint(&public_key_serialized);
+        tracing::info!(realm = %self.realm, epoch, fingerprint = %fingerprint, "Imported key");
+
+        Ok(true)
+    }
+
+    fn generate_key<R: RngCore + CryptoRng>(message_count: usize, rng: &mut R) -> GeneratedKey {
+        let params = PsParams::generate(rng);
+        let signing_key = PsSigningKey::generate(message_count, &params, rng);
+        let public_key = signing_key.derive_public_key(&params);
+
+        (params, signing_key, public_key)
+    }
+
+    // Issues a token for a dummy request and verifies it with the public key
+    fn self_test(
+        params: &PsParams,
+        signing_key: &PsSigningKey,
+        public_key: &PsPublicKey,
+    ) -> Result<(), String> {
+        let mut rng = thread_rng();
+
+        let token_id = Fr::random(&mut rng);
+        let blinding = Fr::random(&mut rng);
+
+        let token_request =
+            create_root_token_request(&token_id, &blinding, public_key, params, &mut rng)
+                .map_err(|e| format!("Could not create token request. {:?}", e))?;
+
+        let token_response =
+            issue_root_token(&token_request, signing_key, public_key, params, &mut rng)
+                .map_err(|e| format!("Could not issue token. {:?}", e))?;
+
+        let root_token =
+            complete_root_token(&token_response, &token_id, &blinding, public_key, params)
+                .map_err(|e| format!("Could not complete token. {:?}", e))?;
+
+        let valid = root_token
+            .verify(public_key, params)
+            .map_err(|e| format!("Could not verify token. {:?}", e))?;
+
+        if !valid {
+            return Err(format!("Token verification failed."));
+        }
+
+        Ok(())
+    }
+
+    // Derive a per realm and epoch rng from the seed. The keys of the default realm are
+    // derived as before realms existed
+    fn create_seeded_rng(seed: &[u8], realm: &str, epoch: u64) -> ChaCha20Rng {
+        let mut hasher = Sha256::new();
+        hasher.update(seed);
+        if !realm.is_empty() {
+            hasher.update(realm.as_bytes());
+            hasher.update([KEY_ID_DELIMITER as u8]);
+        }
+        hasher.update(epoch.to_be_bytes());
 
-        // Generate the params and keys
-        let params = PsParams::generate(&mut rng);
-        let signing_key = PsSigningKey::generate(1, &params, &mut rng);
-        let public_key = signing_key.derive_public_key(&params);
+        ChaCha20Rng::from_seed(hasher.finalize().into())
+    }
 
-        self.store_key_params(&params, &Self::create_key_params_id(epoch))?;
-        self.store_signing_key(&signing_key, &Self::create_signing_key_id(epoch))?;
-        self.store_public_key(&public_key, &Self::create_public_key_id(epoch))?;
+    fn decode_seed(seed: &str) -> Result<Vec<u8>, KeyManagerError> {
+        let seed =
+            hex::decode(seed).map_err(|e| ConfigError(format!("Invalid seed. {:?}", e)))?;
 
-        Ok(())
+        if seed.len() < MIN_SEED_LENGTH {
+            return Err(ConfigError(format!(
+                "Seed must be at least {} bytes.",
+                MIN_SEED_LENGTH
+            )));
+        }
+
+        Ok(seed)
     }
 
     fn store_key_params(
@@ -135,10 +1443,11 @@ impl KeyManager {
         let params_serialized = params
             .serialize()
             .map_err(|e| SerializationError(format!("Could not serialize params. {:?}", e)))?;
+        let params_serialized = self.encrypt(params_id, params_serialized)?;
 
-        self.db
-            .put(params_id, &params_serialized)
-            .map_err(|e| DBError(format!("Could not store params. {:?}", e)))?;
+        self.key_store
+            .put(params_id.as_bytes(), &params_serialized)
+            .map_err(|e| DBError(format!("Could not store params. {}", e)))?;
 
         Ok(())
     }
@@ -152,10 +1461,11 @@ impl KeyManager {
         let key_serialized = signing_key
             .serialize()
             .map_err(|e| SerializationError(format!("Could not serialize signing key. {:?}", e)))?;
+        let key_serialized = self.encrypt(key_id, key_serialized)?;
 
-        self.db
-            .put(key_id, &key_serialized)
-            .map_err(|e| DBError(format!("Could not store signing key. {:?}", e)))?;
+        self.key_store
+            .put(key_id.as_bytes(), &key_serialized)
+            .map_err(|e| DBError(format!("Could not store signing key. {}", e)))?;
 
         Ok(())
     }
@@ -168,133 +1478,987 @@ impl KeyManager {
         let key_serialized = public_key
             .serialize()
             .map_err(|e| SerializationError(format!("Could not serialize public key. {:?}", e)))?;
+        let key_serialized = self.encrypt(key_id, key_serialized)?;
+
+        self.key_store
+            .put(key_id.as_bytes(), &key_serialized)
+            .map_err(|e| DBError(format!("Could not store public keys. {}", e)))?;
+
+        Ok(())
+    }
+
+    fn store_message_count(
+        &mut self,
+        message_count: usize,
+        count_id: &String,
+    ) -> Result<(), KeyManagerError> {
+        let count_serialized = (message_count as u64).to_be_bytes();
 
-        self.db
-            .put(key_id, &key_serialized)
-            .map_err(|e| DBError(format!("Could not store public keys. {:?}", e)))?;
+        self.key_store
+            .put(count_id.as_bytes(), &count_serialized)
+            .map_err(|e| DBError(format!("Could not store message count. {}", e)))?;
 
         Ok(())
     }
 
-    fn get_key_params(&self, key_id: &String) -> Result<PsParams, KeyManagerError> {
+    fn store_key_scheme(
+        &mut self,
+        key_scheme: &str,
+        scheme_id: &String,
+    ) -> Result<(), KeyManagerError> {
+        self.key_store
+            .put(scheme_id.as_bytes(), key_scheme.as_bytes())
+            .map_err(|e| DBError(format!("Could not store key scheme. {}", e)))
+    }
+
+    fn store_key_format_version(
+        &mut self,
+        key_format_version: u8,
+        format_id: &String,
+    ) -> Result<(), KeyManagerError> {
+        self.key_store
+            .put(format_id.as_bytes(), &[key_format_version])
+            .map_err(|e| DBError(format!("Could not store key format version. {}", e)))
+    }
+
+    fn get_key_format_version(&self, epoch: u64) -> Result<u8, KeyManagerError> {
+        let result = self
+            .key_store
+            .get(self.create_key_format_id(epoch).as_bytes())
+            .map_err(|e| DBError(format!("Could not get key format version. {}", e)))?;
+
+        match result.as_deref() {
+            Some([key_format_version]) => Ok(*key_format_version),
+            Some(_) => Err(DeserializationError(format!(
+                "Could not deserialize the key format version of epoch {}.",
+                epoch
+            ))),
+            None => Ok(LEGACY_KEY_FORMAT_VERSION),
+        }
+    }
+
+    fn get_key_scheme(&self, epoch: u64) -> Result<String, KeyManagerError> {
+        let result = self
+            .key_store
+            .get(self.create_key_scheme_id(epoch).as_bytes())
+            .map_err(|e| DBError(format!("Could not get key scheme. {}", e)))?;
+
+        match result {
+            Some(key_scheme) => String::from_utf8(key_scheme).map_err(|e| {
+                DeserializationError(format!(
+                    "Could not deserialize the key scheme of epoch {}. {}",
+                    epoch, e
+                ))
+            }),
+            None => Ok(KEY_SCHEME.to_string()),
+        }
+    }
+
+    fn get_key_lifetime_of(&self, epoch: u64) -> Result<Seconds, KeyManagerError> {
+        Ok(self.get_stored_key_lifetime(epoch)?.unwrap_or(self.key_lifetime))
+    }
+
+    // Set only for keys served under a lifetime before a migration
+    fn get_stored_key_lifetime(&self, epoch: u64) -> Result<Option<Seconds>, KeyManagerError> {
+        let result = self
+            .key_store
+            .get(self.create_key_lifetime_id(epoch).as_bytes())
+            .map_err(|e| DBError(format!("Could not get key lifetime. {}", e)))?;
+
+        let key_lifetime = match result {
+            Some(key_lifetime) => key_lifetime,
+            None => return Ok(None),
+        };
+
+        let key_lifetime: [u8; 8] = key_lifetime.as_slice().try_into().map_err(|_| {
+            DeserializationError(format!(
+                "Could not deserialize the key lifetime of epoch {}.",
+                epoch
+            ))
+        })?;
+
+        Ok(Some(Seconds(u64::from_be_bytes(key_lifetime))))
+    }
+
+    // Serialized key material, decrypted without deserializing it
+    fn get_stored(&self, key_id: &str, name: &str) -> Result<Vec<u8>, KeyManagerError> {
+        let value = self
+            .key_store
+            .get(key_id.as_bytes())
+            .map_err(|e| DBError(format!("Could not get {}. {}", name, e)))?
+            .ok_or_else(|| NotFoundError(format!("The {} of {} was not found.", name, key_id)))?;
+
+        self.decrypt(key_id, value)
+    }
+
+    // Key material is only written encrypted if a DB encryption key is configured
+    fn encrypt(&self, key_id: &str, value: Vec<u8>) -> Result<Vec<u8>, KeyManagerError> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(key_id.as_bytes(), &value),
+            None => Ok(value),
+        }
+    }
+
+    fn decrypt(&self, key_id: &str, value: Vec<u8>) -> Result<Vec<u8>, KeyManagerError> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(key_id.as_bytes(), &value),
+            None => Ok(value),
+        }
+    }
+
+    fn get_key_params(&self, epoch: u64) -> Result<PsParams, KeyManagerError> {
+        let params_id = self.create_key_params_id(epoch);
         let result = self
-            .db
-            .get(key_id)
-            .map_err(|e| DBError(format!("Could not get key params. {:?}", e)))?;
+            .key_store
+            .get(params_id.as_bytes())
+            .map_err(|e| DBError(format!("Could not get key params. {}", e)))?;
 
         let params = match result {
-            Some(params) => params,
-            None => return Err(NotFoundError(format!("Key params not found."))),
+            Some(params) => self.decrypt(&params_id, params)?,
+            None => return Err(NotFoundError(format!("Key params of epoch {} not found.", epoch))),
         };
 
         let params = PsParams::deserialize(&params).map_err(|e| {
-            DeserializationError(format!("Could not deserialize key params. {:?}", e))
+            DeserializationError(format!(
+                "Could not deserialize the key params of epoch {}. {:?}",
+                epoch, e
+            ))
         })?;
 
         Ok(params)
     }
 
-    fn get_public_key(&self, key_id: &String) -> Result<PsPublicKey, KeyManagerError> {
+    fn get_public_key(&self, epoch: u64) -> Result<PsPublicKey, KeyManagerError> {
+        let key_id = self.create_public_key_id(epoch);
         let result = self
-            .db
-            .get(key_id)
-            .map_err(|e| DBError(format!("Could not get public key. {:?}", e)))?;
+            .key_store
+            .get(key_id.as_bytes())
+            .map_err(|e| DBError(format!("Could not get public key. {}", e)))?;
 
         let public_key = match result {
-            Some(key) => key,
-            None => return Err(NotFoundError(format!("Public key not found."))),
+            Some(key) => self.decrypt(&key_id, key)?,
+            None => return Err(NotFoundError(format!("Public key of epoch {} not found.", epoch))),
         };
 
         let public_key = PsPublicKey::deserialize(&public_key).map_err(|e| {
-            DeserializationError(format!("Could not deserialize public key. {:?}", e))
+            DeserializationError(format!(
+                "Could not deserialize the public key of epoch {}. {:?}",
+                epoch, e
+            ))
         })?;
 
         Ok(public_key)
     }
 
-    fn get_signing_key(&self, key_id: &String) -> Result<PsSigningKey, KeyManagerError> {
+    fn get_signing_key(&self, epoch: u64) -> Result<PsSigningKey, KeyManagerError> {
+        let key_id = self.create_signing_key_id(epoch);
         let result = self
-            .db
-            .get(key_id)
-            .map_err(|e| DBError(format!("Could not get signing key. {:?}", e)))?;
+            .key_store
+            .get(key_id.as_bytes())
+            .map_err(|e| DBError(format!("Could not get signing key. {}", e)))?;
 
         let signing_key = match result {
-            Some(key) => key,
-            None => return Err(NotFoundError(format!("Signing key not found."))),
+            Some(key) => self.decrypt(&key_id, key)?,
+            None => return Err(NotFoundError(format!("Signing key of epoch {} not found.", epoch))),
         };
 
+        // The key bytes are never part of the message
         let signing_key = PsSigningKey::deserialize(&signing_key).map_err(|e| {
-            DeserializationError(format!("Could not deserialize signing key. {:?}", e))
+            DeserializationError(format!(
+                "Could not deserialize the signing key of epoch {}. {:?}",
+                epoch, e
+            ))
         })?;
 
         Ok(signing_key)
     }
 
+    fn get_message_count(&self, epoch: u64) -> Result<usize, KeyManagerError> {
+        let result = self
+            .key_store
+            .get(self.create_message_count_id(epoch).as_bytes())
+            .map_err(|e| DBError(format!("Could not get message count. {}", e)))?;
+
+        // Keys provisioned by older versions only support a single message
+        let message_count = match result {
+            Some(count) => count,
+            None => return Ok(LEGACY_MESSAGE_COUNT),
+        };
+
+        let message_count: [u8; 8] = message_count.as_slice().try_into().map_err(|_| {
+            DeserializationError(format!(
+                "Could not deserialize the message count of epoch {}.",
+                epoch
+            ))
+        })?;
+
+        Ok(u64::from_be_bytes(message_count) as usize)
+    }
+
     fn key_exists(&self, epoch: u64) -> bool {
-        self.db.key_may_exist(Self::create_public_key_id(epoch))
-            && self.db.key_may_exist(Self::create_signing_key_id(epoch))
-            && self.db.key_may_exist(Self::create_key_params_id(epoch))
+        self.key_store
+            .key_may_exist(self.create_public_key_id(epoch).as_bytes())
+            && self
+                .key_store
+                .key_may_exist(self.create_signing_key_id(epoch).as_bytes())
+            && self
+                .key_store
+                .key_may_exist(self.create_key_params_id(epoch).as_bytes())
     }
 
     // (current, next)
-    fn get_key_epochs(&self) -> (u64, u64) {
-        let now = SystemTime::now();
-        let now = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    fn get_key_epochs(&self) -> Result<(Epoch, Epoch), KeyManagerError> {
+        Ok(epoch::key_epochs(
+            Self::now()?,
+            self.key_lifetime,
+            self.epoch_offset,
+        ))
+    }
+
+    fn now() -> Result<u64, KeyManagerError> {
+        epoch::now()
+            .map_err(|e| ClockError(format!("System clock is before the unix epoch. {}", e)))
+    }
+
+    // (realm, kind, epoch) of a key id. Markers are not key ids
+    fn parse_key_id(key: &[u8]) -> Option<(&str, &str, u64)> {
+        let key = std::str::from_utf8(key).ok()?;
+        let (key, epoch) = key.rsplit_once(KEY_ID_DELIMITER)?;
+        let (realm, kind) = key.split_once(KEY_ID_DELIMITER).unwrap_or((DEFAULT_REALM, key));
+
+        Some((realm, kind, epoch.parse().ok()?))
+    }
 
-        let current_epoch = now - (now % self.key_lifetime);
+    // (kind, epoch) of a key id of this realm
+    fn parse_realm_key_id<'a>(&self, key: &'a [u8]) -> Option<(&'a str, u64)> {
+        match Self::parse_key_id(key)? {
+            (realm, kind, epoch) if realm == self.realm => Some((kind, epoch)),
+            _ => None,
+        }
+    }
+
+    // Current key id of an entry stored by an older version
+    fn parse_legacy_key_id(key: &[u8]) -> Option<String> {
+        let key = std::str::from_utf8(key).ok()?;
+        let (epoch, suffix) = key.split_once('-')?;
+        let epoch = epoch.parse().ok()?;
+
+        let (_, kind) = LEGACY_SUFFIXES
+            .iter()
+            .find(|(legacy_suffix, _)| *legacy_suffix == suffix)?;
+
+        Some(Self::create_key_id(DEFAULT_REALM, kind, epoch))
+    }
+
+    fn create_key_id(realm: &str, kind: &str, epoch: u64) -> String {
+        if realm.is_empty() {
+            return format!("{}{}{}", kind, KEY_ID_DELIMITER, epoch);
+        }
 
-        let next_epoch = current_epoch + self.key_lifetime;
+        format!("{}{}{}{}{}", realm, KEY_ID_DELIMITER, kind, KEY_ID_DELIMITER, epoch)
+    }
+
+    // Shared by the key ids of a kind in this realm
+    fn create_key_id_prefix(&self, kind: &str) -> String {
+        if self.realm.is_empty() {
+            return format!("{}{}", kind, KEY_ID_DELIMITER);
+        }
 
-        (current_epoch, next_epoch)
+        format!("{}{}{}{}", self.realm, KEY_ID_DELIMITER, kind, KEY_ID_DELIMITER)
     }
 
-    fn create_key_params_id(epoch: u64) -> String {
-        format!("{}-{}", epoch, SUFFIX_PARAMS)
+    // Markers of a named realm are suffixed with the realm, so they keep the marker prefix
+    fn create_marker_id(&self, marker: &str) -> String {
+        if self.realm.is_empty() {
+            return marker.to_string();
+        }
+
+        format!("{}{}{}", marker, KEY_ID_DELIMITER, self.realm)
     }
 
-    fn create_signing_key_id(epoch: u64) -> String {
-        format!("{}-{}", epoch, SUFFIX_SIGNING_KEY)
+    fn create_key_params_id(&self, epoch: u64) -> String {
+        Self::create_key_id(&self.realm, KIND_PARAMS, epoch)
     }
 
-    fn create_public_key_id(epoch: u64) -> String {
-        format!("{}-{}", epoch, SUFFIX_PUBLIC_KEY)
+    fn create_signing_key_id(&self, epoch: u64) -> String {
+        Self::create_key_id(&self.realm, KIND_SIGNING_KEY, epoch)
     }
 
-    // Connect to the database
-    fn connect_to_db(config: &KeyManagerConfig) -> Result<DB, KeyManagerError> {
-        let mut options = Options::default();
-        options.create_if_missing(true);
+    fn create_public_key_id(&self, epoch: u64) -> String {
+        Self::create_key_id(&self.realm, KIND_PUBLIC_KEY, epoch)
+    }
 
-        let db = DB::open(&options, &config.key_file)
-            .map_err(|e| DBError(format!("Could not connect to the keys database. {:?}", e)))?;
+    fn create_message_count_id(&self, epoch: u64) -> String {
+        Self::create_key_id(&self.realm, KIND_MESSAGE_COUNT, epoch)
+    }
 
-        Ok(db)
+    fn create_key_scheme_id(&self, epoch: u64) -> String {
+        Self::create_key_id(&self.realm, KIND_KEY_SCHEME, epoch)
     }
 
-    fn calculate_next_key_update(key_lifetime: u64) -> Instant {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let now_instant = Instant::now();
+    fn create_key_format_id(&self, epoch: u64) -> String {
+        Self::create_key_id(&self.realm, KIND_KEY_FORMAT, epoch)
+    }
+
+    fn create_key_lifetime_id(&self, epoch: u64) -> String {
+        Self::create_key_id(&self.realm, KIND_KEY_LIFETIME, epoch)
+    }
 
-        let current_epoch = now - (now % key_lifetime);
-        let next_epoch = current_epoch + key_lifetime;
+    fn create_revocation_id(&self, epoch: u64) -> String {
+        Self::create_key_id(&self.realm, KIND_REVOCATION, epoch)
+    }
 
-        // Get next epoch as instant
-        let time_until_next_epoch = next_epoch - now;
-        let next_epoch = now_instant + Duration::from_secs(time_until_next_epoch);
+    fn calculate_next_key_update(
+        key_lifetime: Seconds,
+        epoch_offset: Seconds,
+    ) -> Result<Instant, KeyManagerError> {
+        let now = Self::now()?;
+
+        Ok(epoch::next_key_update_at(
+            now,
+            Instant::now(),
+            key_lifetime,
+            epoch_offset,
+        ))
+    }
 
-        next_epoch
+    // Key updates are aligned to the epochs, so wait for the clock to become sane
+    async fn wait_for_next_key_update(key_lifetime: Seconds, epoch_offset: Seconds) -> Instant {
+        loop {
+            match Self::calculate_next_key_update(key_lifetime, epoch_offset) {
+                Ok(next_key_update) => return next_key_update,
+                Err(e) => {
+                    error!("Could not schedule key updates. {}", e);
+                    tokio::time::sleep(Duration::from_secs(KEY_UPDATE_RETRY_INTERVAL)).await;
+                }
+            }
+        }
     }
 }
 
+// A panic while holding the lock must not take down every later request, so the
+// poisoned guard is recovered. Key updates are retried by the scheduler
+pub fn read_lock(key_manager: &RwLock<KeyManager>) -> RwLockReadGuard<'_, KeyManager> {
+    key_manager.read().unwrap_or_else(|e| {
+        warn!("Recovering poisoned key manager lock.");
+        PoisonError::into_inner(e)
+    })
+}
+
+pub fn write_lock(key_manager: &RwLock<KeyManager>) -> RwLockWriteGuard<'_, KeyManager> {
+    key_manager.write().unwrap_or_else(|e| {
+        warn!("Recovering poisoned key manager lock.");
+        PoisonError::into_inner(e)
+    })
+}
+
 pub struct KeyProfile {
-    pub params: PsParams,
+    pub epoch: Epoch,
+
+    pub material: KeyMaterial,
+
+    pub key_lifetime: Seconds,
+
+    pub key_scheme: String,
+
+    pub key_format_version: u8,
+}
+
+// Serialized public key and params of an epoch
+pub struct PublicKeyEntry {
+    pub epoch: u64,
+
+    pub public_key: Vec<u8>,
+
+    pub params: Vec<u8>,
+
+    pub revocation_reason: Option<String>,
+
+    pub key_scheme: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryKeyStore;
+    use config::{Config, File, FileFormat};
+
+    const TEST_CONFIG: &str = "
+host: 127.0.0.1
+port: 30051
+key_file: keys.db
+key_lifetime: 10
+retention_epochs: 2
+tls_cert: server.pem
+tls_key: server.key
+client_ca: auth_ca.pem
+";
+
+    // Key lifetime in seconds
+    const KEY_LIFETIME: u64 = 600;
+
+    const TEST_SEED: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn create_key_manager() -> KeyManager {
+        create_key_manager_with_config("")
+    }
+
+    fn create_key_manager_with_config(extra_config: &str) -> KeyManager {
+        let config = create_config(extra_config);
+
+        KeyManager::new(Arc::new(MemoryKeyStore::default()), &config, DEFAULT_REALM).unwrap()
+    }
+
+    fn create_config(extra_config: &str) -> KeyManagerConfig {
+        let mut config = Config::new();
+        config
+            .merge(File::from_str(
+                &format!("{}{}", TEST_CONFIG, extra_config),
+                FileFormat::Yaml,
+            ))
+            .unwrap();
+
+        config.try_into().unwrap()
+    }
+
+    #[test]
+    fn provision_key_stores_key_profile() {
+        let mut key_manager = create_key_manager();
+
+        key_manager.provision_key(KEY_LIFETIME).unwrap();
+
+        assert!(key_manager.key_exists(KEY_LIFETIME));
+
+        let key_profile = key_manager.get_key_profile(KEY_LIFETIME).unwrap();
+        assert_eq!(key_profile.epoch, Epoch(KEY_LIFETIME));
+        assert_eq!(key_profile.material.message_count, 1);
+        assert_eq!(key_profile.key_lifetime, Seconds(KEY_LIFETIME));
+    }
+
+    #[test]
+    fn legacy_key_ids_are_migrated() {
+        let mut key_manager = create_key_manager();
+
+        key_manager.provision_key(KEY_LIFETIME).unwrap();
+
+        // Store the key under the ids of older versions
+        for key in key_manager.key_store.keys().unwrap() {
+            let (_, kind, epoch) = KeyManager::parse_key_id(&key).unwrap();
+
+            // Not stored by older versions
+            let (legacy_suffix, _) = match LEGACY_SUFFIXES
+                .iter()
+                .find(|(_, legacy_kind)| *legacy_kind == kind)
+            {
+                Some(legacy_suffix) => legacy_suffix,
+                None => continue,
+            };
+
+            let value = key_manager.key_store.get(&key).unwrap().unwrap();
+            let legacy_key = format!("{}-{}", epoch, legacy_suffix);
+
+            key_manager.key_store.put(legacy_key.as_bytes(), &value).unwrap();
+            key_manager.key_store.delete(&key).unwrap();
+        }
+
+        assert!(!key_manager.key_exists(KEY_LIFETIME));
+
+        key_manager.migrate_legacy_key_ids().unwrap();
+
+        assert!(key_manager.key_exists(KEY_LIFETIME));
+        assert!(key_manager.load_key_profile(KEY_LIFETIME).is_ok());
+    }
+
+    #[test]
+    fn key_material_is_encrypted_at_rest() {
+        let mut key_manager = create_key_manager();
+
+        key_manager.provision_key(KEY_LIFETIME).unwrap();
 
-    pub signing_key: PsSigningKey,
+        let signing_key_id = key_manager.create_signing_key_id(KEY_LIFETIME);
+        let unencrypted = key_manager.key_store.get(signing_key_id.as_bytes()).unwrap();
 
-    pub public_key: PsPublicKey,
+        // Encrypt the unencrypted params, signing key and public key
+        key_manager.cipher = Some(KeyCipher::new(&[1; 32]).unwrap());
+        assert!(matches!(key_manager.load_key_profile(KEY_LIFETIME), Err(EncryptionError(_))));
 
-    pub key_lifetime: u64,
+        assert_eq!(key_manager.reencrypt_keys(None).unwrap(), 3);
+        assert_ne!(key_manager.key_store.get(signing_key_id.as_bytes()).unwrap(), unencrypted);
+        assert!(key_manager.load_key_profile(KEY_LIFETIME).is_ok());
+
+        // Rotate the encryption key
+        let previous = key_manager.cipher.replace(KeyCipher::new(&[2; 32]).unwrap());
+        assert!(key_manager.load_key_profile(KEY_LIFETIME).is_err());
+
+        assert_eq!(key_manager.reencrypt_keys(previous.as_ref()).unwrap(), 3);
+        assert!(key_manager.load_key_profile(KEY_LIFETIME).is_ok());
+    }
+
+    #[test]
+    fn key_lifetime_change_requires_migration() {
+        let mut key_manager = create_key_manager();
+
+        key_manager.update_keys().unwrap();
+        let current_epoch = key_manager.get_current_epoch().unwrap();
+
+        key_manager.key_lifetime = Seconds(2 * KEY_LIFETIME);
+        assert!(matches!(key_manager.check_epoch_markers(), Err(ConfigError(_))));
+
+        key_manager.pin_key_lifetimes(Seconds(2 * KEY_LIFETIME)).unwrap();
+        key_manager.check_epoch_markers().unwrap();
+
+        // One of the two keys is on the new epoch boundaries and served again
+        let mut key_lifetimes: Vec<Seconds> = [current_epoch, current_epoch + KEY_LIFETIME]
+            .iter()
+            .map(|epoch| key_manager.load_key_profile(*epoch).unwrap().key_lifetime)
+            .collect();
+        key_lifetimes.sort();
+
+        assert_eq!(key_lifetimes, vec![Seconds(KEY_LIFETIME), Seconds(2 * KEY_LIFETIME)]);
+    }
+
+    #[test]
+    fn provisioned_key_profiles_are_cached() {
+        let mut key_manager = create_key_manager();
+
+        key_manager.update_keys().unwrap();
+
+        let current_epoch = key_manager.get_current_epoch().unwrap();
+        let key_profile = key_manager.get_key_profile(current_epoch).unwrap();
+
+        assert!(Arc::ptr_eq(
+            &key_profile,
+            &key_manager.get_key_profile(current_epoch).unwrap()
+        ));
+    }
+
+    #[test]
+    fn self_test_passes_for_generated_key() {
+        let (params, signing_key, public_key) = KeyManager::generate_key(1, &mut thread_rng());
+
+        assert!(KeyManager::self_test(&params, &signing_key, &public_key).is_ok());
+    }
+
+    #[test]
+    fn self_test_fails_for_mismatched_public_key() {
+        let (params, signing_key, _) = KeyManager::generate_key(1, &mut thread_rng());
+        let other_public_key =
+            PsSigningKey::generate(1, &params, &mut thread_rng()).derive_public_key(&params);
+
+        assert!(KeyManager::self_test(&params, &signing_key, &other_public_key).is_err());
+    }
+
+    #[tokio::test]
+    async fn rotate_now_replaces_next_key() {
+        let key_manager = Arc::new(RwLock::new(create_key_manager()));
+        write_lock(&key_manager).update_keys().unwrap();
+
+        let next_epoch = read_lock(&key_manager).get_next_epoch().unwrap();
+        let next_key = read_lock(&key_manager).get_key_profile(next_epoch).unwrap();
+        let public_key = next_key.material.public_key.serialize();
+
+        let rotated_key = KeyManager::rotate_now(&key_manager, false).await.unwrap();
+
+        assert_eq!(rotated_key.epoch, Epoch(next_epoch));
+        assert_ne!(rotated_key.material.public_key.serialize().unwrap(), public_key.unwrap());
+        assert_eq!(read_lock(&key_manager).get_next_epoch(), Some(next_epoch));
+    }
+
+    #[tokio::test]
+    async fn rotate_now_advances_current_epoch() {
+        let key_manager = Arc::new(RwLock::new(create_key_manager()));
+        write_lock(&key_manager).update_keys().unwrap();
+
+        let next_epoch = read_lock(&key_manager).get_next_epoch().unwrap();
+
+        KeyManager::rotate_now(&key_manager, true).await.unwrap();
+
+        assert_eq!(
+            read_lock(&key_manager).get_current_epoch(),
+            Some(next_epoch)
+        );
+        assert!(read_lock(&key_manager).key_exists(next_epoch + KEY_LIFETIME));
+    }
+
+    #[test]
+    fn revoked_epoch_is_not_served() {
+        let mut key_manager = create_key_manager();
+        key_manager.provision_key(KEY_LIFETIME).unwrap();
+
+        key_manager
+            .revoke_epoch(KEY_LIFETIME, "Key compromise".to_string())
+            .unwrap();
+
+        assert!(matches!(
+            key_manager.get_key_profile(KEY_LIFETIME),
+            Err(RevokedError(_))
+        ));
+
+        let (key_profile, revocation_reason) = key_manager
+            .get_key_profile_and_revocation(KEY_LIFETIME)
+            .unwrap();
+        assert_eq!(key_profile.epoch, Epoch(KEY_LIFETIME));
+        assert_eq!(revocation_reason.as_deref(), Some("Key compromise"));
+
+        // Persisted for restarts
+        assert!(key_manager
+            .key_store
+            .get(key_manager.create_revocation_id(KEY_LIFETIME).as_bytes())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn key_exists_is_false_for_missing_key() {
+        let key_manager = create_key_manager();
+
+        assert!(!key_manager.key_exists(KEY_LIFETIME));
+        assert!(matches!(
+            key_manager.get_key_profile(KEY_LIFETIME),
+            Err(NotFoundError(_))
+        ));
+    }
+
+    #[test]
+    fn purge_expired_keys_removes_old_epochs() {
+        let mut key_manager = create_key_manager();
+
+        for i in 0..5 {
+            key_manager.provision_key(i * KEY_LIFETIME).unwrap();
+        }
+
+        key_manager.purge_expired_keys(4 * KEY_LIFETIME).unwrap();
+
+        assert!(!key_manager.key_exists(0));
+        assert!(!key_manager.key_exists(KEY_LIFETIME));
+        assert!(key_manager.key_exists(2 * KEY_LIFETIME));
+        assert!(key_manager.key_exists(3 * KEY_LIFETIME));
+        assert!(key_manager.key_exists(4 * KEY_LIFETIME));
+    }
+
+    #[test]
+    fn epoch_markers_are_stored_and_not_purged() {
+        let mut key_manager = create_key_manager();
+
+        key_manager.update_keys().unwrap();
+
+        let current_epoch = key_manager.get_current_epoch().unwrap();
+        key_manager
+            .purge_expired_keys(current_epoch + 10 * KEY_LIFETIME)
+            .unwrap();
+
+        assert_eq!(
+            key_manager.get_marker(MARKER_CURRENT_EPOCH).unwrap(),
+            Some(current_epoch)
+        );
+        assert_eq!(
+            key_manager.get_marker(MARKER_KEY_LIFETIME).unwrap(),
+            Some(KEY_LIFETIME)
+        );
+    }
+
+    #[test]
+    fn seeded_keys_are_deterministic() {
+        let seed_config = format!("seed: {}\n", TEST_SEED);
+
+        let mut first = create_key_manager_with_config(&seed_config);
+        let mut second = create_key_manager_with_config(&seed_config);
+
+        first.provision_key(KEY_LIFETIME).unwrap();
+        first.provision_key(2 * KEY_LIFETIME).unwrap();
+        second.provision_key(KEY_LIFETIME).unwrap();
+
+        let first_key = first.get_key_profile(KEY_LIFETIME).unwrap();
+        let second_key = second.get_key_profile(KEY_LIFETIME).unwrap();
+        let other_epoch_key = first.get_key_profile(2 * KEY_LIFETIME).unwrap();
+
+        assert_eq!(
+            first_key.material.public_key.serialize().unwrap(),
+            second_key.material.public_key.serialize().unwrap()
+        );
+        assert_ne!(
+            first_key.material.public_key.serialize().unwrap(),
+            other_epoch_key.material.public_key.serialize().unwrap()
+        );
+    }
+
+    #[test]
+    fn short_seed_is_rejected() {
+        let mut config = Config::new();
+        config
+            .merge(File::from_str(
+                &format!("{}seed: 00010203\n", TEST_CONFIG),
+                FileFormat::Yaml,
+            ))
+            .unwrap();
+
+        let config: KeyManagerConfig = config.try_into().unwrap();
+
+        assert!(matches!(
+            KeyManager::new(Arc::new(MemoryKeyStore::default()), &config, DEFAULT_REALM),
+            Err(ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn zero_key_lifetime_is_rejected() {
+        let mut config = Config::new();
+        config
+            .merge(File::from_str(
+                &TEST_CONFIG.replace("key_lifetime: 10", "key_lifetime: 0"),
+                FileFormat::Yaml,
+            ))
+            .unwrap();
+
+        let config: KeyManagerConfig = config.try_into().unwrap();
+
+        assert!(matches!(
+            KeyManager::new(Arc::new(MemoryKeyStore::default()), &config, DEFAULT_REALM),
+            Err(ConfigError(_))
+        ));
+    }
+
+    // Writes a key file for the epoch and returns its directory
+    fn write_imported_key(
+        name: &str,
+        epoch: u64,
+        params: &PsParams,
+        signing_key: &PsSigningKey,
+        public_key: &PsPublicKey,
+    ) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vt-imported-keys-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+
+        let contents = format!(
+            "{}\n{}\n{}\n",
+            hex::encode(params.serialize().unwrap()),
+            hex::encode(signing_key.serialize().unwrap()),
+            hex::encode(public_key.serialize().unwrap())
+        );
+        fs::write(dir.join(format!("{}.{}", epoch, IMPORTED_KEYS_EXTENSION)), contents).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn imported_key_is_used_instead_of_generating() {
+        let (params, signing_key, public_key) = KeyManager::generate_key(1, &mut thread_rng());
+        let dir = write_imported_key("valid", KEY_LIFETIME, &params, &signing_key, &public_key);
+
+        let mut key_manager =
+            create_key_manager_with_config(&format!("imported_keys_dir: {}\n", dir.display()));
+
+        key_manager.update_key(KEY_LIFETIME).unwrap();
+        key_manager.update_key(2 * KEY_LIFETIME).unwrap();
+
+        let imported_key = key_manager.get_key_profile(KEY_LIFETIME).unwrap();
+        assert_eq!(
+            imported_key.material.public_key.serialize().unwrap(),
+            public_key.serialize().unwrap()
+        );
+
+        // Epochs without a key file fall back to generation
+        assert!(key_manager.key_exists(2 * KEY_LIFETIME));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn imported_key_with_mismatched_public_key_is_rejected() {
+        let (params, signing_key, _) = KeyManager::generate_key(1, &mut thread_rng());
+        let (_, _, other_public_key) = KeyManager::generate_key(1, &mut thread_rng());
+        let dir =
+            write_imported_key("mismatch", KEY_LIFETIME, &params, &signing_key, &other_public_key);
+
+        let mut key_manager =
+            create_key_manager_with_config(&format!("imported_keys_dir: {}\n", dir.display()));
+
+        assert!(matches!(key_manager.update_key(KEY_LIFETIME), Err(ConfigError(_))));
+        assert!(!key_manager.key_exists(KEY_LIFETIME));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn key_profiles_are_readable_during_key_updates() {
+        let mut key_manager = create_key_manager();
+        key_manager.provision_key(KEY_LIFETIME).unwrap();
+
+        let key_manager = Arc::new(RwLock::new(key_manager));
+
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let key_manager = key_manager.clone();
+
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        let key_manager = key_manager.read().unwrap();
+                        let key_profile = key_manager.get_key_profile(KEY_LIFETIME).unwrap();
+
+                        assert_eq!(key_profile.epoch, Epoch(KEY_LIFETIME));
+                    }
+                })
+            })
+            .collect();
+
+        let writer = {
+            let key_manager = key_manager.clone();
+
+            std::thread::spawn(move || {
+                for i in 2..12 {
+                    key_manager.write().unwrap().provision_key(i * KEY_LIFETIME).unwrap();
+                }
+            })
+        };
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        writer.join().unwrap();
+
+        let key_manager = key_manager.read().unwrap();
+        for i in 2..12 {
+            assert!(key_manager.key_exists(i * KEY_LIFETIME));
+        }
+    }
+
+    #[test]
+    fn key_profiles_are_readable_after_lock_is_poisoned() {
+        let mut key_manager = create_key_manager();
+        key_manager.provision_key(KEY_LIFETIME).unwrap();
+
+        let key_manager = Arc::new(RwLock::new(key_manager));
+
+        // Panic while holding the write lock
+        let poisoner = key_manager.clone();
+        let result = std::thread::spawn(move || {
+            let _key_manager = poisoner.write().unwrap();
+            panic!("Key update failed");
+        })
+        .join();
+
+        assert!(result.is_err());
+        assert!(key_manager.is_poisoned());
+
+        let key_profile = read_lock(&key_manager).get_key_profile(KEY_LIFETIME).unwrap();
+        assert_eq!(key_profile.epoch, Epoch(KEY_LIFETIME));
+
+        assert!(write_lock(&key_manager).key_exists(KEY_LIFETIME));
+    }
+
+    #[test]
+    fn stored_keys_are_verified() {
+        let mut key_manager = create_key_manager();
+
+        for i in 0..3 {
+            key_manager.provision_key(i * KEY_LIFETIME).unwrap();
+        }
+
+        assert_eq!(key_manager.verify_stored_keys().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn pending_keys_are_provisioned_outside_the_lock() {
+        let key_manager = Arc::new(RwLock::new(create_key_manager_with_config(
+            "prefetch_epochs: 3\n",
+        )));
+
+        assert_eq!(read_lock(&key_manager).get_pending_epochs(0).unwrap().len(), 4);
+
+        KeyManager::provision_pending_keys(&key_manager, 0).await.unwrap();
+
+        assert!(read_lock(&key_manager).get_pending_epochs(0).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keys_are_provisioned_ahead_of_the_epoch_boundary() {
+        let key_manager = Arc::new(RwLock::new(create_key_manager()));
+
+        KeyManager::provision_pending_keys(&key_manager, 0).await.unwrap();
+
+        // One lifetime ahead only the key of the following epoch is missing
+        let lead = read_lock(&key_manager).key_lifetime.as_secs();
+        assert_eq!(read_lock(&key_manager).get_pending_epochs(lead).unwrap().len(), 1);
+
+        KeyManager::provision_pending_keys(&key_manager, lead).await.unwrap();
+
+        assert!(read_lock(&key_manager).get_pending_epochs(lead).unwrap().is_empty());
+        assert_eq!(read_lock(&key_manager).get_current_epoch(), None);
+    }
+
+    #[test]
+    fn public_key_history_is_sorted_and_limited() {
+        let mut key_manager = create_key_manager();
+
+        for i in (0..4).rev() {
+            key_manager.provision_key(i * KEY_LIFETIME).unwrap();
+        }
+
+        let (entries, truncated) = key_manager
+            .get_public_key_history(KEY_LIFETIME, 2)
+            .unwrap();
+
+        let epochs: Vec<u64> = entries.iter().map(|entry| entry.epoch).collect();
+        assert_eq!(epochs, vec![KEY_LIFETIME, 2 * KEY_LIFETIME]);
+        assert!(truncated);
+
+        let (entries, truncated) = key_manager
+            .get_public_key_history(3 * KEY_LIFETIME, 2)
+            .unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn listed_epochs_are_paged_within_the_realm() {
+        let config = create_config("");
+        let key_store: Arc<dyn KeyStore> = Arc::new(MemoryKeyStore::default());
+
+        let mut key_manager = KeyManager::new(key_store.clone(), &config, DEFAULT_REALM).unwrap();
+        let mut other_realm = KeyManager::new(key_store, &config, "other").unwrap();
+
+        for i in 1..4 {
+            key_manager.provision_key(i * KEY_LIFETIME).unwrap();
+        }
+        other_realm.provision_key(4 * KEY_LIFETIME).unwrap();
+
+        let (epochs, truncated) = key_manager.list_epochs(0, 2).unwrap();
+        assert_eq!(epochs, vec![KEY_LIFETIME, 2 * KEY_LIFETIME]);
+        assert!(truncated);
+
+        let (epochs, truncated) = key_manager.list_epochs(epochs[1] + 1, 2).unwrap();
+        assert_eq!(epochs, vec![3 * KEY_LIFETIME]);
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn realms_share_the_key_store_but_not_the_keys() {
+        // The seed must not derive the same keys for every realm
+        let config = create_config(&format!("seed: {}\n", TEST_SEED));
+        let key_store: Arc<dyn KeyStore> = Arc::new(MemoryKeyStore::default());
+
+        let mut default_realm = KeyManager::new(key_store.clone(), &config, DEFAULT_REALM).unwrap();
+        let mut other_realm = KeyManager::new(key_store, &config, "other").unwrap();
+
+        default_realm.provision_key(KEY_LIFETIME).unwrap();
+
+        assert!(!other_realm.key_exists(KEY_LIFETIME));
+
+        other_realm.provision_key(KEY_LIFETIME).unwrap();
+
+        let default_key = default_realm.get_key_profile(KEY_LIFETIME).unwrap();
+        let other_key = other_realm.get_key_profile(KEY_LIFETIME).unwrap();
+        assert_ne!(
+            default_key.material.public_key.serialize().unwrap(),
+            other_key.material.public_key.serialize().unwrap()
+        );
+
+        // Revocations and the key history are per realm
+        other_realm.revoke_epoch(KEY_LIFETIME, "Compromised".to_string()).unwrap();
+        assert!(default_realm.get_key_profile(KEY_LIFETIME).is_ok());
+
+        let (entries, _) = default_realm.get_public_key_history(0, 10).unwrap();
+        assert_eq!(entries.len(), 1);
+    }
 }
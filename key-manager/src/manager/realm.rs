@@ -0,0 +1,152 @@
+use crate::cipher::KeyCipher;
+use crate::config::KeyManagerConfig;
+use crate::error::KeyManagerError;
+use crate::error::KeyManagerError::NotFoundError;
+use crate::manager::{read_lock, write_lock, KeyManager, DEFAULT_REALM};
+use crate::store::{connect_to_db, restore_db, KeyStore};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use vt_common::epoch::Seconds;
+
+// Key managers of the served realms. The realms share the key store and the epochs, but
+// provision and serve their keys independently
+pub struct Realms {
+    // Ordered by name, the default realm first
+    key_managers: BTreeMap<String, Arc<RwLock<KeyManager>>>,
+}
+
+impl Realms {
+    pub fn create(config: &KeyManagerConfig) -> Result<Arc<Self>, KeyManagerError> {
+        Self::create_with_key_store(Arc::new(connect_to_db(config)?), config)
+    }
+
+    // Realms on another key store, e.g. the in-memory store of the integration tests
+    pub fn create_with_key_store(
+        key_store: Arc<dyn KeyStore>,
+        config: &KeyManagerConfig,
+    ) -> Result<Arc<Self>, KeyManagerError> {
+        let mut key_managers = BTreeMap::new();
+
+        for realm in Self::realm_names(config) {
+            let key_manager = KeyManager::create(key_store.clone(), config, realm)?;
+            key_managers.insert(realm.to_string(), key_manager);
+        }
+
+        Ok(Arc::new(Self { key_managers }))
+    }
+
+    // The default realm is always served
+    fn realm_names(config: &KeyManagerConfig) -> impl Iterator<Item = &str> {
+        std::iter::once(DEFAULT_REALM).chain(config.realms.iter().map(String::as_str))
+    }
+
+    pub fn get(&self, realm: &str) -> Result<&Arc<RwLock<KeyManager>>, KeyManagerError> {
+        self.key_managers
+            .get(realm)
+            .ok_or_else(|| NotFoundError(format!("Unknown realm '{}'.", realm)))
+    }
+
+    pub fn default_realm(&self) -> &Arc<RwLock<KeyManager>> {
+        // Created with every set of realms
+        &self.key_managers[DEFAULT_REALM]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Arc<RwLock<KeyManager>>)> {
+        self.key_managers
+            .iter()
+            .map(|(realm, key_manager)| (realm.as_str(), key_manager))
+    }
+
+    // Holds the read lock of every realm, so no realm is backed up mid-provisioning
+    pub fn backup(&self, backup_dir: &Path, backups_to_keep: usize) -> Result<(), KeyManagerError> {
+        let key_managers: Vec<_> = self
+            .key_managers
+            .values()
+            .map(|key_manager| read_lock(key_manager))
+            .collect();
+
+        key_managers[0].backup(backup_dir, backups_to_keep)
+    }
+
+    // Provision and load the keys of the current epochs of every realm, without waiting for
+    // the key update scheduler
+    pub fn update_keys(&self) -> Result<(), KeyManagerError> {
+        for key_manager in self.key_managers.values() {
+            write_lock(key_manager).update_keys()?;
+        }
+
+        Ok(())
+    }
+
+    // Durably persist the shared key store before exiting
+    pub fn close(&self) -> Result<(), KeyManagerError> {
+        read_lock(self.default_realm()).close()
+    }
+
+    // Restore the latest backup and make sure the restored keys of every realm can be
+    // read. Returns the number of restored epochs
+    pub fn restore(config: &KeyManagerConfig, backup_dir: &Path) -> Result<usize, KeyManagerError> {
+        restore_db(config, backup_dir)?;
+
+        let key_store: Arc<dyn KeyStore> = Arc::new(connect_to_db(config)?);
+
+        let mut verified = 0;
+
+        for realm in Self::realm_names(config) {
+            let mut key_manager = KeyManager::new(key_store.clone(), config, realm)?;
+
+            key_manager.migrate_legacy_key_ids()?;
+            verified += key_manager.verify_stored_keys()?;
+        }
+
+        Ok(verified)
+    }
+
+    // Switch the stopped key manager's keys of every realm to a new key lifetime. Returns
+    // the number of migrated epochs
+    pub fn migrate_lifetime(
+        config: &KeyManagerConfig,
+        key_lifetime: Seconds,
+    ) -> Result<usize, KeyManagerError> {
+        let key_store: Arc<dyn KeyStore> = Arc::new(connect_to_db(config)?);
+
+        let mut migrated = 0;
+
+        for realm in Self::realm_names(config) {
+            let mut key_manager = KeyManager::new(key_store.clone(), config, realm)?;
+
+            key_manager.migrate_legacy_key_ids()?;
+            migrated += key_manager.pin_key_lifetimes(key_lifetime)?;
+        }
+
+        Ok(migrated)
+    }
+
+    // Re-encrypt the stopped key manager's key material of every realm with the configured
+    // DB encryption key, and make sure every key can still be read. Returns the number of
+    // re-encrypted values
+    pub fn reencrypt_keys(
+        config: &KeyManagerConfig,
+        previous_key: Option<&str>,
+    ) -> Result<usize, KeyManagerError> {
+        let previous = match previous_key {
+            Some(previous_key) => Some(KeyCipher::load(previous_key)?),
+            None => None,
+        };
+
+        let key_store: Arc<dyn KeyStore> = Arc::new(connect_to_db(config)?);
+
+        let mut reencrypted = 0;
+
+        for realm in Self::realm_names(config) {
+            let mut key_manager = KeyManager::new(key_store.clone(), config, realm)?;
+
+            key_manager.migrate_legacy_key_ids()?;
+            reencrypted += key_manager.reencrypt_keys(previous.as_ref())?;
+            key_manager.verify_stored_keys()?;
+        }
+
+        Ok(reencrypted)
+    }
+}
@@ -0,0 +1,34 @@
+use tonic::metadata::MetadataValue;
+use tonic::{Request, Status};
+use uuid::Uuid;
+
+// Correlates the logs of a request across services
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Assigns a request id to requests without one
+pub fn intercept(mut request: Request<()>) -> Result<Request<()>, Status> {
+    if !request.metadata().contains_key(REQUEST_ID_HEADER) {
+        set(&mut request, &generate());
+    }
+
+    Ok(request)
+}
+
+pub fn get<T>(request: &Request<T>) -> String {
+    request
+        .metadata()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+pub fn set<T>(request: &mut Request<T>, request_id: &str) {
+    if let Ok(request_id) = MetadataValue::try_from(request_id) {
+        request.metadata_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+}
+
+pub fn generate() -> String {
+    Uuid::new_v4().to_string()
+}
@@ -0,0 +1,93 @@
+use crate::audit::AuditLog;
+use crate::auth;
+use crate::config::KeyManagerConfig;
+use crate::controller::KeyManagerController;
+use crate::deadline;
+use crate::error::KeyManagerError;
+use crate::error::KeyManagerError::{ConfigError, TlsError};
+use crate::grpc;
+use crate::grpc::key_manager_service::key_manager_service_server::KeyManagerServiceServer;
+use crate::listener;
+use crate::manager::Realms;
+use crate::request_id;
+use std::future::Future;
+use std::sync::atomic::AtomicI64;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tonic::transport::{Server, ServerTlsConfig};
+use tonic_health::pb::health_server::{Health, HealthServer};
+
+// Server with the configured keepalive and request timeout
+pub fn server_builder(config: &KeyManagerConfig) -> Server {
+    Server::builder()
+        .timeout(Duration::from_secs(config.request_timeout))
+        .http2_keepalive_interval(config.http2_keepalive_interval.map(Duration::from_secs))
+        .http2_keepalive_timeout(Some(Duration::from_secs(config.http2_keepalive_timeout)))
+        .tcp_keepalive(config.tcp_keepalive.map(Duration::from_secs))
+}
+
+// Key manager service with the configured message sizes and compression. The shutdown ends
+// the key watches, which would otherwise keep the server from shutting down
+pub fn key_manager_service(
+    config: &KeyManagerConfig,
+    realms: Arc<Realms>,
+    tls_cert_not_after: Arc<AtomicI64>,
+    audit_log: Arc<AuditLog>,
+    shutdown: watch::Receiver<bool>,
+) -> KeyManagerServiceServer<KeyManagerController> {
+    let mut service = KeyManagerServiceServer::new(KeyManagerController::new(
+        realms,
+        tls_cert_not_after,
+        audit_log,
+        shutdown,
+    ))
+    .max_decoding_message_size(config.max_decoding_message_size)
+    .max_encoding_message_size(config.max_encoding_message_size);
+
+    if let Some(encoding) = config.grpc_compression.encoding() {
+        service = service.accept_compressed(encoding).send_compressed(encoding);
+    }
+
+    service
+}
+
+// Serves the key manager on a bound listener until the shutdown completes. The listener
+// outlives the server, so a server with a reloaded certificate can take over the port
+pub fn serve(
+    config: &KeyManagerConfig,
+    tls_config: Option<ServerTlsConfig>,
+    listener: Arc<TcpListener>,
+    health_service: HealthServer<impl Health>,
+    key_manager_service: KeyManagerServiceServer<KeyManagerController>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<impl Future<Output = Result<(), tonic::transport::Error>> + Send, KeyManagerError> {
+    // Client certificates are only requested with a client CA
+    let client_auth = tls_config.is_some() && config.client_auth_ca().is_some();
+
+    let mut server = server_builder(config);
+    if let Some(tls_config) = tls_config {
+        server = server
+            .tls_config(tls_config)
+            .map_err(|e| TlsError(format!("Could not configure TLS. {}", e)))?;
+    }
+
+    // Lets tools like grpcurl discover the services
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(grpc::FILE_DESCRIPTOR_SET)
+        .build()
+        .map_err(|e| ConfigError(format!("Could not build the reflection service. {}", e)))?;
+
+    Ok(server
+        .layer(tonic::service::interceptor(auth::interceptor(client_auth)))
+        .layer(tonic::service::interceptor(request_id::intercept))
+        .layer(tonic::service::interceptor(deadline::intercept))
+        .add_service(health_service)
+        .add_service(reflection_service)
+        .add_service(key_manager_service)
+        .serve_with_incoming_shutdown(
+            listener::incoming(listener, config.tcp_keepalive.map(Duration::from_secs)),
+            shutdown,
+        ))
+}
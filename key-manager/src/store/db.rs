@@ -0,0 +1,195 @@
+use crate::config::{DbCompression, KeyManagerConfig};
+use crate::error::KeyManagerError;
+use crate::error::KeyManagerError::DBError;
+use crate::store::KeyStore;
+use rocksdb::backup::{BackupEngine, BackupEngineOptions, RestoreOptions};
+use rocksdb::{
+    ColumnFamily, DBCompressionType, Direction, Env, IteratorMode, Options, WriteBatch,
+    WriteOptions, DB,
+};
+use std::path::Path;
+
+// Column family holding the key material
+const KEYS_COLUMN_FAMILY: &str = "keys";
+
+pub struct DbKeyStore {
+    db: DB,
+
+    // Wait for the WAL to be synced to disk before a write returns
+    sync_writes: bool,
+}
+
+impl DbKeyStore {
+    fn write_options(&self) -> WriteOptions {
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(self.sync_writes);
+
+        write_options
+    }
+}
+
+impl KeyStore for DbKeyStore {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.db
+            .put_cf_opt(keys_cf(&self.db)?, key, value, &self.write_options())
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.db.get_cf(keys_cf(&self.db)?, key).map_err(|e| format!("{:?}", e))
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.db
+            .delete_cf_opt(keys_cf(&self.db)?, key, &self.write_options())
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn key_may_exist(&self, key: &[u8]) -> bool {
+        match keys_cf(&self.db) {
+            Ok(keys_cf) => self.db.key_may_exist_cf(keys_cf, key),
+            Err(_) => false,
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+        self.db
+            .iterator_cf(keys_cf(&self.db)?, IteratorMode::Start)
+            .map(|entry| {
+                entry
+                    .map(|(key, _)| key.into_vec())
+                    .map_err(|e| format!("{:?}", e))
+            })
+            .collect()
+    }
+
+    // Seeks to the prefix instead of iterating over every key
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        self.db
+            .iterator_cf(keys_cf(&self.db)?, IteratorMode::From(prefix, Direction::Forward))
+            .map(|entry| {
+                entry
+                    .map(|(key, _)| key.into_vec())
+                    .map_err(|e| format!("{:?}", e))
+            })
+            .take_while(|key| key.as_ref().map_or(true, |key| key.starts_with(prefix)))
+            .collect()
+    }
+
+    fn backup(&self, backup_dir: &Path, backups_to_keep: usize) -> Result<(), String> {
+        let mut backup_engine = open_backup_engine(backup_dir)?;
+
+        // Flush the memtables so the backup does not depend on the WAL
+        backup_engine
+            .create_new_backup_flush(&self.db, true)
+            .map_err(|e| format!("{:?}", e))?;
+
+        backup_engine
+            .purge_old_backups(backups_to_keep)
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        // Memtables to SST files, then sync the WAL for anything written in the meantime
+        self.db.flush_cf(keys_cf(&self.db)?).map_err(|e| format!("{:?}", e))?;
+        self.db.flush().map_err(|e| format!("{:?}", e))?;
+
+        self.db.flush_wal(true).map_err(|e| format!("{:?}", e))
+    }
+}
+
+fn keys_cf(db: &DB) -> Result<&ColumnFamily, String> {
+    db.cf_handle(KEYS_COLUMN_FAMILY)
+        .ok_or_else(|| format!("Missing '{}' column family.", KEYS_COLUMN_FAMILY))
+}
+
+// Connect to the database
+pub fn connect_to_db(config: &KeyManagerConfig) -> Result<DbKeyStore, KeyManagerError> {
+    let mut options = Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+
+    let db_options = &config.db_options;
+
+    if let Some(max_open_files) = db_options.max_open_files {
+        options.set_max_open_files(max_open_files);
+    }
+
+    if let Some(write_buffer_size) = db_options.write_buffer_size {
+        options.set_write_buffer_size(write_buffer_size);
+    }
+
+    if let Some(compression) = db_options.compression {
+        options.set_compression_type(compression_type(compression));
+    }
+
+    let db = DB::open_cf(&options, &config.key_file, [KEYS_COLUMN_FAMILY])
+        .map_err(|e| DBError(format!("Could not connect to the keys database. {:?}", e)))?;
+
+    migrate_default_column_family(&db)?;
+
+    Ok(DbKeyStore {
+        db,
+        sync_writes: config.sync_writes,
+    })
+}
+
+// Replace the keys database with the latest backup. The key manager must not be running
+pub fn restore_db(config: &KeyManagerConfig, backup_dir: &Path) -> Result<(), KeyManagerError> {
+    let mut backup_engine = open_backup_engine(backup_dir)
+        .map_err(|e| DBError(format!("Could not open the backups. {}", e)))?;
+
+    backup_engine
+        .restore_from_latest_backup(&config.key_file, &config.key_file, &RestoreOptions::default())
+        .map_err(|e| DBError(format!("Could not restore the keys database. {:?}", e)))
+}
+
+fn open_backup_engine(backup_dir: &Path) -> Result<BackupEngine, String> {
+    let options = BackupEngineOptions::new(backup_dir).map_err(|e| format!("{:?}", e))?;
+    let env = Env::new().map_err(|e| format!("{:?}", e))?;
+
+    BackupEngine::open(&options, &env).map_err(|e| format!("{:?}", e))
+}
+
+// Move key material stored by older versions into the keys column family
+fn migrate_default_column_family(db: &DB) -> Result<(), KeyManagerError> {
+    let keys_cf = keys_cf(db).map_err(DBError)?;
+
+    let mut batch = WriteBatch::default();
+
+    for entry in db.iterator(IteratorMode::Start) {
+        let (key, value) =
+            entry.map_err(|e| DBError(format!("Could not read legacy keys. {:?}", e)))?;
+
+        batch.put_cf(keys_cf, &key, &value);
+        batch.delete(&key);
+    }
+
+    if batch.is_empty() {
+        return Ok(());
+    }
+
+    let migrated = batch.len() / 2;
+
+    db.write(batch)
+        .map_err(|e| DBError(format!("Could not migrate legacy keys. {:?}", e)))?;
+
+    info!(
+        "Migrated {} entries to the '{}' column family",
+        migrated, KEYS_COLUMN_FAMILY
+    );
+
+    Ok(())
+}
+
+fn compression_type(compression: DbCompression) -> DBCompressionType {
+    match compression {
+        DbCompression::None => DBCompressionType::None,
+        DbCompression::Snappy => DBCompressionType::Snappy,
+        DbCompression::Zlib => DBCompressionType::Zlib,
+        DbCompression::Bz2 => DBCompressionType::Bz2,
+        DbCompression::Lz4 => DBCompressionType::Lz4,
+        DbCompression::Lz4hc => DBCompressionType::Lz4hc,
+        DbCompression::Zstd => DBCompressionType::Zstd,
+    }
+}
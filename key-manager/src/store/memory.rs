@@ -0,0 +1,61 @@
+use crate::store::KeyStore;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+// In-memory key store for tests, also used by the token issuer's integration tests
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    entries: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), String> {
+        self.entries.lock().unwrap().remove(key);
+
+        Ok(())
+    }
+
+    fn key_may_exist(&self, key: &[u8]) -> bool {
+        self.entries.lock().unwrap().contains_key(key)
+    }
+
+    fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+        Ok(self.entries.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let mut keys: Vec<Vec<u8>> = self
+            .entries
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+
+        Ok(keys)
+    }
+
+    fn backup(&self, _: &Path, _: usize) -> Result<(), String> {
+        Err(format!("The memory key store can not be backed up."))
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+mod db;
+#[cfg(any(test, feature = "test-util"))]
+mod memory;
+
+pub use db::{connect_to_db, restore_db};
+#[cfg(any(test, feature = "test-util"))]
+pub use memory::MemoryKeyStore;
+
+use std::path::Path;
+
+// Storage for the serialized key material
+pub trait KeyStore: Send + Sync {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    fn delete(&self, key: &[u8]) -> Result<(), String>;
+
+    // May return false positives, never false negatives
+    fn key_may_exist(&self, key: &[u8]) -> bool;
+
+    fn keys(&self) -> Result<Vec<Vec<u8>>, String>;
+
+    // Keys starting with the prefix, in key order
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, String>;
+
+    // Create a new backup in the directory, keeping only the latest backups
+    fn backup(&self, backup_dir: &Path, backups_to_keep: usize) -> Result<(), String>;
+
+    // Persist all the writes to disk
+    fn flush(&self) -> Result<(), String>;
+}
@@ -0,0 +1,275 @@
+use crate::config::{KeyManagerConfig, TlsKeyFormat};
+use crate::error::KeyManagerError;
+use crate::error::KeyManagerError::TlsError;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use x509_parser::pem::parse_x509_pem;
+
+// Seconds between certificate expiry checks
+const CERT_EXPIRY_CHECK_INTERVAL: u64 = 60 * 60;
+
+// Seconds to wait for a rotation to settle, e.g. the cert and the key being replaced one
+// after the other
+const TLS_RELOAD_DELAY: u64 = 2;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+const PEM_PREFIX: &[u8] = b"-----BEGIN";
+const PEM_LINE_LENGTH: usize = 64;
+
+// DER encodings start with a SEQUENCE tag
+const DER_SEQUENCE_TAG: u8 = 0x30;
+
+// Without a client CA any client is accepted
+pub fn build_tls_config(config: &KeyManagerConfig) -> Result<ServerTlsConfig, KeyManagerError> {
+    match config.client_auth_ca() {
+        Some(client_ca) => build_server_tls_config(config, "client_ca", client_ca),
+        None => build_server_identity(config),
+    }
+}
+
+// Same server identity, but only clients of the admin CA are accepted
+pub fn build_admin_tls_config(
+    config: &KeyManagerConfig,
+    admin_client_ca: &str,
+) -> Result<ServerTlsConfig, KeyManagerError> {
+    build_server_tls_config(config, "admin_client_ca", admin_client_ca)
+}
+
+fn build_server_identity(config: &KeyManagerConfig) -> Result<ServerTlsConfig, KeyManagerError> {
+    // Encryption
+    let cert = read_file("tls_cert", &config.tls_cert)?;
+    let key = read_private_key("tls_key", &config.tls_key, config.tls_key_format)?;
+
+    let id = Identity::from_pem(cert, key);
+
+    Ok(ServerTlsConfig::new().identity(id))
+}
+
+fn build_server_tls_config(
+    config: &KeyManagerConfig,
+    ca_field: &str,
+    ca_path: &str,
+) -> Result<ServerTlsConfig, KeyManagerError> {
+    let tls_config = build_server_identity(config)?;
+
+    // Auth
+    let ca = read_file(ca_field, ca_path)?;
+    let ca = Certificate::from_pem(ca);
+
+    // Clients without a certificate are rejected by the auth interceptor
+    Ok(tls_config.client_ca_root(ca).client_auth_optional(true))
+}
+
+// Reads a private key as the PEM that tonic expects
+pub fn read_private_key(
+    field: &str,
+    path: &str,
+    format: TlsKeyFormat,
+) -> Result<Vec<u8>, KeyManagerError> {
+    let key = read_file(field, path)?;
+
+    let format = match format {
+        TlsKeyFormat::Auto if key.starts_with(PEM_PREFIX) => TlsKeyFormat::Pem,
+        TlsKeyFormat::Auto => TlsKeyFormat::Der,
+        format => format,
+    };
+
+    match format {
+        TlsKeyFormat::Pem if key.starts_with(PEM_PREFIX) => Ok(key),
+        TlsKeyFormat::Der if key.first() == Some(&DER_SEQUENCE_TAG) => {
+            Ok(der_to_pem("PRIVATE KEY", &key))
+        }
+        _ => Err(TlsError(format!(
+            "{} '{}' is not a {:?} encoded private key.",
+            field, path, format
+        ))),
+    }
+}
+
+fn der_to_pem(label: &str, der: &[u8]) -> Vec<u8> {
+    let encoded = base64::encode(der);
+
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(PEM_LINE_LENGTH) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+
+    pem.into_bytes()
+}
+
+// Unix timestamp after which the certificate is no longer valid
+pub fn certificate_not_after(field: &str, path: &str) -> Result<i64, KeyManagerError> {
+    let cert = read_file(field, path)?;
+
+    let (_, pem) = parse_x509_pem(&cert)
+        .map_err(|e| TlsError(format!("Could not parse {} '{}'. {:?}", field, path, e)))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|e| TlsError(format!("Could not parse {} '{}'. {:?}", field, path, e)))?;
+
+    Ok(cert.validity().not_after.timestamp())
+}
+
+// Periodically warns when the certificate is about to expire. Updated on certificate reloads
+pub fn schedule_expiry_check(not_after: Arc<AtomicI64>, warn_days: u64) {
+    tokio::spawn(async move {
+        let mut interval_timer =
+            tokio::time::interval(Duration::from_secs(CERT_EXPIRY_CHECK_INTERVAL));
+
+        loop {
+            interval_timer.tick().await;
+
+            check_expiry(not_after.load(Ordering::Relaxed), warn_days);
+        }
+    });
+}
+
+// TLS config of the rotated certificate. Fails without replacing anything if the new files
+// can not be loaded, e.g. a key that does not belong to the certificate
+pub fn reload_tls_config(
+    config: &KeyManagerConfig,
+    tls_cert_not_after: &AtomicI64,
+) -> Result<ServerTlsConfig, KeyManagerError> {
+    let tls_config = build_tls_config(config)?;
+
+    Server::builder()
+        .tls_config(tls_config.clone())
+        .map_err(|e| TlsError(format!("Invalid TLS config. {}", e)))?;
+
+    let not_after = certificate_not_after("tls_cert", &config.tls_cert)?;
+    tls_cert_not_after.store(not_after, Ordering::Relaxed);
+
+    Ok(tls_config)
+}
+
+// Notices when the contents of the TLS files change, e.g. after a certificate renewal. The
+// directories are watched, so files replaced by a rename or a symlink swap are noticed too
+pub struct TlsFileWatcher {
+    // Stops watching when dropped
+    _watcher: RecommendedWatcher,
+
+    events: mpsc::Receiver<()>,
+
+    paths: Vec<PathBuf>,
+
+    // Of the contents last reported
+    digest: Option<Vec<u8>>,
+}
+
+impl TlsFileWatcher {
+    // Watches the certificate, the key and the client CA
+    pub fn new(config: &KeyManagerConfig) -> Result<Self, KeyManagerError> {
+        let (sender, events) = mpsc::channel(1);
+
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            // A pending event covers this one
+            if event.is_ok() {
+                let _ = sender.try_send(());
+            }
+        })
+        .map_err(|e| TlsError(format!("Could not watch the TLS files. {}", e)))?;
+
+        let mut paths = vec![PathBuf::from(&config.tls_cert), PathBuf::from(&config.tls_key)];
+        paths.extend(config.client_auth_ca().map(PathBuf::from));
+
+        let dirs: BTreeSet<&Path> = paths
+            .iter()
+            .map(|path| match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            })
+            .collect();
+
+        for dir in dirs {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .map_err(|e| TlsError(format!("Could not watch '{}'. {}", dir.display(), e)))?;
+        }
+
+        let digest = digest_files(&paths);
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            paths,
+            digest,
+        })
+    }
+
+    // Resolves once the contents of the files have changed
+    pub async fn changed(&mut self) {
+        loop {
+            if self.events.recv().await.is_none() {
+                // The watcher stopped, nothing will change anymore
+                return std::future::pending().await;
+            }
+
+            tokio::time::sleep(Duration::from_secs(TLS_RELOAD_DELAY)).await;
+
+            // Covered by the digest below
+            while self.events.try_recv().is_ok() {}
+
+            let digest = digest_files(&self.paths);
+
+            if digest.is_some() && digest != self.digest {
+                self.digest = digest;
+                return;
+            }
+        }
+    }
+}
+
+// None while a file can not be read, e.g. in the middle of a rotation
+fn digest_files(paths: &[PathBuf]) -> Option<Vec<u8>> {
+    let mut hasher = Sha256::new();
+
+    for path in paths {
+        hasher.update(fs::read(path).ok()?);
+    }
+
+    Some(hasher.finalize().to_vec())
+}
+
+fn check_expiry(not_after: i64, warn_days: u64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let remaining = not_after - now;
+
+    if remaining <= 0 {
+        tracing::error!(not_after, "TLS certificate expired");
+    } else if remaining <= warn_days as i64 * SECONDS_PER_DAY {
+        tracing::warn!(
+            not_after,
+            "TLS certificate expires in {} days",
+            remaining / SECONDS_PER_DAY
+        );
+    }
+}
+
+// Reads a TLS file, reporting the config field on failure
+pub fn read_file(field: &str, path: &str) -> Result<Vec<u8>, KeyManagerError> {
+    fs::read(path).map_err(|e| {
+        let reason = match e.kind() {
+            ErrorKind::NotFound => "file not found".to_string(),
+            ErrorKind::PermissionDenied => "permission denied".to_string(),
+            _ => e.to_string(),
+        };
+
+        TlsError(format!("Could not read {} '{}': {}", field, path, reason))
+    })
+}
@@ -0,0 +1,69 @@
+use crate::error::KeyManagerError;
+use crate::error::KeyManagerError::ConfigError;
+use reqwest::Client;
+use serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Seconds to wait for the webhook to respond
+const WEBHOOK_TIMEOUT: u64 = 10;
+
+// Notifies downstream systems of key rotations
+pub struct RotationWebhook {
+    client: Client,
+
+    url: String,
+}
+
+#[derive(Serialize)]
+struct RotationEvent {
+    // Left out for the default realm
+    #[serde(skip_serializing_if = "String::is_empty")]
+    realm: String,
+
+    epoch: u64,
+
+    timestamp: u64,
+
+    // Base64 encoded public key of the new epoch
+    public_key: String,
+}
+
+impl RotationWebhook {
+    pub fn new(url: String) -> Result<Self, KeyManagerError> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(WEBHOOK_TIMEOUT))
+            .build()
+            .map_err(|e| ConfigError(format!("Could not create webhook client. {}", e)))?;
+
+        Ok(Self { client, url })
+    }
+
+    // Sends the event in the background, failures are only logged
+    pub fn notify(&self, realm: &str, epoch: u64, public_key: &[u8]) {
+        let event = RotationEvent {
+            realm: realm.to_string(),
+            epoch,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            public_key: base64::encode(public_key),
+        };
+
+        let client = self.client.clone();
+        let url = self.url.clone();
+
+        tokio::spawn(async move {
+            let result = client
+                .post(&url)
+                .json(&event)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            if let Err(e) = result {
+                tracing::warn!(epoch, "Could not send rotation webhook. {}", e);
+            }
+        });
+    }
+}
@@ -1,9 +1,37 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
 use tonic_build;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("./proto/veronymous_token_info_service.proto")?;
-    tonic_build::compile_protos("./proto/veronymous_token_service.proto")?;
-    tonic_build::compile_protos("../key-manager/proto/key_manager_service.proto")?;
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+
+    // The descriptor set is served by the reflection service. All three protos are compiled
+    // together, so the descriptor set also describes the key manager messages
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("token_issuer_descriptor.bin"))
+        .compile(
+            &[
+                "./proto/veronymous_token_info_service.proto",
+                "./proto/veronymous_token_service.proto",
+                "../key-manager/proto/key_manager_service.proto",
+            ],
+            &["./proto", "../key-manager/proto"],
+        )?;
+
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash());
 
     Ok(())
 }
+
+// Short hash of the built commit, "unknown" outside of a git checkout
+fn git_hash() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -0,0 +1,105 @@
+// Verifies an issued root token offline, without a running token issuer.
+//
+// Usage: verify-token --token <file> --epoch <epoch> --public-key <file> --params <file>
+//
+// Files hold base64 encoded serialized values, e.g. the public key and params printed by
+// the key manager's --dump-current-key. A token response must first be completed with
+// the client's token id and blinding into a root token.
+use ps_signatures::keys::{PsParams, PsPublicKey};
+use ps_signatures::serde::Serializable as PsSerializable;
+use std::collections::HashMap;
+use std::fs;
+use std::process::exit;
+use veronymous_token::root::RootVeronymousToken;
+use veronymous_token::serde::Serializable;
+
+const TOKEN_ARG: &str = "--token";
+const EPOCH_ARG: &str = "--epoch";
+const PUBLIC_KEY_ARG: &str = "--public-key";
+const PARAMS_ARG: &str = "--params";
+
+const USAGE: &str =
+    "Usage: verify-token --token <file> --epoch <epoch> --public-key <file> --params <file>";
+
+// Exit codes
+const EXIT_INVALID: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => fail(&e),
+    };
+
+    let epoch: u64 = match args[EPOCH_ARG].parse() {
+        Ok(epoch) => epoch,
+        Err(e) => fail(&format!("Invalid epoch. {}", e)),
+    };
+
+    let token = read_file(TOKEN_ARG, &args[TOKEN_ARG]).and_then(|token| {
+        RootVeronymousToken::deserialize(&token)
+            .map_err(|e| format!("Could not deserialize the token. {:?}", e))
+    });
+    let public_key = read_file(PUBLIC_KEY_ARG, &args[PUBLIC_KEY_ARG]).and_then(|public_key| {
+        PsPublicKey::deserialize(&public_key)
+            .map_err(|e| format!("Could not deserialize the public key. {:?}", e))
+    });
+    let params = read_file(PARAMS_ARG, &args[PARAMS_ARG]).and_then(|params| {
+        PsParams::deserialize(&params)
+            .map_err(|e| format!("Could not deserialize the params. {:?}", e))
+    });
+
+    let (token, public_key, params) = match (token, public_key, params) {
+        (Ok(token), Ok(public_key), Ok(params)) => (token, public_key, params),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => fail(&e),
+    };
+
+    match token.verify(&public_key, &params) {
+        Ok(true) => println!("valid (epoch {})", epoch),
+        Ok(false) => {
+            println!("invalid (epoch {})", epoch);
+            exit(EXIT_INVALID);
+        }
+        Err(e) => fail(&format!("Could not verify the token. {:?}", e)),
+    }
+}
+
+fn parse_args() -> Result<HashMap<&'static str, String>, String> {
+    let mut args = HashMap::new();
+    let mut input = std::env::args().skip(1);
+
+    while let Some(arg) = input.next() {
+        let name = [TOKEN_ARG, EPOCH_ARG, PUBLIC_KEY_ARG, PARAMS_ARG]
+            .into_iter()
+            .find(|name| *name == arg)
+            .ok_or_else(|| format!("Unknown argument '{}'.", arg))?;
+
+        let value = input
+            .next()
+            .ok_or_else(|| format!("Missing value of '{}'.", name))?;
+
+        args.insert(name, value);
+    }
+
+    for name in [TOKEN_ARG, EPOCH_ARG, PUBLIC_KEY_ARG, PARAMS_ARG] {
+        if !args.contains_key(name) {
+            return Err(format!("Missing '{}'.", name));
+        }
+    }
+
+    Ok(args)
+}
+
+// Read and decode a base64 file
+fn read_file(arg: &str, path: &str) -> Result<Vec<u8>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read '{}' file '{}'. {}", arg, path, e))?;
+
+    base64::decode(contents.trim())
+        .map_err(|e| format!("Could not decode '{}' file '{}'. {}", arg, path, e))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    exit(EXIT_ERROR);
+}
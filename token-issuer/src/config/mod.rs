@@ -1,13 +1,34 @@
 use crate::error::TokenIssuerError;
 use crate::error::TokenIssuerError::ConfigError;
-use config::{Config, File};
-use serde::Deserialize;
+use config::{Config, Environment, File};
+use serde::{Deserialize, Serialize};
+use std::fs::File as FsFile;
 use std::net::IpAddr;
+use std::path::PathBuf;
+use tonic::codec::CompressionEncoding;
+use vt_common::epoch::{Minutes, Seconds};
+
+mod sample;
+
+pub use sample::sample_config;
 
 const CONFIG_ENV_VAR: &str = "VERONYMOUS_TOKEN_ISSUER_CONFIG";
 const DEFAULT_CONFIG_LOCATION: &str = "veronymous_token_issuer_config.yml";
 
-#[derive(Clone, Debug, Deserialize)]
+// Searched for the config file, after the env var and before the working directory
+const USER_CONFIG_DIR: &str = "veronymous";
+const SYSTEM_CONFIG_DIR: &str = "/etc/veronymous";
+
+// Overrides the config file fields, e.g. VERONYMOUS_TOKEN_ISSUER_PORT.
+// Nested fields are separated with '__'
+const ENV_PREFIX: &str = "VERONYMOUS_TOKEN_ISSUER";
+const ENV_SEPARATOR: &str = "__";
+
+// Bounds of the key lifetime in minutes
+const MIN_KEY_LIFETIME: u64 = 1;
+const MAX_KEY_LIFETIME: u64 = 43200; // 30 days
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TokenIssuerConfig {
     pub host: IpAddr,
 
@@ -15,6 +36,10 @@ pub struct TokenIssuerConfig {
 
     pub key_lifetime: u64,
 
+    // Shift of the epoch boundaries in seconds
+    #[serde(default)]
+    pub epoch_offset: u64,
+
     pub key_manager_endpoint: String,
 
     pub key_manager_ca: String,
@@ -27,14 +52,260 @@ pub struct TokenIssuerConfig {
 
     pub tls_key: String,
 
-    pub auth_ca: String
+    // Encoding of the TLS private key
+    #[serde(default)]
+    pub tls_key_format: TlsKeyFormat,
+
+    // Client ca for tls authentication. Client authentication is disabled if not set
+    pub auth_ca: Option<String>,
+
+    // Set to false to accept clients without a certificate, e.g. for local development
+    #[serde(default = "default_require_client_auth")]
+    pub require_client_auth: bool,
+
+    // Port of the prometheus metrics endpoint. Metrics are disabled if not set
+    pub metrics_port: Option<u16>,
+
+    // Address of the admin and metrics listeners. Defaults to the host
+    pub admin_host: Option<IpAddr>,
+
+    // Port of the plaintext admin listener serving health checks. Disabled if not set
+    pub admin_port: Option<u16>,
+
+    // Maximum size of a serialized token request
+    #[serde(default = "default_max_token_request_bytes")]
+    pub max_token_request_bytes: usize,
+
+    // Maximum number of tokens issued under the key of an epoch. Unlimited if not set
+    pub max_issuance_per_epoch: Option<u64>,
+
+    // Reject a token request that was already issued for in the same epoch
+    #[serde(default)]
+    pub replay_protection: bool,
+
+    // Maximum number of token requests tracked per epoch by the replay protection
+    #[serde(default = "default_max_tracked_requests_per_epoch")]
+    pub max_tracked_requests_per_epoch: usize,
+
+    // Maximum size in bytes of a received gRPC message. Kept tight since token
+    // requests are small
+    #[serde(default = "default_max_decoding_message_size")]
+    pub max_decoding_message_size: usize,
+
+    // Maximum size in bytes of a sent gRPC message
+    #[serde(default = "default_max_encoding_message_size")]
+    pub max_encoding_message_size: usize,
+
+    // Maximum size in bytes of a key manager response
+    #[serde(default = "default_key_manager_max_decoding_message_size")]
+    pub key_manager_max_decoding_message_size: usize,
+
+    // Compression of the served gRPC messages
+    #[serde(default)]
+    pub grpc_compression: GrpcCompression,
+
+    // Compression of the key manager requests and responses. The key manager must have
+    // the same compression enabled
+    #[serde(default)]
+    pub key_manager_compression: GrpcCompression,
+
+    // Client certificate subjects allowed to call the admin RPCs, e.g. "CN=admin". The
+    // admin RPCs are disabled if empty
+    #[serde(default)]
+    pub admin_clients: Vec<String>,
+
+    // Requests allowed per client certificate per minute. No limit if not set
+    pub rate_limit_per_minute: Option<u32>,
+
+    // Number of attempts at retrieving keys from the key manager
+    #[serde(default = "default_retrieve_key_attempts")]
+    pub retrieve_key_attempts: u8,
+
+    // Base retry interval in seconds, doubled after every failed attempt
+    #[serde(default = "default_retrieve_key_interval")]
+    pub retrieve_key_interval: u64,
+
+    // Maximum retry interval in seconds
+    #[serde(default = "default_retrieve_key_max_interval")]
+    pub retrieve_key_max_interval: u64,
+
+    // Receive key rotations and revocations as they happen, in addition to the scheduled updates
+    #[serde(default = "default_watch_key_updates")]
+    pub watch_key_updates: bool,
+
+    // Number of past epoch keys kept for serving token info
+    #[serde(default = "default_key_history_size")]
+    pub key_history_size: usize,
+
+    // Seconds after an epoch boundary during which clients may still request tokens under
+    // the key of the previous epoch. Disabled if 0
+    #[serde(default)]
+    pub overlap_seconds: u64,
+
+    // Current tokens requested with less than this many seconds of the epoch left are
+    // issued under the next key. Disabled if 0
+    #[serde(default)]
+    pub min_remaining_seconds: u64,
+
+    // Next tokens are only issued within this many seconds of the next epoch boundary.
+    // Issued at any time if 0
+    #[serde(default)]
+    pub next_token_window_seconds: u64,
+
+    // Seconds after an epoch boundary during which the key of the previous epoch is used
+    // if the key manager has no key for the current epoch yet. Disabled if 0
+    #[serde(default)]
+    pub clock_drift_tolerance: u64,
+
+    // Issuance taking longer than this many milliseconds is logged as a warning
+    #[serde(default = "default_slow_issue_threshold_ms")]
+    pub slow_issue_threshold_ms: u64,
+
+    // Directory of the store persisting the tokens issued per epoch. Not counted if not set
+    pub issuance_counter_path: Option<String>,
+
+    // Seconds between writes of the issuance counts to the store
+    #[serde(default = "default_issuance_counter_flush_interval")]
+    pub issuance_counter_flush_interval: u64,
+
+    // Warn when the TLS certificate expires within this many days
+    #[serde(default = "default_cert_expiry_warn_days")]
+    pub cert_expiry_warn_days: u64,
+
+    #[serde(default)]
+    pub log_format: LogFormat,
+
+    // Consecutive key update failures before reporting the service as not serving
+    #[serde(default = "default_health_failure_threshold")]
+    pub health_failure_threshold: u64,
+
+    // Seconds since the last successful key update after which issuance is refused and the
+    // service is reported as not serving. Stale keys are served indefinitely if not set
+    pub max_staleness: Option<u64>,
+
+    // Number of async runtime worker threads
+    #[serde(default = "default_worker_threads")]
+    pub worker_threads: usize,
+
+    // Seconds a request may take before it is cancelled
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+
+    // Seconds between HTTP/2 pings on idle connections. No pings if not set
+    pub http2_keepalive_interval: Option<u64>,
+
+    // Seconds to wait for a ping acknowledgement before closing the connection
+    #[serde(default = "default_http2_keepalive_timeout")]
+    pub http2_keepalive_timeout: u64,
+
+    // Seconds of idleness before TCP keepalive probes are sent. Disabled if not set
+    pub tcp_keepalive: Option<u64>,
+
+    // Realm of the issuing keys. The key manager's default realm if empty
+    #[serde(default)]
+    pub realm: String,
+
+    // Seconds to wait for a connection to the key manager
+    #[serde(default = "default_key_manager_connect_timeout")]
+    pub key_manager_connect_timeout: u64,
+
+    // Seconds a single key manager request may take, sent to the key manager as the deadline
+    #[serde(default = "default_key_request_timeout")]
+    pub key_request_timeout: u64,
+
+    // Serve and connect without TLS, for CI only. Ignored unless built with the 'insecure'
+    // feature
+    #[serde(default)]
+    pub insecure: bool,
+
+    // Path the config was loaded from
+    #[serde(skip)]
+    pub config_location: String,
+}
+
+fn default_require_client_auth() -> bool {
+    true
+}
+
+fn default_max_tracked_requests_per_epoch() -> usize {
+    1_000_000
+}
+
+fn default_max_token_request_bytes() -> usize {
+    16 * 1024
+}
+
+fn default_max_decoding_message_size() -> usize {
+    64 * 1024
+}
+
+fn default_max_encoding_message_size() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_key_manager_max_decoding_message_size() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_retrieve_key_attempts() -> u8 {
+    10
+}
+
+fn default_retrieve_key_interval() -> u64 {
+    2
+}
+
+fn default_retrieve_key_max_interval() -> u64 {
+    30
+}
+
+fn default_watch_key_updates() -> bool {
+    true
+}
+
+fn default_key_history_size() -> usize {
+    6
+}
+
+fn default_health_failure_threshold() -> u64 {
+    3
+}
+
+fn default_worker_threads() -> usize {
+    2
+}
+
+fn default_request_timeout() -> u64 {
+    30
+}
+
+fn default_http2_keepalive_timeout() -> u64 {
+    20
+}
+
+fn default_key_manager_connect_timeout() -> u64 {
+    5
+}
+
+fn default_key_request_timeout() -> u64 {
+    10
+}
+
+fn default_slow_issue_threshold_ms() -> u64 {
+    100
+}
+
+fn default_issuance_counter_flush_interval() -> u64 {
+    10
+}
+
+fn default_cert_expiry_warn_days() -> u64 {
+    30
 }
 
 impl TokenIssuerConfig {
     pub fn load() -> Result<Self, TokenIssuerError> {
-        // Get the config location
-        let config_location =
-            std::env::var(CONFIG_ENV_VAR).unwrap_or_else(|_| DEFAULT_CONFIG_LOCATION.into());
+        let config_location = config_location();
 
         // Load the config
         let mut config = Config::new();
@@ -42,8 +313,253 @@ impl TokenIssuerConfig {
             .merge(File::with_name(&config_location))
             .map_err(|e| ConfigError(format!("{:?}", e)))?;
 
-        Ok(config
-            .try_into()
-            .map_err(|e| ConfigError(format!("{:?}", e)))?)
+        // Environment variables take precedence over the file
+        config
+            .merge(Environment::with_prefix(ENV_PREFIX).separator(ENV_SEPARATOR))
+            .map_err(|e| ConfigError(format!("{:?}", e)))?;
+
+        let mut config: Self = config.try_into().map_err(|e| ConfigError(format!("{}", e)))?;
+        config.config_location = config_location;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // Key lifetime in seconds
+    pub fn key_lifetime_seconds(&self) -> Result<Seconds, TokenIssuerError> {
+        if !(MIN_KEY_LIFETIME..=MAX_KEY_LIFETIME).contains(&self.key_lifetime) {
+            return Err(ConfigError(format!(
+                "'key_lifetime' must be between {} and {} minutes.",
+                MIN_KEY_LIFETIME, MAX_KEY_LIFETIME
+            )));
+        }
+
+        Minutes(self.key_lifetime)
+            .to_seconds()
+            .ok_or_else(|| ConfigError(format!("'key_lifetime' is too large.")))
+    }
+
+    // Release builds never run without TLS, whatever the config says
+    #[cfg(feature = "insecure")]
+    pub fn is_insecure(&self) -> bool {
+        self.insecure
+    }
+
+    #[cfg(not(feature = "insecure"))]
+    pub fn is_insecure(&self) -> bool {
+        false
+    }
+
+    // CA of the client certificates, if client authentication is enabled
+    pub fn client_auth_ca(&self) -> Option<&str> {
+        match &self.auth_ca {
+            Some(auth_ca) if self.require_client_auth => Some(auth_ca),
+            _ => None,
+        }
+    }
+
+    // Logs the settings that matter when diagnosing an incident. Secrets are never logged,
+    // only the paths they are read from
+    pub fn log_effective_config(&self) {
+        tracing::info!(
+            host = %self.host,
+            port = self.port,
+            admin_host = %self.admin_host.unwrap_or(self.host),
+            admin_port = ?self.admin_port,
+            metrics_port = ?self.metrics_port,
+            tls_cert = %self.tls_cert,
+            tls_key = %self.tls_key,
+            auth_ca = ?self.auth_ca,
+            client_auth_required = self.client_auth_ca().is_some(),
+            insecure = self.is_insecure(),
+            key_manager_endpoint = %self.key_manager_endpoint,
+            key_manager_ca = %self.key_manager_ca,
+            key_manager_auth_cert = %self.key_manager_auth_cert,
+            key_manager_auth_key = %self.key_manager_auth_key,
+            realm = %self.realm,
+            key_lifetime_minutes = self.key_lifetime,
+            epoch_offset = self.epoch_offset,
+            max_staleness = ?self.max_staleness,
+            max_issuance_per_epoch = ?self.max_issuance_per_epoch,
+            issuance_counter_path = ?self.issuance_counter_path,
+            "Effective config"
+        );
+    }
+
+    fn validate(&self) -> Result<(), TokenIssuerError> {
+        if self.port == 0 {
+            return Err(ConfigError(format!("'port' must not be 0.")));
+        }
+
+        if self.worker_threads == 0 {
+            return Err(ConfigError(format!("'worker_threads' must be positive.")));
+        }
+
+        if self.request_timeout == 0
+            || self.http2_keepalive_timeout == 0
+            || self.key_manager_connect_timeout == 0
+            || self.key_request_timeout == 0
+        {
+            return Err(ConfigError(format!("Server timeouts must be positive.")));
+        }
+
+        if self.issuance_counter_flush_interval == 0 {
+            return Err(ConfigError(format!(
+                "'issuance_counter_flush_interval' must be positive."
+            )));
+        }
+
+        if self.admin_port.is_some() && self.admin_port == Some(self.port) {
+            return Err(ConfigError(format!("'admin_port' must differ from 'port'.")));
+        }
+
+        let key_lifetime = self.key_lifetime_seconds()?.as_secs();
+
+        if self.overlap_seconds >= key_lifetime {
+            return Err(ConfigError(format!(
+                "'overlap_seconds' must be shorter than the key lifetime."
+            )));
+        }
+
+        if self.min_remaining_seconds >= key_lifetime {
+            return Err(ConfigError(format!(
+                "'min_remaining_seconds' must be shorter than the key lifetime."
+            )));
+        }
+
+        if self.next_token_window_seconds >= key_lifetime {
+            return Err(ConfigError(format!(
+                "'next_token_window_seconds' must be shorter than the key lifetime."
+            )));
+        }
+
+        if self.clock_drift_tolerance >= key_lifetime {
+            return Err(ConfigError(format!(
+                "'clock_drift_tolerance' must be shorter than the key lifetime."
+            )));
+        }
+
+        // The previous key is served from the key history
+        if self.overlap_seconds > 0 && self.key_history_size == 0 {
+            return Err(ConfigError(format!(
+                "'overlap_seconds' requires a 'key_history_size' of at least 1."
+            )));
+        }
+
+        // Keys are only updated once per key lifetime without the key update watch
+        if matches!(self.max_staleness, Some(max_staleness) if max_staleness < key_lifetime) {
+            return Err(ConfigError(format!(
+                "'max_staleness' must be at least the key lifetime."
+            )));
+        }
+
+        if self.max_decoding_message_size == 0
+            || self.max_encoding_message_size == 0
+            || self.key_manager_max_decoding_message_size == 0
+        {
+            return Err(ConfigError(format!("Message size limits must be positive.")));
+        }
+
+        if self.replay_protection && self.max_tracked_requests_per_epoch == 0 {
+            return Err(ConfigError(format!(
+                "'max_tracked_requests_per_epoch' must be positive."
+            )));
+        }
+
+        if self.max_issuance_per_epoch == Some(0) {
+            return Err(ConfigError(format!("'max_issuance_per_epoch' must be positive.")));
+        }
+
+        if self.max_decoding_message_size < self.max_token_request_bytes {
+            return Err(ConfigError(format!(
+                "'max_decoding_message_size' must not be smaller than 'max_token_request_bytes'."
+            )));
+        }
+
+        // No certificates are read without TLS
+        if self.is_insecure() {
+            if !self.key_manager_endpoint.starts_with("http://") {
+                return Err(ConfigError(format!(
+                    "'key_manager_endpoint' must be an http:// endpoint without TLS."
+                )));
+            }
+
+            return Ok(());
+        }
+
+        validate_file("key_manager_ca", &self.key_manager_ca)?;
+        validate_file("key_manager_auth_cert", &self.key_manager_auth_cert)?;
+        validate_file("key_manager_auth_key", &self.key_manager_auth_key)?;
+        validate_file("tls_cert", &self.tls_cert)?;
+        validate_file("tls_key", &self.tls_key)?;
+        if let Some(auth_ca) = &self.auth_ca {
+            validate_file("auth_ca", auth_ca)?;
+        }
+
+        Ok(())
     }
 }
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GrpcCompression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl GrpcCompression {
+    pub fn encoding(self) -> Option<CompressionEncoding> {
+        match self {
+            GrpcCompression::None => None,
+            GrpcCompression::Gzip => Some(CompressionEncoding::Gzip),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Plain,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsKeyFormat {
+    // Detect PEM or DER from the contents
+    #[default]
+    Auto,
+    Pem,
+    // DER encoded PKCS#8
+    Der,
+}
+
+// The env var, then the user and system config dirs, then the working directory
+fn config_location() -> String {
+    if let Ok(config_location) = std::env::var(CONFIG_ENV_VAR) {
+        return config_location;
+    }
+
+    let user_config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    user_config_dir
+        .map(|user_config_dir| user_config_dir.join(USER_CONFIG_DIR))
+        .into_iter()
+        .chain(std::iter::once(PathBuf::from(SYSTEM_CONFIG_DIR)))
+        .map(|config_dir| config_dir.join(DEFAULT_CONFIG_LOCATION))
+        .find(|config_location| config_location.is_file())
+        .map(|config_location| config_location.to_string_lossy().into_owned())
+        .unwrap_or_else(|| DEFAULT_CONFIG_LOCATION.into())
+}
+
+// Make sure the file at the path exists and is readable
+fn validate_file(field: &str, path: &str) -> Result<(), TokenIssuerError> {
+    FsFile::open(path)
+        .map(|_| ())
+        .map_err(|e| ConfigError(format!("'{}' file '{}' is not readable. {}", field, path, e)))
+}
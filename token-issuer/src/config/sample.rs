@@ -0,0 +1,329 @@
+use super::TokenIssuerConfig;
+use crate::error::TokenIssuerError;
+use crate::error::TokenIssuerError::ConfigError;
+use config::{Config, File, FileFormat};
+use serde_yaml::{Mapping, Value};
+
+const HEADER: &str = "\
+# Sample token issuer config. Save it as veronymous_token_issuer_config.yml or point
+# VERONYMOUS_TOKEN_ISSUER_CONFIG at it. Commented out fields are not set by default
+";
+
+// Placeholders of the fields without a default. Every other field gets its default
+const REQUIRED_FIELDS: &str = "
+host: 127.0.0.1
+port: 30041
+key_lifetime: 10
+key_manager_endpoint: https://localhost.veronymous.io:30051
+key_manager_ca: ./certs/key_manager/tls_ca.pem
+key_manager_auth_cert: ./certs/km_auth/auth_cert.pem
+key_manager_auth_key: ./certs/km_auth/auth_cert.key
+tls_cert: ./certs/tls/server.pem
+tls_key: ./certs/tls/server.key
+";
+
+// Placeholders of the fields that are not set by default, printed commented out
+const OPTIONAL_FIELDS: &[(&str, &str)] = &[
+    ("auth_ca", "./certs/auth/ca.pem"),
+    ("metrics_port", "30042"),
+    ("admin_host", "10.0.0.1"),
+    ("admin_port", "30043"),
+    ("max_issuance_per_epoch", "1000000"),
+    ("admin_clients", "\n  - CN=admin"),
+    ("rate_limit_per_minute", "60"),
+    ("issuance_counter_path", "./issuance_counts"),
+    ("max_staleness", "1800"),
+    ("realm", "vpn"),
+    ("http2_keepalive_interval", "60"),
+    ("tcp_keepalive", "60"),
+];
+
+// Printed above the fields
+const DESCRIPTIONS: &[(&str, &str)] = &[
+    ("host", "Address to listen on"),
+    ("port", "Port of the token services"),
+    (
+        "key_lifetime",
+        "Key lifetime in MINUTES, between 1 and 43200 (30 days). Must match the key manager",
+    ),
+    (
+        "epoch_offset",
+        "Shift of the epoch boundaries in seconds. Must match the key manager",
+    ),
+    ("key_manager_endpoint", "Endpoint of the key manager"),
+    ("key_manager_ca", "CA of the key manager's TLS certificate"),
+    (
+        "key_manager_auth_cert",
+        "Client certificate presented to the key manager",
+    ),
+    ("key_manager_auth_key", "Private key of the key manager client certificate"),
+    (
+        "tls_cert",
+        "TLS certificate. The TLS files are reloaded when they change",
+    ),
+    ("tls_key", "Private key of the TLS certificate"),
+    ("tls_key_format", "Private key encoding: auto, pem or der (PKCS#8)"),
+    (
+        "auth_ca",
+        "CA of the client certificates. Client authentication is disabled if not set",
+    ),
+    (
+        "require_client_auth",
+        "Set to false to accept clients without a certificate, for local development only",
+    ),
+    ("metrics_port", "Prometheus metrics port. Metrics are disabled if not set"),
+    (
+        "admin_host",
+        "Address of the admin and metrics listeners. Defaults to the host",
+    ),
+    (
+        "admin_port",
+        "Port of the plaintext admin listener serving health checks. Disabled if not set",
+    ),
+    ("max_token_request_bytes", "Maximum size of a serialized token request in bytes"),
+    (
+        "max_issuance_per_epoch",
+        "Maximum number of tokens issued under the key of an epoch. Unlimited if not set",
+    ),
+    (
+        "replay_protection",
+        "Reject a token request that was already issued for in the same epoch",
+    ),
+    (
+        "max_tracked_requests_per_epoch",
+        "Maximum number of token requests tracked per epoch by the replay protection",
+    ),
+    ("max_decoding_message_size", "Maximum size in bytes of a received gRPC message"),
+    ("max_encoding_message_size", "Maximum size in bytes of a sent gRPC message"),
+    (
+        "key_manager_max_decoding_message_size",
+        "Maximum size in bytes of a key manager response",
+    ),
+    ("grpc_compression", "Compression of the served gRPC messages: none or gzip"),
+    (
+        "key_manager_compression",
+        "Compression of the key manager messages: none or gzip. Must match the key manager",
+    ),
+    (
+        "admin_clients",
+        "Client certificate subjects allowed to call the admin RPCs",
+    ),
+    (
+        "rate_limit_per_minute",
+        "Requests allowed per client certificate per minute. No limit if not set",
+    ),
+    (
+        "retrieve_key_attempts",
+        "Number of attempts at retrieving keys from the key manager",
+    ),
+    (
+        "retrieve_key_interval",
+        "Base key retrieval retry interval in seconds, doubled after every failed attempt",
+    ),
+    ("retrieve_key_max_interval", "Maximum key retrieval retry interval in seconds"),
+    (
+        "watch_key_updates",
+        "Stream key rotations and revocations from the key manager as they happen",
+    ),
+    ("key_history_size", "Number of past epoch keys kept for serving token info"),
+    (
+        "overlap_seconds",
+        "Seconds after an epoch boundary during which tokens of the previous epoch can\n\
+         still be requested. Disabled if 0",
+    ),
+    (
+        "min_remaining_seconds",
+        "Seconds before the end of an epoch from which current tokens are issued under the\n\
+         next key. Disabled if 0",
+    ),
+    (
+        "next_token_window_seconds",
+        "Seconds before the end of an epoch from which next tokens are issued. Issued at\n\
+         any time if 0",
+    ),
+    (
+        "clock_drift_tolerance",
+        "Seconds after an epoch boundary during which the previous key is used if the key\n\
+         manager has no key for the current epoch yet. Disabled if 0",
+    ),
+    (
+        "slow_issue_threshold_ms",
+        "Log a warning when signing a token takes longer than this many milliseconds",
+    ),
+    (
+        "issuance_counter_path",
+        "Directory of the store persisting the tokens issued per epoch. Not counted if not set",
+    ),
+    (
+        "issuance_counter_flush_interval",
+        "Seconds between writes of the issuance counts to the store",
+    ),
+    (
+        "cert_expiry_warn_days",
+        "Warn when the TLS certificate expires within this many days",
+    ),
+    ("log_format", "Log format: plain or json"),
+    (
+        "health_failure_threshold",
+        "Consecutive key update failures before reporting not serving",
+    ),
+    (
+        "max_staleness",
+        "Seconds since the last successful key update after which issuance is refused.\n\
+         Stale keys are served indefinitely if not set",
+    ),
+    ("worker_threads", "Number of async runtime worker threads"),
+    ("request_timeout", "Seconds a request may take before it is cancelled"),
+    (
+        "http2_keepalive_interval",
+        "Seconds between HTTP/2 pings on idle connections. No pings if not set",
+    ),
+    (
+        "http2_keepalive_timeout",
+        "Seconds to wait for a ping acknowledgement before closing the connection",
+    ),
+    (
+        "tcp_keepalive",
+        "Seconds of idleness before TCP keepalive probes are sent. Disabled if not set",
+    ),
+    (
+        "realm",
+        "Realm of the issuing keys. The key manager's default realm if empty",
+    ),
+    (
+        "key_manager_connect_timeout",
+        "Seconds to wait for a connection to the key manager",
+    ),
+    ("key_request_timeout", "Seconds a single key manager request may take"),
+    (
+        "insecure",
+        "Serve and connect without TLS, for CI only. Ignored unless built with the\n\
+         'insecure' feature",
+    ),
+];
+
+// Every field of the config with its description, the defaults filled in by serde
+pub fn sample_config() -> Result<String, TokenIssuerError> {
+    let mut config = Config::new();
+    config
+        .merge(File::from_str(REQUIRED_FIELDS, FileFormat::Yaml))
+        .map_err(|e| ConfigError(format!("{:?}", e)))?;
+
+    let config: TokenIssuerConfig = config
+        .try_into()
+        .map_err(|e| ConfigError(format!("{}", e)))?;
+
+    let fields = match serde_yaml::to_value(&config) {
+        Ok(Value::Mapping(fields)) => fields,
+        Ok(_) => return Err(ConfigError(format!("The config is not a mapping."))),
+        Err(e) => return Err(ConfigError(format!("Could not serialize the config. {}", e))),
+    };
+
+    let mut sample = HEADER.to_string();
+
+    for (field, value) in fields {
+        let field = field
+            .as_str()
+            .ok_or_else(|| ConfigError(format!("Config field {:?} is not a string.", field)))?;
+
+        sample.push('\n');
+        write_field(&mut sample, field, value)?;
+    }
+
+    Ok(sample)
+}
+
+fn write_field(sample: &mut String, field: &str, value: Value) -> Result<(), TokenIssuerError> {
+    if let Some(description) = lookup(DESCRIPTIONS, field) {
+        for line in description.lines() {
+            sample.push_str(&format!("# {}\n", line.trim()));
+        }
+    }
+
+    if is_unset(&value) {
+        let placeholder = lookup(OPTIONAL_FIELDS, field).unwrap_or_default();
+
+        for line in format!("{}: {}", field, placeholder).lines() {
+            sample.push_str(&format!("#{}\n", line.trim_end()));
+        }
+
+        return Ok(());
+    }
+
+    let mut mapping = Mapping::new();
+    mapping.insert(Value::String(field.to_string()), value);
+
+    let yaml = serde_yaml::to_string(&mapping)
+        .map_err(|e| ConfigError(format!("Could not serialize '{}'. {}", field, e)))?;
+    sample.push_str(&yaml);
+
+    Ok(())
+}
+
+// Not set, empty, or only made of fields that are not set
+fn is_unset(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(value) => value.is_empty(),
+        Value::Sequence(values) => values.is_empty(),
+        Value::Mapping(values) => values.values().all(is_unset),
+        _ => false,
+    }
+}
+
+fn lookup(entries: &[(&str, &'static str)], field: &str) -> Option<&'static str> {
+    entries
+        .iter()
+        .find(|(name, _)| *name == field)
+        .map(|(_, entry)| *entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(sample: &str) -> TokenIssuerConfig {
+        let mut config = Config::new();
+        config
+            .merge(File::from_str(sample, FileFormat::Yaml))
+            .unwrap();
+
+        config.try_into().unwrap()
+    }
+
+    #[test]
+    fn sample_config_describes_every_field() {
+        let sample = sample_config().unwrap();
+
+        for line in sample.lines() {
+            let field = match line.trim_start_matches('#').split_once(':') {
+                Some((field, _)) if !line.starts_with("# ") && !field.starts_with(' ') => field,
+                _ => continue,
+            };
+
+            assert!(lookup(DESCRIPTIONS, field).is_some(), "'{}' is not described", field);
+        }
+
+        assert!(sample.contains("\nkey_lifetime: 10\n"));
+        assert!(sample.contains("\n#max_issuance_per_epoch: 1000000\n"));
+    }
+
+    #[test]
+    fn sample_config_parses() {
+        let sample = sample_config().unwrap();
+
+        let config = parse(&sample);
+        assert_eq!(config.key_lifetime, 10);
+        assert_eq!(config.max_issuance_per_epoch, None);
+
+        // With the placeholders of the optional fields set
+        let uncommented: Vec<&str> = sample
+            .lines()
+            .filter(|line| !line.starts_with("# "))
+            .map(|line| line.trim_start_matches('#'))
+            .collect();
+
+        let config = parse(&uncommented.join("\n"));
+        assert_eq!(config.max_issuance_per_epoch, Some(1000000));
+    }
+}
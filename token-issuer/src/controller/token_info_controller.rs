@@ -1,29 +1,44 @@
 use crate::grpc::veronymous_token_info_service::veronymous_token_info_service_server::VeronymousTokenInfoService;
-use crate::grpc::veronymous_token_info_service::{TokenInfo, TokenInfoRequest};
+use crate::grpc::veronymous_token_info_service::{
+    TokenInfo, TokenInfoByEpochRequest, TokenInfoRequest, VersionRequest, VersionResponse,
+};
+use crate::health;
 use crate::manager::{KeyManager, KeyProfile};
+use crate::request_id;
 use ps_signatures::serde::Serializable;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tonic::{Request, Response, Status};
 
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Set by the build script
+const GIT_HASH: &str = env!("GIT_HASH");
+
 pub struct TokenInfoController {
     key_manager: Arc<RwLock<KeyManager>>,
+
+    ready: Arc<AtomicBool>,
 }
 
 impl TokenInfoController {
-    pub fn new(key_manager: Arc<RwLock<KeyManager>>) -> Self {
-        Self { key_manager }
+    pub fn new(key_manager: Arc<RwLock<KeyManager>>, ready: Arc<AtomicBool>) -> Self {
+        Self { key_manager, ready }
     }
 }
 
 #[tonic::async_trait]
 impl VeronymousTokenInfoService for TokenInfoController {
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
     async fn get_token_info(
         &self,
-        _: Request<TokenInfoRequest>,
+        request: Request<TokenInfoRequest>,
     ) -> Result<Response<TokenInfo>, Status> {
         debug!("Got 'get_token_info' request.");
 
+        health::check_ready(&self.ready)?;
+
         let key_manager = self.key_manager.read().await;
 
         let key_profile = match key_manager.get_current_key() {
@@ -34,15 +49,21 @@ impl VeronymousTokenInfoService for TokenInfoController {
             }
         };
 
-        Ok(Response::new(key_profile.try_into()?))
+        let mut token_info: TokenInfo = key_profile.try_into()?;
+        token_info.stale = key_manager.is_stale();
+
+        Ok(Response::new(token_info))
     }
 
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
     async fn get_next_token_info(
         &self,
-        _: Request<TokenInfoRequest>,
+        request: Request<TokenInfoRequest>,
     ) -> Result<Response<TokenInfo>, Status> {
         debug!("Got 'get_next_token_info' request.");
 
+        health::check_ready(&self.ready)?;
+
         let key_manager = self.key_manager.read().await;
 
         let key_profile = match key_manager.get_next_key() {
@@ -53,7 +74,51 @@ impl VeronymousTokenInfoService for TokenInfoController {
             }
         };
 
-        Ok(Response::new(key_profile.try_into()?))
+        let mut token_info: TokenInfo = key_profile.try_into()?;
+        token_info.stale = key_manager.is_stale();
+
+        Ok(Response::new(token_info))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn get_token_info_by_epoch(
+        &self,
+        request: Request<TokenInfoByEpochRequest>,
+    ) -> Result<Response<TokenInfo>, Status> {
+        let request = request.into_inner();
+
+        debug!("Got 'get_token_info_by_epoch' request: {:?}", request);
+
+        health::check_ready(&self.ready)?;
+
+        let key_manager = self.key_manager.read().await;
+
+        let key_profile = match key_manager.get_key_by_epoch(request.epoch) {
+            Some(key_profile) => key_profile,
+            None => {
+                tracing::debug!(epoch = request.epoch, "Key profile not found");
+                return Err(Status::not_found("Could not get token info."));
+            }
+        };
+
+        let mut token_info: TokenInfo = key_profile.try_into()?;
+        token_info.stale = key_manager.is_stale();
+
+        Ok(Response::new(token_info))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn get_version(
+        &self,
+        request: Request<VersionRequest>,
+    ) -> Result<Response<VersionResponse>, Status> {
+        let key_manager = self.key_manager.read().await;
+
+        Ok(Response::new(VersionResponse {
+            version: VERSION.to_string(),
+            git_hash: GIT_HASH.to_string(),
+            key_lifetime: key_manager.get_key_lifetime().as_secs(),
+        }))
     }
 }
 
@@ -61,7 +126,7 @@ impl TryInto<TokenInfo> for &KeyProfile {
     type Error = Status;
 
     fn try_into(self) -> Result<TokenInfo, Status> {
-        let params = match self.params.serialize() {
+        let params = match self.material.params.serialize() {
             Ok(params) => params,
             Err(e) => {
                 error!("Could not serialize ps params. {:?}", e);
@@ -69,7 +134,7 @@ impl TryInto<TokenInfo> for &KeyProfile {
             }
         };
 
-        let public_key = match self.public_key.serialize() {
+        let public_key = match self.material.public_key.serialize() {
             Ok(public_key) => public_key,
             Err(e) => {
                 error!("Could not serialize public key. {:?}", e);
@@ -80,7 +145,12 @@ impl TryInto<TokenInfo> for &KeyProfile {
         Ok(TokenInfo {
             params,
             public_key,
-            key_lifetime: self.key_lifetime,
+            key_lifetime: self.key_lifetime.as_secs(),
+            expires_at: self.epoch.next(self.key_lifetime).as_secs(),
+            // Set by the controller
+            stale: false,
+            key_fingerprint: self.key_fingerprint.clone(),
+            key_format_version: self.key_format_version as u32,
         })
     }
 }
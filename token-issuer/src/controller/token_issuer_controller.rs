@@ -1,22 +1,95 @@
 use crate::grpc::veronymous_token_service::veronymous_token_service_server::VeronymousTokenService;
-use crate::grpc::veronymous_token_service::{TokenRequest, TokenResponse};
+use crate::auth;
+use crate::grpc::veronymous_token_service::{
+    DrainRequest, DrainResponse, EpochIssuanceCount, EpochTokenRequest, IssuanceCountsRequest,
+    IssuanceCountsResponse, TokenRequest, TokenResponse,
+};
+use crate::health;
 use crate::issuer::TokenIssuer;
+use crate::request_id;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tonic::{Request, Response, Status};
+use tonic_health::server::HealthReporter;
 use veronymous_token::root_exchange::RootTokenRequest;
 use veronymous_token::serde::Serializable;
 
+// Maximum number of epochs per issuance counts response
+const MAX_ISSUANCE_COUNTS: usize = 1000;
+
 pub struct TokenIssuerController {
     token_issuer: TokenIssuer,
+
+    max_token_request_bytes: usize,
+
+    // Client certificate subjects allowed to call the admin RPCs
+    admin_clients: Vec<String>,
+
+    ready: Arc<AtomicBool>,
+
+    // Set by the drain RPC. Token requests are rejected while draining
+    draining: Arc<AtomicBool>,
+
+    health_reporter: HealthReporter,
 }
 
 impl TokenIssuerController {
-    pub fn new(token_issuer: TokenIssuer) -> Self {
-        Self { token_issuer }
+    pub fn new(
+        token_issuer: TokenIssuer,
+        max_token_request_bytes: usize,
+        admin_clients: Vec<String>,
+        ready: Arc<AtomicBool>,
+        draining: Arc<AtomicBool>,
+        health_reporter: HealthReporter,
+    ) -> Self {
+        Self {
+            token_issuer,
+            max_token_request_bytes,
+            admin_clients,
+            ready,
+            draining,
+            health_reporter,
+        }
+    }
+
+    fn check_admin<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let subject = auth::client_subject(request);
+
+        match subject {
+            Some(subject) if self.admin_clients.contains(&subject) => {
+                info!("Admin request from {}", subject);
+                Ok(())
+            }
+            subject => {
+                warn!("Rejected admin request from {:?}", subject);
+                Err(Status::permission_denied("Admin client required."))
+            }
+        }
+    }
+
+    // Reject oversized requests before deserializing them
+    fn check_request_size(&self, token_request: &[u8]) -> Result<(), Status> {
+        if token_request.len() > self.max_token_request_bytes {
+            debug!(
+                "Token request too large: {} bytes, max {} bytes",
+                token_request.len(),
+                self.max_token_request_bytes
+            );
+
+            return Err(Status::invalid_argument(format!(
+                "Token request too large: {} bytes, max {} bytes.",
+                token_request.len(),
+                self.max_token_request_bytes
+            )));
+        }
+
+        Ok(())
     }
 }
 
 #[tonic::async_trait]
 impl VeronymousTokenService for TokenIssuerController {
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
     async fn issue_token(
         &self,
         request: Request<TokenRequest>,
@@ -25,8 +98,13 @@ impl VeronymousTokenService for TokenIssuerController {
 
         debug!("Got 'issue_token' request: {:?}", request);
 
+        health::check_ready(&self.ready)?;
+        health::check_not_draining(&self.draining)?;
+
         let token_request = request.token_request;
 
+        self.check_request_size(&token_request)?;
+
         // parse the token request
         let token_request = match RootTokenRequest::deserialize(&token_request) {
             Ok(request) => request,
@@ -37,20 +115,32 @@ impl VeronymousTokenService for TokenIssuerController {
             }
         };
 
-        let token_response = match self.token_issuer.issue_current_token(&token_request).await {
-            Ok(response) => response,
+        let result = if request.previous_epoch {
+            self.token_issuer.issue_previous_token(&token_request).await
+        } else {
+            self.token_issuer.issue_current_token(&token_request).await
+        };
+
+        let issued_token = match result {
+            Ok(issued_token) => issued_token,
             Err(e) => {
                 debug!("Could not issue token response. {:?}", e);
 
-                return Err(Status::aborted("Could not issue token"));
+                return Err(e.into());
             }
         };
 
-        let response = TokenResponse { token_response };
+        let response = TokenResponse {
+            token_response: issued_token.token_response,
+            epoch: issued_token.epoch,
+            expires_at: issued_token.expires_at,
+            stale: issued_token.stale,
+        };
 
         Ok(Response::new(response))
     }
 
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
     async fn issue_next_token(
         &self,
         request: Request<TokenRequest>,
@@ -59,8 +149,13 @@ impl VeronymousTokenService for TokenIssuerController {
 
         debug!("Got 'issue_next_token' request: {:?}", request);
 
+        health::check_ready(&self.ready)?;
+        health::check_not_draining(&self.draining)?;
+
         let token_request = request.token_request;
 
+        self.check_request_size(&token_request)?;
+
         // parse the token request
         let token_request = match RootTokenRequest::deserialize(&token_request) {
             Ok(request) => request,
@@ -71,17 +166,129 @@ impl VeronymousTokenService for TokenIssuerController {
             }
         };
 
-        let token_response = match self.token_issuer.issue_next_token(&token_request).await {
-            Ok(response) => response,
+        let issued_token = match self.token_issuer.issue_next_token(&token_request).await {
+            Ok(issued_token) => issued_token,
             Err(e) => {
                 debug!("Could not issue token response. {:?}", e);
 
-                return Err(Status::aborted("Could not issue token"));
+                return Err(e.into());
             }
         };
 
-        let response = TokenResponse { token_response };
+        let response = TokenResponse {
+            token_response: issued_token.token_response,
+            epoch: issued_token.epoch,
+            expires_at: issued_token.expires_at,
+            stale: issued_token.stale,
+        };
 
         Ok(Response::new(response))
     }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn issue_token_for_epoch(
+        &self,
+        request: Request<EpochTokenRequest>,
+    ) -> Result<Response<TokenResponse>, Status> {
+        self.check_admin(&request)?;
+
+        let request = request.into_inner();
+
+        debug!("Got 'issue_token_for_epoch' request: {:?}", request);
+
+        let token_request = request.token_request;
+
+        self.check_request_size(&token_request)?;
+
+        // parse the token request
+        let token_request = match RootTokenRequest::deserialize(&token_request) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Could not decode veronymous root token request. {:?}", e);
+
+                return Err(Status::invalid_argument("Invalid token request."));
+            }
+        };
+
+        let issued_token = match self
+            .token_issuer
+            .issue_token_for_epoch(&token_request, request.epoch)
+            .await
+        {
+            Ok(issued_token) => issued_token,
+            Err(e) => {
+                debug!("Could not issue token response. {:?}", e);
+
+                return Err(e.into());
+            }
+        };
+
+        let response = TokenResponse {
+            token_response: issued_token.token_response,
+            epoch: issued_token.epoch,
+            expires_at: issued_token.expires_at,
+            stale: issued_token.stale,
+        };
+
+        Ok(Response::new(response))
+    }
+
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn get_issuance_counts(
+        &self,
+        request: Request<IssuanceCountsRequest>,
+    ) -> Result<Response<IssuanceCountsResponse>, Status> {
+        self.check_admin(&request)?;
+
+        let request = request.into_inner();
+
+        debug!("Got 'get_issuance_counts' request: {:?}", request);
+
+        let issuance_counter = self
+            .token_issuer
+            .issuance_counter()
+            .ok_or_else(|| Status::failed_precondition("Issuance counting is disabled."))?;
+
+        let limit = match request.limit as usize {
+            0 => MAX_ISSUANCE_COUNTS,
+            limit => limit.min(MAX_ISSUANCE_COUNTS),
+        };
+
+        let (counts, truncated) = issuance_counter.counts(request.since_epoch, limit)?;
+
+        let counts = counts
+            .into_iter()
+            .map(|(epoch, count)| EpochIssuanceCount { epoch, count })
+            .collect();
+
+        Ok(Response::new(IssuanceCountsResponse { counts, truncated }))
+    }
+
+    // Requests that passed the drain check complete normally
+    #[tracing::instrument(skip_all, fields(request_id = %request_id::get(&request)))]
+    async fn drain(
+        &self,
+        request: Request<DrainRequest>,
+    ) -> Result<Response<DrainResponse>, Status> {
+        self.check_admin(&request)?;
+
+        let request = request.into_inner();
+
+        debug!("Got 'drain' request: {:?}", request);
+
+        let draining = !request.resume;
+        self.draining.store(draining, Ordering::Release);
+
+        // The key update scheduler reports the full serving status on its next update
+        let serving = !draining && self.ready.load(Ordering::Acquire);
+        health::set_serving_status(&mut self.health_reporter.clone(), serving).await;
+
+        if draining {
+            warn!("Draining, no longer issuing tokens.");
+        } else {
+            info!("Resumed issuing tokens.");
+        }
+
+        Ok(Response::new(DrainResponse { draining }))
+    }
 }
@@ -0,0 +1,159 @@
+use crate::error::TokenIssuerError;
+use crate::error::TokenIssuerError::StoreError;
+use rocksdb::{Direction, IteratorMode, Options, WriteBatch, DB};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use vt_common::scheduler::Scheduler;
+
+// Tokens issued per epoch, surviving restarts. Issued tokens are only counted in memory,
+// the counts are added to the store in batches off the issuance path
+pub struct IssuanceCounter {
+    // Epochs as big endian keys, so they are ordered numerically
+    db: DB,
+
+    // Counted since the last flush
+    pending: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl IssuanceCounter {
+    pub fn open(path: &str) -> Result<Arc<Self>, TokenIssuerError> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+
+        let db = DB::open(&options, path).map_err(|e| {
+            StoreError(format!(
+                "Could not open the issuance counter at '{}'. {}",
+                path, e
+            ))
+        })?;
+
+        Ok(Arc::new(Self {
+            db,
+            pending: Mutex::new(BTreeMap::new()),
+        }))
+    }
+
+    pub fn record(&self, epoch: u64) {
+        *self.lock_pending().entry(epoch).or_default() += 1;
+    }
+
+    // Adds the pending counts to the stored counts in one batch. Only called by one task
+    // at a time, so the stored counts do not change in between
+    pub fn flush(&self) -> Result<(), TokenIssuerError> {
+        let pending = std::mem::take(&mut *self.lock_pending());
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.write_counts(&pending);
+
+        // Counted again on the next flush
+        if result.is_err() {
+            let mut counts = self.lock_pending();
+
+            for (epoch, count) in pending {
+                *counts.entry(epoch).or_default() += count;
+            }
+        }
+
+        result
+    }
+
+    fn write_counts(&self, pending: &BTreeMap<u64, u64>) -> Result<(), TokenIssuerError> {
+        let mut batch = WriteBatch::default();
+
+        for (epoch, count) in pending {
+            let stored = self.get_stored(*epoch)?.unwrap_or_default();
+            batch.put(
+                epoch.to_be_bytes(),
+                stored.saturating_add(*count).to_be_bytes(),
+            );
+        }
+
+        self.db
+            .write(batch)
+            .map_err(|e| StoreError(format!("Could not store the issuance counts. {}", e)))
+    }
+
+    // Counts of the epochs starting at since_epoch, including the pending counts, oldest
+    // first. Also returns whether epochs were left out because of the limit
+    pub fn counts(
+        &self,
+        since_epoch: u64,
+        limit: usize,
+    ) -> Result<(Vec<(u64, u64)>, bool), TokenIssuerError> {
+        let mut counts = BTreeMap::new();
+
+        let since = since_epoch.to_be_bytes();
+        let entries = self
+            .db
+            .iterator(IteratorMode::From(&since, Direction::Forward));
+
+        for entry in entries {
+            let (epoch, count) = entry
+                .map_err(|e| StoreError(format!("Could not read the issuance counts. {}", e)))?;
+
+            counts.insert(decode(&epoch)?, decode(&count)?);
+        }
+
+        for (epoch, count) in self.lock_pending().range(since_epoch..) {
+            *counts.entry(*epoch).or_default() += count;
+        }
+
+        let truncated = counts.len() > limit;
+
+        Ok((counts.into_iter().take(limit).collect(), truncated))
+    }
+
+    // Periodically flush the pending counts, and once more on shutdown
+    pub fn schedule_flushes(counter: Arc<Self>, flush_interval: Duration) -> Scheduler {
+        Scheduler::spawn(
+            "Issuance count flusher",
+            move |mut shutdown_receiver| async move {
+                let mut interval_timer = tokio::time::interval(flush_interval);
+
+                loop {
+                    tokio::select! {
+                        _ = interval_timer.tick() => {}
+                        _ = &mut shutdown_receiver => break,
+                    }
+
+                    let counter = counter.clone();
+
+                    match tokio::task::spawn_blocking(move || counter.flush()).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => error!("Could not flush the issuance counts. {}", e),
+                        Err(e) => error!("Issuance count flush failed. {:?}", e),
+                    }
+                }
+
+                // Keep the counts of the tokens issued since the last flush
+                if let Err(e) = counter.flush() {
+                    error!("Could not flush the issuance counts. {}", e);
+                }
+            },
+        )
+    }
+
+    fn get_stored(&self, epoch: u64) -> Result<Option<u64>, TokenIssuerError> {
+        self.db
+            .get(epoch.to_be_bytes())
+            .map_err(|e| StoreError(format!("Could not get the issuance count. {}", e)))?
+            .map(|count| decode(&count))
+            .transpose()
+    }
+
+    fn lock_pending(&self) -> MutexGuard<'_, BTreeMap<u64, u64>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn decode(bytes: &[u8]) -> Result<u64, TokenIssuerError> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| StoreError(format!("Could not decode the issuance count.")))?;
+
+    Ok(u64::from_be_bytes(bytes))
+}
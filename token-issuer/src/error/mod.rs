@@ -1,4 +1,5 @@
 use thiserror::Error;
+use tonic::Status;
 
 #[derive(Clone, Debug, Error)]
 pub enum TokenIssuerError {
@@ -19,4 +20,50 @@ pub enum TokenIssuerError {
 
     #[error("Token error. {0}")]
     TokenError(String),
+
+    #[error("Metrics error. {0}")]
+    MetricsError(String),
+
+    #[error("TLS error. {0}")]
+    TlsError(String),
+
+    #[error("Clock error. {0}")]
+    ClockError(String),
+
+    #[error("Not found. {0}")]
+    NotFoundError(String),
+
+    #[error("Quota exceeded. {0}")]
+    QuotaExceededError(String),
+
+    #[error("Replay. {0}")]
+    ReplayError(String),
+
+    #[error("Store error. {0}")]
+    StoreError(String),
+
+    #[error("Precondition failed. {0}")]
+    PreconditionError(String),
+}
+
+impl From<TokenIssuerError> for Status {
+    fn from(err: TokenIssuerError) -> Self {
+        match err {
+            TokenIssuerError::IllegalStateError(_)
+            | TokenIssuerError::ConnectionError(_)
+            | TokenIssuerError::KeyManagerError(_)
+            | TokenIssuerError::ClockError(_) => Status::unavailable(err.to_string()),
+            TokenIssuerError::TokenError(_) | TokenIssuerError::DeserializationError(_) => {
+                Status::invalid_argument(err.to_string())
+            }
+            TokenIssuerError::NotFoundError(_) => Status::not_found(err.to_string()),
+            TokenIssuerError::QuotaExceededError(_) => Status::resource_exhausted(err.to_string()),
+            TokenIssuerError::ReplayError(_) => Status::already_exists(err.to_string()),
+            TokenIssuerError::PreconditionError(_) => Status::failed_precondition(err.to_string()),
+            TokenIssuerError::ConfigError(_)
+            | TokenIssuerError::MetricsError(_)
+            | TokenIssuerError::TlsError(_)
+            | TokenIssuerError::StoreError(_) => Status::internal(err.to_string()),
+        }
+    }
 }
@@ -1,3 +1,6 @@
+pub const FILE_DESCRIPTOR_SET: &[u8] =
+    tonic::include_file_descriptor_set!("token_issuer_descriptor");
+
 pub mod veronymous_token_service {
     tonic::include_proto!("veronymous_token_service");
 }
@@ -0,0 +1,44 @@
+use crate::controller::token_info_controller::TokenInfoController;
+use crate::controller::token_issuer_controller::TokenIssuerController;
+use crate::grpc::veronymous_token_info_service::veronymous_token_info_service_server::VeronymousTokenInfoServiceServer;
+use crate::grpc::veronymous_token_service::veronymous_token_service_server::VeronymousTokenServiceServer;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tonic::Status;
+use tonic_health::server::HealthReporter;
+
+// Report the serving status of the token issuer services
+pub async fn set_serving_status(health_reporter: &mut HealthReporter, serving: bool) {
+    if serving {
+        health_reporter
+            .set_serving::<VeronymousTokenInfoServiceServer<TokenInfoController>>()
+            .await;
+        health_reporter
+            .set_serving::<VeronymousTokenServiceServer<TokenIssuerController>>()
+            .await;
+    } else {
+        health_reporter
+            .set_not_serving::<VeronymousTokenInfoServiceServer<TokenInfoController>>()
+            .await;
+        health_reporter
+            .set_not_serving::<VeronymousTokenServiceServer<TokenIssuerController>>()
+            .await;
+    }
+}
+
+// Reject requests until the current and next keys are loaded
+pub fn check_ready(ready: &AtomicBool) -> Result<(), Status> {
+    if !ready.load(Ordering::Acquire) {
+        return Err(Status::unavailable("warming up"));
+    }
+
+    Ok(())
+}
+
+// Reject token requests once the instance is draining before a shutdown
+pub fn check_not_draining(draining: &AtomicBool) -> Result<(), Status> {
+    if draining.load(Ordering::Acquire) {
+        return Err(Status::unavailable("draining"));
+    }
+
+    Ok(())
+}
@@ -1,19 +1,126 @@
+use crate::config::TokenIssuerConfig;
+use crate::counter::IssuanceCounter;
 use crate::error::TokenIssuerError;
-use crate::error::TokenIssuerError::{IllegalStateError, TokenError};
+use crate::error::TokenIssuerError::{
+    IllegalStateError, NotFoundError, PreconditionError, QuotaExceededError, ReplayError,
+    TokenError,
+};
 use crate::manager::{KeyManager, KeyProfile};
-use rand::thread_rng;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use veronymous_token::root_exchange::{issue_root_token, RootTokenRequest};
+use crate::metrics::{
+    Metrics, ISSUE_NEXT_TOKEN, ISSUE_PREVIOUS_TOKEN, ISSUE_TOKEN, ISSUE_TOKEN_FOR_EPOCH,
+};
+use crate::signer::Signer;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use veronymous_token::root_exchange::RootTokenRequest;
 use veronymous_token::serde::Serializable;
 
+// Number of messages bound into a root token
+const ROOT_TOKEN_MESSAGE_COUNT: usize = 1;
+
 pub struct TokenIssuer {
     key_manager: Arc<RwLock<KeyManager>>,
+
+    metrics: Arc<Metrics>,
+
+    signer: Box<dyn Signer>,
+
+    // Seconds after an epoch boundary during which the previous key is still issued under
+    overlap_seconds: u64,
+
+    // Seconds before the end of an epoch from which current tokens use the next key
+    min_remaining_seconds: u64,
+
+    // Seconds before the next epoch boundary from which next tokens are issued
+    next_token_window_seconds: u64,
+
+    // Tokens issued per epoch. Unlimited if not set
+    max_issuance_per_epoch: Option<u64>,
+
+    // Reject token requests that were already issued for in the same epoch
+    replay_protection: bool,
+
+    max_tracked_requests_per_epoch: usize,
+
+    // Signing slower than this is logged
+    slow_issue_threshold: Duration,
+
+    issuance_state: Mutex<IssuanceState>,
+
+    // Persists the tokens issued per epoch. Not counted if not set
+    issuance_counter: Option<Arc<IssuanceCounter>>,
+}
+
+// Tokens issued under the key of each epoch since the current epoch started
+#[derive(Default)]
+struct IssuanceState {
+    current_epoch: u64,
+
+    counts: HashMap<u64, u64>,
+
+    // Digests of the token requests issued for
+    seen_requests: HashMap<u64, HashSet<[u8; 32]>>,
+}
+
+// A serialized token response and the validity of its key
+pub struct IssuedToken {
+    pub token_response: Vec<u8>,
+
+    pub epoch: u64,
+
+    // Unix timestamp of the end of the key's epoch
+    pub expires_at: u64,
+
+    // The key manager could not be reached on the last key update
+    pub stale: bool,
+}
+
+impl IssuanceState {
+    // Forget the past epochs once the current epoch advances, which bounds the memory. The
+    // previous epoch is kept while its tokens are still issued within the overlap window
+    fn advance(&mut self, current_epoch: Option<u64>, key_lifetime: u64, overlap_seconds: u64) {
+        let current_epoch = match current_epoch {
+            Some(current_epoch) if current_epoch > self.current_epoch => current_epoch,
+            _ => return,
+        };
+
+        let oldest_epoch = if overlap_seconds > 0 {
+            current_epoch.saturating_sub(key_lifetime)
+        } else {
+            current_epoch
+        };
+
+        self.current_epoch = current_epoch;
+        self.counts.retain(|epoch, _| *epoch >= oldest_epoch);
+        self.seen_requests.retain(|epoch, _| *epoch >= oldest_epoch);
+    }
 }
 
 impl TokenIssuer {
-    pub fn new(key_manager: Arc<RwLock<KeyManager>>) -> Self {
-        Self { key_manager }
+    pub fn new(
+        key_manager: Arc<RwLock<KeyManager>>,
+        signer: Box<dyn Signer>,
+        metrics: Arc<Metrics>,
+        config: &TokenIssuerConfig,
+        issuance_counter: Option<Arc<IssuanceCounter>>,
+    ) -> Self {
+        Self {
+            key_manager,
+            metrics,
+            signer,
+            overlap_seconds: config.overlap_seconds,
+            min_remaining_seconds: config.min_remaining_seconds,
+            next_token_window_seconds: config.next_token_window_seconds,
+            max_issuance_per_epoch: config.max_issuance_per_epoch,
+            replay_protection: config.replay_protection,
+            max_tracked_requests_per_epoch: config.max_tracked_requests_per_epoch,
+            slow_issue_threshold: Duration::from_millis(config.slow_issue_threshold_ms),
+            issuance_state: Mutex::new(IssuanceState::default()),
+            issuance_counter,
+        }
     }
 }
 
@@ -21,47 +128,439 @@ impl TokenIssuer {
     pub async fn issue_current_token(
         &self,
         token_request: &RootTokenRequest,
-    ) -> Result<Vec<u8>, TokenIssuerError> {
-        let key_manager = self.key_manager.read().await;
+    ) -> Result<IssuedToken, TokenIssuerError> {
+        let key_manager = self.read_key_manager().await;
+        key_manager.check_staleness()?;
 
-        let key = key_manager.get_current_key();
+        let key = match key_manager.get_current_key() {
+            Some(current_key) if self.expires_soon(current_key)? => {
+                match key_manager.get_next_key() {
+                    Some(next_key) => {
+                        tracing::info!(
+                            epoch = current_key.epoch.as_secs(),
+                            next_epoch = next_key.epoch.as_secs(),
+                            "Current key expires soon, issuing under the next key"
+                        );
+                        Some(next_key)
+                    }
+                    None => Some(current_key),
+                }
+            }
+            current_key => current_key.as_ref(),
+        };
+        let current_epoch = Self::current_epoch(&key_manager);
+        let stale = key_manager.is_stale();
 
-        Self::issue_token(token_request, key)
+        self.issue_token(token_request, key, current_epoch, stale, ISSUE_TOKEN)
     }
 
     pub async fn issue_next_token(
         &self,
         token_request: &RootTokenRequest,
-    ) -> Result<Vec<u8>, TokenIssuerError> {
+    ) -> Result<IssuedToken, TokenIssuerError> {
+        let key_manager = self.read_key_manager().await;
+        key_manager.check_staleness()?;
+
+        let key = key_manager.get_next_key().as_ref();
+
+        if let Some(next_key) = key {
+            self.check_next_token_window(next_key)?;
+        }
+
+        let current_epoch = Self::current_epoch(&key_manager);
+        let stale = key_manager.is_stale();
+
+        self.issue_token(token_request, key, current_epoch, stale, ISSUE_NEXT_TOKEN)
+    }
+
+    // Slow clients may still get a token under the key of the epoch that just ended, within
+    // the overlap window after the boundary
+    pub async fn issue_previous_token(
+        &self,
+        token_request: &RootTokenRequest,
+    ) -> Result<IssuedToken, TokenIssuerError> {
+        let key_manager = self.read_key_manager().await;
+        key_manager.check_staleness()?;
+
+        let current_epoch = match Self::current_epoch(&key_manager) {
+            Some(current_epoch) => current_epoch,
+            None => return Err(IllegalStateError(format!("Missing issuing key."))),
+        };
+
+        if KeyManager::now()? >= current_epoch + self.overlap_seconds {
+            return Err(TokenError(format!(
+                "Previous epoch tokens are only issued within {} seconds of epoch {}.",
+                self.overlap_seconds, current_epoch
+            )));
+        }
+
+        let previous_epoch = current_epoch.saturating_sub(key_manager.get_key_lifetime().as_secs());
+
+        let key = key_manager
+            .get_key_by_epoch(previous_epoch)
+            .ok_or_else(|| {
+                NotFoundError(format!("No key for previous epoch {}.", previous_epoch))
+            })?;
+        let stale = key_manager.is_stale();
+
+        self.issue_token(
+            token_request,
+            Some(key),
+            Some(current_epoch),
+            stale,
+            ISSUE_PREVIOUS_TOKEN,
+        )
+    }
+
+    // The key is retrieved from the key manager for every request
+    pub async fn issue_token_for_epoch(
+        &self,
+        token_request: &RootTokenRequest,
+        epoch: u64,
+    ) -> Result<IssuedToken, TokenIssuerError> {
+        let key = KeyManager::fetch_key(&self.key_manager, epoch).await?;
+        let current_epoch = Self::current_epoch(&*self.read_key_manager().await);
+
+        // The key was just retrieved from the key manager
+        self.issue_token(
+            token_request,
+            Some(&key),
+            current_epoch,
+            false,
+            ISSUE_TOKEN_FOR_EPOCH,
+        )
+    }
+
+    pub fn issuance_counter(&self) -> Option<&IssuanceCounter> {
+        self.issuance_counter.as_deref()
+    }
+
+    // Tracks the lock wait apart from the issuance latency
+    async fn read_key_manager(&self) -> RwLockReadGuard<'_, KeyManager> {
+        let timer = self.metrics.key_lock_wait.start_timer();
         let key_manager = self.key_manager.read().await;
+        timer.observe_duration();
+
+        key_manager
+    }
+
+    // Whether less than min_remaining_seconds of the key's epoch are left
+    fn expires_soon(&self, key: &KeyProfile) -> Result<bool, TokenIssuerError> {
+        if self.min_remaining_seconds == 0 {
+            return Ok(false);
+        }
+
+        let expires_at = key.epoch.next(key.key_lifetime).as_secs();
+
+        Ok(KeyManager::now()? + self.min_remaining_seconds >= expires_at)
+    }
+
+    // Keeps clients from collecting next tokens long before the next key's epoch starts
+    fn check_next_token_window(&self, next_key: &KeyProfile) -> Result<(), TokenIssuerError> {
+        if self.next_token_window_seconds == 0 {
+            return Ok(());
+        }
 
-        let key = key_manager.get_next_key();
+        if KeyManager::now()? + self.next_token_window_seconds < next_key.epoch.as_secs() {
+            return Err(PreconditionError(format!(
+                "Next tokens are only issued within {} seconds of epoch {}.",
+                self.next_token_window_seconds, next_key.epoch
+            )));
+        }
 
-        Self::issue_token(token_request, key)
+        Ok(())
+    }
+
+    fn current_epoch(key_manager: &KeyManager) -> Option<u64> {
+        key_manager
+            .get_current_key()
+            .as_ref()
+            .map(|key| key.epoch.as_secs())
+    }
+
+    // Identifies a token request, for detecting replays
+    fn request_digest(&self, token_request: &RootTokenRequest) -> Option<[u8; 32]> {
+        if !self.replay_protection {
+            return None;
+        }
+
+        Some(Sha256::digest(&token_request.serialize()).into())
+    }
+
+    // Counts a token against the quota and replay protection of the key's epoch, before
+    // it is issued
+    fn reserve_issuance(
+        &self,
+        epoch: u64,
+        key_lifetime: u64,
+        current_epoch: Option<u64>,
+        request_digest: Option<[u8; 32]>,
+    ) -> Result<(), TokenIssuerError> {
+        if self.max_issuance_per_epoch.is_none() && request_digest.is_none() {
+            return Ok(());
+        }
+
+        let mut state = self
+            .issuance_state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        state.advance(current_epoch, key_lifetime, self.overlap_seconds);
+
+        if let Some(request_digest) = request_digest {
+            let seen_requests = state.seen_requests.entry(epoch).or_default();
+
+            if seen_requests.contains(&request_digest) {
+                return Err(ReplayError(format!(
+                    "The token request was already issued for in epoch {}.",
+                    epoch
+                )));
+            }
+
+            if seen_requests.len() >= self.max_tracked_requests_per_epoch {
+                return Err(QuotaExceededError(format!(
+                    "Tracked token requests of epoch {} exceed the replay protection capacity.",
+                    epoch
+                )));
+            }
+        }
+
+        if let Some(max_issuance) = self.max_issuance_per_epoch {
+            let count = state.counts.get(&epoch).copied().unwrap_or_default();
+
+            if count >= max_issuance {
+                return Err(QuotaExceededError(format!(
+                    "{} tokens were already issued for epoch {}.",
+                    count, epoch
+                )));
+            }
+        }
+
+        *state.counts.entry(epoch).or_default() += 1;
+
+        if let Some(request_digest) = request_digest {
+            state
+                .seen_requests
+                .entry(epoch)
+                .or_default()
+                .insert(request_digest);
+        }
+
+        Ok(())
+    }
+
+    // Gives back a reserved issuance if the token could not be issued
+    fn release_issuance(&self, epoch: u64, request_digest: Option<[u8; 32]>) {
+        if self.max_issuance_per_epoch.is_none() && request_digest.is_none() {
+            return;
+        }
+
+        let mut state = self
+            .issuance_state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        if let Some(count) = state.counts.get_mut(&epoch) {
+            *count = count.saturating_sub(1);
+        }
+
+        if let (Some(request_digest), Some(seen_requests)) =
+            (request_digest, state.seen_requests.get_mut(&epoch))
+        {
+            seen_requests.remove(&request_digest);
+        }
     }
 
     fn issue_token(
+        &self,
         token_request: &RootTokenRequest,
-        key: &Option<KeyProfile>,
-    ) -> Result<Vec<u8>, TokenIssuerError> {
+        key: Option<&KeyProfile>,
+        current_epoch: Option<u64>,
+        stale: bool,
+        request_type: &str,
+    ) -> Result<IssuedToken, TokenIssuerError> {
         let key = match key {
             Some(key) => key,
             None => return Err(IllegalStateError(format!("Missing issuing key."))),
         };
+        let epoch = key.epoch.as_secs();
 
-        let mut rng = thread_rng();
+        if let Some(reason) = &key.revocation_reason {
+            return Err(IllegalStateError(format!(
+                "Issuing key of epoch {} is revoked. {}",
+                key.epoch, reason
+            )));
+        }
 
-        let token_response = issue_root_token(
-            token_request,
-            &key.signing_key,
-            &key.public_key,
-            &key.params,
-            &mut rng,
+        // The key must be able to sign every message of the request
+        if key.material.message_count < ROOT_TOKEN_MESSAGE_COUNT {
+            return Err(TokenError(format!(
+                "Issuing key supports {} messages, the token request requires {}.",
+                key.material.message_count, ROOT_TOKEN_MESSAGE_COUNT
+            )));
+        }
+
+        let request_digest = self.request_digest(token_request);
+        self.reserve_issuance(
+            epoch,
+            key.key_lifetime.as_secs(),
+            current_epoch,
+            request_digest,
+        )?;
+
+        // Only the signing is timed, the key lock is already held
+        let started = Instant::now();
+
+        let token_response = self
+            .signer
+            .issue(
+                epoch,
+                token_request,
+                &key.material.params,
+                &key.material.public_key,
+            )
+            .map_err(|e| {
+                self.release_issuance(epoch, request_digest);
+                e
+            })?;
+
+        let elapsed = started.elapsed();
+        self.metrics.issuance_latency.observe(elapsed.as_secs_f64());
+
+        if elapsed > self.slow_issue_threshold {
+            tracing::warn!(
+                epoch,
+                request_type,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "Slow token issuance"
+            );
+        }
+
+        self.metrics
+            .issued_tokens
+            .with_label_values(&[request_type, &epoch.to_string()])
+            .inc();
+
+        // Only counted in memory, written to the store in the background
+        if let Some(issuance_counter) = &self.issuance_counter {
+            issuance_counter.record(epoch);
+        }
+
+        tracing::debug!(epoch, request_type, "Issued token");
+
+        if stale {
+            tracing::warn!(epoch, request_type, "Issued token with stale keys");
+        }
+
+        Ok(IssuedToken {
+            token_response,
+            epoch,
+            expires_at: key.epoch.next(key.key_lifetime).as_secs(),
+            stale,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signer::InMemorySigner;
+    use config::{Config, File, FileFormat};
+    use tonic::transport::Endpoint;
+
+    const TEST_CONFIG: &str = "
+host: 127.0.0.1
+port: 30041
+key_lifetime: 10
+key_manager_endpoint: http://127.0.0.1:30051
+key_manager_ca: ca.pem
+key_manager_auth_cert: auth.pem
+key_manager_auth_key: auth.key
+tls_cert: server.pem
+tls_key: server.key
+auth_ca: auth_ca.pem
+overlap_seconds: 60
+max_issuance_per_epoch: 2
+replay_protection: true
+";
+
+    // Key lifetime in seconds
+    const KEY_LIFETIME: u64 = 600;
+
+    fn create_token_issuer() -> TokenIssuer {
+        let mut config = Config::new();
+        config
+            .merge(File::from_str(TEST_CONFIG, FileFormat::Yaml))
+            .unwrap();
+
+        let config: TokenIssuerConfig = config.try_into().unwrap();
+
+        let endpoint = Endpoint::from_static("http://127.0.0.1:30051");
+        let key_manager = KeyManager::new(endpoint.connect_lazy(), &config).unwrap();
+        let signer = InMemorySigner::new(key_manager.signing_keys());
+
+        TokenIssuer::new(
+            Arc::new(RwLock::new(key_manager)),
+            Box::new(signer),
+            Arc::new(Metrics::new().unwrap()),
+            &config,
+            None,
         )
-        .map_err(|e| TokenError(format!("Could not issue root token. {:?}", e)))?;
+    }
+
+    #[test]
+    fn previous_epoch_issuance_is_tracked_across_the_boundary() {
+        let token_issuer = create_token_issuer();
+
+        let previous_epoch = KEY_LIFETIME;
+        let current_epoch = previous_epoch + KEY_LIFETIME;
+
+        token_issuer
+            .reserve_issuance(
+                previous_epoch,
+                KEY_LIFETIME,
+                Some(previous_epoch),
+                Some([1; 32]),
+            )
+            .unwrap();
+
+        // Within the overlap window after the boundary, the previous epoch is still tracked
+        assert!(matches!(
+            token_issuer.reserve_issuance(
+                previous_epoch,
+                KEY_LIFETIME,
+                Some(current_epoch),
+                Some([1; 32])
+            ),
+            Err(ReplayError(_))
+        ));
+
+        token_issuer
+            .reserve_issuance(
+                previous_epoch,
+                KEY_LIFETIME,
+                Some(current_epoch),
+                Some([2; 32]),
+            )
+            .unwrap();
 
-        let token_response = token_response.serialize();
+        assert!(matches!(
+            token_issuer.reserve_issuance(
+                previous_epoch,
+                KEY_LIFETIME,
+                Some(current_epoch),
+                Some([3; 32])
+            ),
+            Err(QuotaExceededError(_))
+        ));
 
-        Ok(token_response)
+        // Forgotten once it is no longer the previous epoch
+        token_issuer
+            .reserve_issuance(
+                previous_epoch,
+                KEY_LIFETIME,
+                Some(current_epoch + KEY_LIFETIME),
+                Some([1; 32]),
+            )
+            .unwrap();
     }
 }
@@ -0,0 +1,19 @@
+#[macro_use]
+extern crate log;
+
+pub mod auth;
+pub mod config;
+pub mod controller;
+pub mod counter;
+pub mod error;
+pub mod grpc;
+pub mod health;
+pub mod issuer;
+pub mod limiter;
+pub mod listener;
+pub mod logging;
+pub mod manager;
+pub mod metrics;
+pub mod request_id;
+pub mod signer;
+pub mod tls;
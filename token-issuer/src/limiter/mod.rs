@@ -0,0 +1,120 @@
+use crate::auth;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+use vt_common::scheduler::Scheduler;
+
+// Clients without a parsable certificate share a bucket
+const UNKNOWN_CLIENT: &str = "unknown";
+
+// Buckets unused for this long are dropped
+const BUCKET_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const BUCKET_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Token bucket rate limiter keyed on the client certificate subject
+pub struct RateLimiter {
+    buckets: DashMap<String, Bucket>,
+
+    // Maximum burst
+    capacity: f64,
+
+    // Tokens added per second
+    refill_rate: f64,
+}
+
+struct Bucket {
+    tokens: f64,
+
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate_limit_per_minute: u32) -> Self {
+        let capacity = rate_limit_per_minute as f64;
+
+        Self {
+            buckets: DashMap::new(),
+            capacity,
+            refill_rate: capacity / 60.0,
+        }
+    }
+
+    // Periodically drop idle buckets
+    pub fn schedule_cleanup(rate_limiter: Arc<Self>) -> Scheduler {
+        Scheduler::spawn(
+            "Rate limit bucket cleanup",
+            move |mut shutdown_receiver| async move {
+                let mut interval_timer = tokio::time::interval(BUCKET_CLEANUP_INTERVAL);
+
+                loop {
+                    tokio::select! {
+                        _ = interval_timer.tick() => {}
+                        _ = &mut shutdown_receiver => break,
+                    }
+
+                    rate_limiter
+                        .buckets
+                        .retain(|_, bucket| bucket.last_refill.elapsed() < BUCKET_IDLE_TIMEOUT);
+                }
+            },
+        )
+    }
+
+    fn try_acquire(&self, client: &str) -> bool {
+        let now = Instant::now();
+
+        let mut bucket = self
+            .buckets
+            .entry(client.to_string())
+            .or_insert_with(|| Bucket {
+                tokens: self.capacity,
+                last_refill: now,
+            });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_rate).min(self.capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitInterceptor {
+    // No limit if not set
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimitInterceptor {
+    pub fn new(rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        Self { rate_limiter }
+    }
+}
+
+impl Interceptor for RateLimitInterceptor {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let rate_limiter = match &self.rate_limiter {
+            Some(rate_limiter) => rate_limiter,
+            None => return Ok(request),
+        };
+
+        let client = auth::client_subject(&request).unwrap_or_else(|| UNKNOWN_CLIENT.to_string());
+
+        if !rate_limiter.try_acquire(&client) {
+            warn!("Rate limit exceeded for client: {}", client);
+
+            return Err(Status::resource_exhausted("Rate limit exceeded."));
+        }
+
+        Ok(request)
+    }
+}
@@ -0,0 +1,43 @@
+use futures::stream::{self, Stream};
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+// Wait before accepting again after a failed accept, e.g. when out of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub type Incoming = Pin<Box<dyn Stream<Item = io::Result<TcpStream>> + Send>>;
+
+// Connections of a listener that outlives the server, so a new server can take over the
+// port while the previous one drains. The server's own tcp_keepalive does not apply to
+// these connections, so it is set here
+pub fn incoming(listener: Arc<TcpListener>, tcp_keepalive: Option<Duration>) -> Incoming {
+    Box::pin(stream::unfold(listener, move |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    if let Some(tcp_keepalive) = tcp_keepalive {
+                        set_keepalive(&stream, tcp_keepalive);
+                    }
+
+                    return Some((Ok(stream), listener));
+                }
+                Err(e) => {
+                    error!("Could not accept connection. {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                }
+            }
+        }
+    }))
+}
+
+fn set_keepalive(stream: &TcpStream, tcp_keepalive: Duration) {
+    let keepalive = TcpKeepalive::new().with_time(tcp_keepalive);
+
+    if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        warn!("Could not set TCP keepalive. {}", e);
+    }
+}
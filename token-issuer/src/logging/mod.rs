@@ -0,0 +1,12 @@
+use crate::config::LogFormat;
+use tracing_subscriber::EnvFilter;
+
+// Log level is set with the RUST_LOG environment variable
+pub fn init(log_format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+
+    match log_format {
+        LogFormat::Plain => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
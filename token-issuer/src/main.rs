@@ -1,65 +1,363 @@
 #[macro_use]
 extern crate log;
 
-use std::fs;
-use crate::config::TokenIssuerConfig;
-use crate::controller::token_info_controller::TokenInfoController;
-use crate::controller::token_issuer_controller::TokenIssuerController;
-use crate::grpc::veronymous_token_info_service::veronymous_token_info_service_server::VeronymousTokenInfoServiceServer;
-use crate::grpc::veronymous_token_service::veronymous_token_service_server::VeronymousTokenServiceServer;
-use crate::issuer::TokenIssuer;
-use crate::manager::KeyManager;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tonic::service::interceptor::InterceptedService;
 use tonic::transport::Server;
+use vt_issuer::config::TokenIssuerConfig;
+use vt_issuer::controller::token_info_controller::TokenInfoController;
+use vt_issuer::controller::token_issuer_controller::TokenIssuerController;
+use vt_issuer::counter::IssuanceCounter;
+use vt_issuer::grpc::veronymous_token_info_service::veronymous_token_info_service_server::VeronymousTokenInfoServiceServer;
+use vt_issuer::grpc::veronymous_token_service::veronymous_token_service_server::VeronymousTokenServiceServer;
+use vt_issuer::issuer::TokenIssuer;
+use vt_issuer::limiter::{RateLimitInterceptor, RateLimiter};
+use vt_issuer::manager::KeyManager;
+use vt_issuer::metrics::Metrics;
+use vt_issuer::signer::InMemorySigner;
+use vt_issuer::{auth, config, grpc, health, listener, logging, request_id, tls};
 
-mod config;
-mod controller;
-mod error;
-mod grpc;
-mod issuer;
-mod manager;
+// Verifies the key manager connection and key retrieval, then exits
+const CHECK_ARG: &str = "--check";
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+// Prints a commented sample config and exits, without loading the config
+const PRINT_SAMPLE_CONFIG_ARG: &str = "--print-sample-config";
 
-    info!("Loading token issuer...");
+// Prefix of the runtime thread names
+const THREAD_NAME: &str = "vt-issuer";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::args().any(|arg| arg == PRINT_SAMPLE_CONFIG_ARG) {
+        print!("{}", config::sample_config()?);
+        return Ok(());
+    }
 
     // Config
     let config = TokenIssuerConfig::load().unwrap();
 
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.worker_threads)
+        .thread_name(THREAD_NAME)
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(run(config))
+}
+
+async fn run(config: TokenIssuerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    // Logging
+    logging::init(config.log_format);
+
+    info!("Loading token issuer...");
+    info!("Config loaded from {}", config.config_location);
+    config.log_effective_config();
+
+    let insecure = config.is_insecure();
+    if insecure {
+        warn!("TLS is disabled. Never run an 'insecure' build in production.");
+    } else if config.insecure {
+        warn!("'insecure' is ignored, the issuer is not built with the 'insecure' feature.");
+    }
+
+    if std::env::args().any(|arg| arg == CHECK_ARG) {
+        check(&config).await;
+    }
+
+    // Health
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health::set_serving_status(&mut health_reporter, false).await;
+
     // Services
-    let key_manager = KeyManager::create(&config).await.unwrap();
-    let token_issuer = TokenIssuer::new(key_manager.clone());
+    let key_manager = KeyManager::create(&config).await?;
+    let draining = Arc::new(AtomicBool::new(false));
+    let key_update_scheduler = KeyManager::schedule_key_updates(
+        key_manager.clone(),
+        &config,
+        health_reporter.clone(),
+        draining.clone(),
+    );
+    let key_update_watcher = config
+        .watch_key_updates
+        .then(|| KeyManager::watch_key_updates(key_manager.clone()));
+
+    let metrics = Arc::new(Metrics::new().unwrap());
+
+    // TLS certificate expiry
+    let tls_cert_not_after = Arc::new(AtomicI64::new(0));
+    let tls_cert_gauge = metrics.tls_cert_not_after.clone();
+    if !insecure {
+        tls_cert_not_after.store(
+            tls::certificate_not_after("tls_cert", &config.tls_cert)?,
+            Ordering::Relaxed,
+        );
+        tls::schedule_expiry_check(tls_cert_not_after.clone(), config.cert_expiry_warn_days);
+        tls_cert_gauge.set(tls_cert_not_after.load(Ordering::Relaxed));
+    }
+
+    let issuance_counter = match &config.issuance_counter_path {
+        Some(path) => Some(IssuanceCounter::open(path)?),
+        None => None,
+    };
+    let flush_scheduler = issuance_counter.clone().map(|issuance_counter| {
+        IssuanceCounter::schedule_flushes(
+            issuance_counter,
+            Duration::from_secs(config.issuance_counter_flush_interval),
+        )
+    });
+
+    let signer = InMemorySigner::new(key_manager.read().await.signing_keys());
+    let token_issuer = TokenIssuer::new(
+        key_manager.clone(),
+        Box::new(signer),
+        metrics.clone(),
+        &config,
+        issuance_counter,
+    );
+
+    // Orchestrators wait for both keys before routing traffic
+    let ready = key_manager.read().await.readiness();
+    health::set_serving_status(&mut health_reporter, key_manager.read().await.is_ready()).await;
+
+    // Metrics
+    let admin_host = config.admin_host.unwrap_or(config.host);
+
+    if let Some(metrics_port) = config.metrics_port {
+        let metrics_address = SocketAddr::new(admin_host, metrics_port);
+        let key_manager = key_manager.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = Metrics::serve(metrics, key_manager, metrics_address).await {
+                error!("{}", e);
+            }
+        });
+    }
+
+    // Admin listener
+    if let Some(admin_port) = config.admin_port {
+        let admin_address = SocketAddr::new(admin_host, admin_port);
+
+        info!("Starting admin server on {}", admin_address);
+
+        let admin_server = server_builder(&config)
+            .add_service(health_service.clone())
+            .serve_with_shutdown(admin_address, shutdown_signal());
+
+        tokio::spawn(async move {
+            if let Err(e) = admin_server.await {
+                error!("Admin server error. {}", e);
+            }
+        });
+    }
+
+    // Rate limiting
+    let rate_limiter = config
+        .rate_limit_per_minute
+        .map(|rate_limit_per_minute| Arc::new(RateLimiter::new(rate_limit_per_minute)));
+    let cleanup_scheduler = rate_limiter.clone().map(RateLimiter::schedule_cleanup);
+    let rate_limit_interceptor = RateLimitInterceptor::new(rate_limiter);
 
     // Controllers
-    let token_info_controller =
-        VeronymousTokenInfoServiceServer::new(TokenInfoController::new(key_manager.clone()));
+    let mut token_info_controller = VeronymousTokenInfoServiceServer::new(
+        TokenInfoController::new(key_manager.clone(), ready.clone()),
+    )
+    .max_decoding_message_size(config.max_decoding_message_size)
+    .max_encoding_message_size(config.max_encoding_message_size);
 
+    let mut token_issuer_controller = VeronymousTokenServiceServer::new(
+        TokenIssuerController::new(
+            token_issuer,
+            config.max_token_request_bytes,
+            config.admin_clients.clone(),
+            ready,
+            draining,
+            health_reporter.clone(),
+        ),
+    )
+    .max_decoding_message_size(config.max_decoding_message_size)
+    .max_encoding_message_size(config.max_encoding_message_size);
+
+    if let Some(encoding) = config.grpc_compression.encoding() {
+        token_info_controller = token_info_controller
+            .accept_compressed(encoding)
+            .send_compressed(encoding);
+        token_issuer_controller = token_issuer_controller
+            .accept_compressed(encoding)
+            .send_compressed(encoding);
+    }
+
+    let token_info_controller =
+        InterceptedService::new(token_info_controller, rate_limit_interceptor.clone());
     let token_issuer_controller =
-        VeronymousTokenServiceServer::new(TokenIssuerController::new(token_issuer));
+        InterceptedService::new(token_issuer_controller, rate_limit_interceptor);
+
+    let client_auth = !insecure && config.client_auth_ca().is_some();
+    if !client_auth {
+        warn!("Client authentication is disabled. Clients are rate limited as one client.");
+    }
+
+    // Lets tools like grpcurl discover the services
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(grpc::FILE_DESCRIPTOR_SET)
+        .build()?;
+
+    // Shared by the servers, so a server with a reloaded certificate can take over the port
+    let listener = Arc::new(TcpListener::bind(SocketAddr::new(config.host, config.port)).await?);
 
     // TLS config
+    let (mut tls_config, mut tls_watcher) = if insecure {
+        (None, None)
+    } else {
+        (
+            Some(tls::build_tls_config(&config)?),
+            Some(tls::TlsFileWatcher::new(&config)?),
+        )
+    };
 
-    // Encryption
-    let cert = fs::read(&config.tls_cert).unwrap();
-    let key = fs::read(&config.tls_key).unwrap();
+    // Servers of replaced certificates, finishing their requests
+    let mut draining_servers = Vec::new();
 
-    let id = tonic::transport::Identity::from_pem(cert, key);
-    let tls_config = tonic::transport::ServerTlsConfig::new().identity(id);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
-    // Auth
-    let ca = fs::read(&config.auth_ca).unwrap();
-    let ca = tonic::transport::Certificate::from_pem(ca);
-    let tls_config = tls_config.client_ca_root(ca);
+    'serve: loop {
+        let mut server = server_builder(&config);
+        if let Some(tls_config) = &tls_config {
+            server = server.tls_config(tls_config.clone())?;
+        }
 
-    Server::builder()
-        .tls_config(tls_config)
-        .unwrap()
-        .add_service(token_info_controller)
-        .add_service(token_issuer_controller)
-        .serve(SocketAddr::new(config.host, config.port))
-        .await?;
+        let (drain, drain_signal) = oneshot::channel::<()>();
+
+        let mut server = Box::pin(
+            server
+                .layer(tonic::service::interceptor(auth::interceptor(client_auth)))
+                .layer(tonic::service::interceptor(request_id::intercept))
+                .add_service(health_service.clone())
+                .add_service(reflection_service.clone())
+                .add_service(token_info_controller.clone())
+                .add_service(token_issuer_controller.clone())
+                .serve_with_incoming_shutdown(
+                    listener::incoming(
+                        listener.clone(),
+                        config.tcp_keepalive.map(Duration::from_secs),
+                    ),
+                    async {
+                        let _ = drain_signal.await;
+                    },
+                ),
+        );
+
+        loop {
+            tokio::select! {
+                result = &mut server => {
+                    result?;
+                    break 'serve;
+                }
+                _ = &mut shutdown => {
+                    let _ = drain.send(());
+                    server.await?;
+                    break 'serve;
+                }
+                Some(()) = async { Some(tls_watcher.as_mut()?.changed().await) } => {
+                    // tonic can not swap the certificate of a running server. New connections
+                    // go to a new server while the current one drains its connections
+                    match tls::reload_tls_config(&config, &tls_cert_not_after) {
+                        Ok(reloaded) => {
+                            info!("TLS files changed, serving new connections with them.");
+                            tls_config = Some(reloaded);
+                            tls_cert_gauge.set(tls_cert_not_after.load(Ordering::Relaxed));
+
+                            let _ = drain.send(());
+                            draining_servers.retain(|server: &JoinHandle<_>| !server.is_finished());
+                            draining_servers.push(tokio::spawn(server));
+                            continue 'serve;
+                        }
+                        Err(e) => error!("Could not reload the TLS files, keeping them. {}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    // Long lived streams end on shutdown too
+    for server in draining_servers {
+        match server.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Server error. {}", e),
+            Err(e) => error!("Server failed. {:?}", e),
+        }
+    }
+
+    // Let any in-flight key update complete
+    key_update_scheduler.shutdown().await;
+
+    if let Some(key_update_watcher) = key_update_watcher {
+        key_update_watcher.shutdown().await;
+    }
+
+    if let Some(flush_scheduler) = flush_scheduler {
+        flush_scheduler.shutdown().await;
+    }
+
+    if let Some(cleanup_scheduler) = cleanup_scheduler {
+        cleanup_scheduler.shutdown().await;
+    }
+
+    info!("Token issuer stopped.");
 
     Ok(())
 }
+
+// Exits with a non-zero status if the keys can not be retrieved
+async fn check(config: &TokenIssuerConfig) -> ! {
+    match KeyManager::create(config).await {
+        Ok(_) => {
+            info!("Check passed.");
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("Check failed. {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Server with the configured keepalive and request timeout
+fn server_builder(config: &TokenIssuerConfig) -> Server {
+    Server::builder()
+        .timeout(Duration::from_secs(config.request_timeout))
+        .http2_keepalive_interval(config.http2_keepalive_interval.map(Duration::from_secs))
+        .http2_keepalive_timeout(Some(Duration::from_secs(config.http2_keepalive_timeout)))
+        .tcp_keepalive(config.tcp_keepalive.map(Duration::from_secs))
+}
+
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate_signal) => {
+                terminate_signal.recv().await;
+            }
+            Err(e) => {
+                error!("Could not listen for SIGTERM. {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                error!("Could not listen for the shutdown signal. {:?}", e);
+            }
+        }
+        _ = terminate => {}
+    }
+
+    info!("Shutting down...");
+}
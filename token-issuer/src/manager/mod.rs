@@ -1,44 +1,141 @@
 use crate::config::TokenIssuerConfig;
 use crate::error::TokenIssuerError;
-use crate::error::TokenIssuerError::{ConnectionError, DeserializationError, KeyManagerError};
+use crate::error::TokenIssuerError::{
+    ClockError, ConfigError, ConnectionError, DeserializationError, IllegalStateError,
+    KeyManagerError, NotFoundError,
+};
+use crate::health;
 use crate::manager::grpc::key_manager_service::key_manager_service_client::KeyManagerServiceClient;
-use crate::manager::grpc::key_manager_service::GetIssuingKeyRequest;
+use crate::manager::grpc::key_manager_service::{
+    GetIssuingKeyRequest, GetIssuingKeyResponse, GetIssuingKeysRequest, HealthRequest,
+    WatchIssuingKeysRequest,
+};
+use crate::request_id;
+use crate::signer::SigningKeys;
+use crate::tls;
 use ps_signatures::keys::{PsParams, PsPublicKey, PsSigningKey};
 use ps_signatures::serde::Serializable;
+use rand::{thread_rng, Rng};
+use std::collections::VecDeque;
+use std::future::Future;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{fs, thread};
+use std::time::{Duration, SystemTime};
 use tokio::sync::RwLock;
 use tokio::time::Instant;
 use tonic::transport::{Channel, Endpoint};
-use tonic::Code;
+use tonic::{Code, Response, Status};
+use tonic_health::server::HealthReporter;
+use vt_common::epoch::{self, Epoch, Seconds};
+use vt_common::key::{
+    key_fingerprint, KeyMaterial, KEY_FORMAT_VERSION, KEY_SCHEME, LEGACY_KEY_FORMAT_VERSION,
+};
+use vt_common::scheduler::Scheduler;
 
 mod grpc;
 
-const RETRIEVE_KEY_ATTEMPTS: u8 = 10;
-const RETRIEVE_KEY_INTERVAL: u64 = 2;
+// Seconds to wait before retrying a failed key update
+const KEY_UPDATE_RETRY_INTERVAL: u64 = 5;
 
 // This class talks to the key manager
 pub struct KeyManager {
     key_manager_client: KeyManagerServiceClient<Channel>,
 
-    key_lifetime: u64,
+    // Realm of the keys requested from the key manager
+    realm: String,
+
+    key_lifetime: Seconds,
+
+    // Offset of the epoch boundaries
+    epoch_offset: Seconds,
+
+    retrieve_key_attempts: u8,
+
+    // Base and max retry intervals in milliseconds
+    retrieve_key_interval: u64,
+
+    retrieve_key_max_interval: u64,
+
+    // Deadline of a single key manager request
+    key_request_timeout: Duration,
 
     current_key: Option<KeyProfile>,
 
     next_key: Option<KeyProfile>,
+
+    // Keys of past epochs, oldest first
+    previous_keys: VecDeque<KeyProfile>,
+
+    key_history_size: usize,
+
+    // Set while both the current and next keys are loaded
+    ready: Arc<AtomicBool>,
+
+    consecutive_failures: u64,
+
+    // Time of the last update that left the current and next keys up to date
+    last_successful_update: SystemTime,
+
+    // Seconds since the last successful update after which the keys must not be used
+    max_staleness: Option<u64>,
+
+    // Seconds after an epoch boundary during which the key manager's clock may still be
+    // in the previous epoch
+    clock_drift_tolerance: u64,
+
+    // Set while the key of the previous epoch is used as the current key
+    clock_drift_fallback: bool,
+
+    // Looked up by the in-memory signer
+    signing_keys: Arc<SigningKeys>,
 }
 
 impl KeyManager {
     pub async fn create(config: &TokenIssuerConfig) -> Result<Arc<RwLock<Self>>, TokenIssuerError> {
+        let endpoint = Endpoint::from_str(&config.key_manager_endpoint)
+            .map_err(|e| ConfigError(format!("Invalid key manager endpoint. {:?}", e)))?
+            .connect_timeout(Duration::from_secs(config.key_manager_connect_timeout));
+
+        // Plaintext http:// endpoint in CI
+        let endpoint = if config.is_insecure() {
+            endpoint
+        } else {
+            Self::configure_tls(endpoint, config)?
+        };
+
+        // A lazy channel re-dials the key manager whenever the connection drops
+        let mut key_manager = Self::new(endpoint.connect_lazy(), config)?;
+
+        // Both services must agree on the epochs and the key scheme
+        key_manager.verify_key_manager_settings().await?;
+
+        let key_manager = Arc::new(RwLock::new(key_manager));
+
+        // Update keys. The next key may not be provisioned yet at an epoch boundary, the
+        // scheduled update retrieves it later
+        if let Err(e) = Self::update_keys(&key_manager).await {
+            if key_manager.read().await.current_key.is_none() {
+                return Err(e);
+            }
+
+            warn!("Starting without the next key. {}", e);
+        }
+
+        Ok(key_manager)
+    }
+
+    fn configure_tls(
+        endpoint: Endpoint,
+        config: &TokenIssuerConfig,
+    ) -> Result<Endpoint, TokenIssuerError> {
         // Key manager encryption
-        let tls_ca = fs::read(&config.key_manager_ca).unwrap();
+        let tls_ca = tls::read_file("key_manager_ca", &config.key_manager_ca)?;
         let tls_ca = tonic::transport::Certificate::from_pem(tls_ca);
 
         // TLS authentication credentials
-        let auth_cert = fs::read(&config.key_manager_auth_cert).unwrap();
-        let auth_cert_key = fs::read(&config.key_manager_auth_key).unwrap();
+        let auth_cert = tls::read_file("key_manager_auth_cert", &config.key_manager_auth_cert)?;
+        let auth_cert_key = tls::read_file("key_manager_auth_key", &config.key_manager_auth_key)?;
 
         let auth_id = tonic::transport::Identity::from_pem(&auth_cert, &auth_cert_key);
 
@@ -47,31 +144,46 @@ impl KeyManager {
             .ca_certificate(tls_ca)
             .identity(auth_id);
 
-        let endpoint = Endpoint::from_str(&config.key_manager_endpoint)
-            .unwrap()
-            .tls_config(tls_config.clone())
-            .unwrap();
+        endpoint
+            .tls_config(tls_config)
+            .map_err(|e| ConnectionError(format!("Could not configure TLS. {:?}", e)))
+    }
 
-        let key_manager_client = KeyManagerServiceClient::connect(endpoint)
-            .await
-            .map_err(|e| ConnectionError(format!("Could not connect to key manager. {:?}", e)))?;
+    pub fn new(channel: Channel, config: &TokenIssuerConfig) -> Result<Self, TokenIssuerError> {
+        let mut key_manager_client = KeyManagerServiceClient::new(channel)
+            .max_decoding_message_size(config.key_manager_max_decoding_message_size);
 
-        let mut key_manager = Self {
+        if let Some(encoding) = config.key_manager_compression.encoding() {
+            key_manager_client = key_manager_client
+                .accept_compressed(encoding)
+                .send_compressed(encoding);
+        }
+
+        Ok(Self {
             key_manager_client,
-            key_lifetime: config.key_lifetime * 60, // To seconds
+            realm: config.realm.clone(),
+            key_lifetime: config.key_lifetime_seconds()?,
+            epoch_offset: Seconds(config.epoch_offset),
+            retrieve_key_attempts: config.retrieve_key_attempts,
+            retrieve_key_interval: config.retrieve_key_interval * 1000, // To milliseconds
+            retrieve_key_max_interval: config.retrieve_key_max_interval * 1000, // To milliseconds
+            key_request_timeout: Duration::from_secs(config.key_request_timeout),
             current_key: None,
             next_key: None,
-        };
-
-        // Update keys
-        key_manager.update_keys().await?;
-
-        let key_manager = Arc::new(RwLock::new(key_manager));
-
-        //  Schedule key updates
-        Self::schedule_key_updates(key_manager.clone(), config);
+            previous_keys: VecDeque::with_capacity(config.key_history_size),
+            key_history_size: config.key_history_size,
+            ready: Arc::new(AtomicBool::new(false)),
+            consecutive_failures: 0,
+            last_successful_update: SystemTime::now(),
+            max_staleness: config.max_staleness,
+            clock_drift_tolerance: config.clock_drift_tolerance,
+            clock_drift_fallback: false,
+            signing_keys: Arc::new(SigningKeys::default()),
+        })
+    }
 
-        Ok(key_manager)
+    pub fn signing_keys(&self) -> Arc<SigningKeys> {
+        self.signing_keys.clone()
     }
 
     pub fn get_current_key(&self) -> &Option<KeyProfile> {
@@ -82,83 +194,748 @@ impl KeyManager {
         &self.next_key
     }
 
-    fn schedule_key_updates(key_manager: Arc<RwLock<KeyManager>>, config: &TokenIssuerConfig) {
-        // Convert minutes to seconds
-        let key_lifetime = config.key_lifetime * 60;
+    // Get the key of a current, next or recent past epoch
+    pub fn get_key_by_epoch(&self, epoch: u64) -> Option<&KeyProfile> {
+        self.current_key
+            .iter()
+            .chain(self.next_key.iter())
+            .chain(self.previous_keys.iter())
+            .find(|key| key.epoch == Epoch(epoch))
+    }
+
+    pub fn get_key_lifetime(&self) -> Seconds {
+        self.key_lifetime
+    }
+
+    pub fn readiness(&self) -> Arc<AtomicBool> {
+        self.ready.clone()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    // Number of scheduled key updates that failed in a row
+    pub fn get_consecutive_failures(&self) -> u64 {
+        self.consecutive_failures
+    }
+
+    // The keys are kept but stale while the key manager can not be reached
+    pub fn is_stale(&self) -> bool {
+        self.consecutive_failures > 0
+    }
+
+    // Seconds since the last successful key update
+    pub fn get_staleness(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(self.last_successful_update)
+            .map(|staleness| staleness.as_secs())
+            .unwrap_or_default()
+    }
+
+    // Refuse keys that were not updated for longer than the max staleness
+    pub fn check_staleness(&self) -> Result<(), TokenIssuerError> {
+        let staleness = self.get_staleness();
+
+        if matches!(self.max_staleness, Some(max_staleness) if staleness > max_staleness) {
+            return Err(IllegalStateError(format!(
+                "Keys were not updated for {} seconds.",
+                staleness
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn schedule_key_updates(
+        key_manager: Arc<RwLock<KeyManager>>,
+        config: &TokenIssuerConfig,
+        mut health_reporter: HealthReporter,
+        draining: Arc<AtomicBool>,
+    ) -> Scheduler {
+        let epoch_offset = Seconds(config.epoch_offset);
+        let health_failure_threshold = config.health_failure_threshold;
 
-        let next_key_update = Self::calculate_next_key_update(key_lifetime);
-        let key_lifetime = Duration::from_secs(key_lifetime);
+        Scheduler::spawn("Key update scheduler", move |mut shutdown_receiver| async move {
+            // Validated when the key manager was created
+            let key_lifetime = key_manager.read().await.key_lifetime;
 
-        tokio::spawn(async move {
+            let next_key_update = tokio::select! {
+                next_key_update = Self::wait_for_next_key_update(key_lifetime, epoch_offset) => {
+                    next_key_update
+                }
+                _ = &mut shutdown_receiver => return,
+            };
+
+            let key_lifetime = key_lifetime.as_duration();
             let mut interval_timer = tokio::time::interval_at(next_key_update, key_lifetime);
 
+            let retry_interval = Duration::from_secs(KEY_UPDATE_RETRY_INTERVAL);
+
+            // Backfill keys that were missing at startup
+            let mut retry = {
+                let key_manager = key_manager.read().await;
+
+                !key_manager.is_ready() || key_manager.clock_drift_fallback
+            };
+
             debug!("Scheduled key updates...");
             loop {
-                interval_timer.tick().await;
+                // Retry a failed update once before the next scheduled update
+                tokio::select! {
+                    _ = interval_timer.tick(), if !retry => {}
+                    _ = tokio::time::sleep(retry_interval), if retry => {}
+                    _ = &mut shutdown_receiver => break,
+                }
 
                 debug!("Updating keys...");
 
-                let mut key_manager = key_manager.write().await;
+                // The key manager is not locked while the retrieval is retried, so the
+                // issuance keeps serving the loaded keys during an outage
+                let result = Self::update_keys(&key_manager).await;
+
+                let serving = {
+                    let mut key_manager = key_manager.write().await;
+
+                    retry = match result {
+                        Ok(()) => {
+                            key_manager.consecutive_failures = 0;
+                            // Retrieve the key of the current epoch once the key manager has it
+                            key_manager.clock_drift_fallback
+                        }
+                        Err(e) => {
+                            key_manager.consecutive_failures += 1;
+                            error!(
+                                "Could not update keys ({} consecutive failures). {:?}",
+                                key_manager.consecutive_failures, e
+                            );
+                            // Keep retrying until the missing keys are retrieved
+                            key_manager.consecutive_failures == 1 || !key_manager.is_ready()
+                        }
+                    };
+
+                    key_manager.is_ready()
+                        && key_manager.consecutive_failures <= health_failure_threshold
+                        && key_manager.check_staleness().is_ok()
+                        && !draining.load(Ordering::Acquire)
+                };
+
+                health::set_serving_status(&mut health_reporter, serving).await;
+            }
+
+            debug!("Stopped key updates.");
+        })
+    }
+
+    // Retrieve the key of any epoch, bypassing the current and next keys. The lock is not
+    // held while waiting for the key manager
+    pub async fn fetch_key(
+        key_manager: &RwLock<KeyManager>,
+        epoch: u64,
+    ) -> Result<KeyProfile, TokenIssuerError> {
+        let (mut client, realm, timeout) = {
+            let key_manager = key_manager.read().await;
+
+            (
+                key_manager.key_manager_client.clone(),
+                key_manager.realm.clone(),
+                key_manager.key_request_timeout,
+            )
+        };
+
+        let mut request = tonic::Request::new(GetIssuingKeyRequest { epoch, realm });
+        request_id::set(&mut request, &request_id::generate());
+        request.set_timeout(timeout);
+
+        let response = Self::with_deadline(timeout, client.get_issuing_key(request))
+            .await
+            .map_err(|e| match e.code() {
+                Code::NotFound => NotFoundError(format!("No key for epoch {}.", epoch)),
+                Code::FailedPrecondition => IllegalStateError(e.message().to_string()),
+                _ => KeyManagerError(format!("Could not get key. {:?}", e)),
+            })?
+            .into_inner();
+
+        key_manager.read().await.decode_key(response, &[epoch])
+    }
+
+    // Apply the keys pushed by the key manager as soon as they change
+    pub fn watch_key_updates(key_manager: Arc<RwLock<KeyManager>>) -> Scheduler {
+        Scheduler::spawn("Key update watcher", move |mut shutdown_receiver| async move {
+            let retry_interval = Duration::from_secs(KEY_UPDATE_RETRY_INTERVAL);
+
+            debug!("Watching key updates...");
+            loop {
+                // Clones share the underlying channel
+                let (client, realm) = {
+                    let key_manager = key_manager.read().await;
+
+                    (key_manager.key_manager_client.clone(), key_manager.realm.clone())
+                };
+                let key_updates = Self::receive_key_updates(&key_manager, client, realm);
+
+                tokio::select! {
+                    result = key_updates => match result {
+                        Ok(()) => debug!("Key update stream closed."),
+                        Err(e) if e.code() == Code::Unimplemented => {
+                            warn!("Key manager does not support watching key updates.");
+                            break;
+                        }
+                        Err(e) => warn!("Key update stream failed. {:?}", e),
+                    },
+                    _ = &mut shutdown_receiver => break,
+                }
+
+                // Reconnect
+                tokio::select! {
+                    _ = tokio::time::sleep(retry_interval) => {}
+                    _ = &mut shutdown_receiver => break,
+                }
+            }
+
+            debug!("Stopped watching key updates.");
+        })
+    }
+
+    async fn receive_key_updates(
+        key_manager: &RwLock<KeyManager>,
+        mut client: KeyManagerServiceClient<Channel>,
+        realm: String,
+    ) -> Result<(), Status> {
+        let mut request = tonic::Request::new(WatchIssuingKeysRequest { realm });
+        request_id::set(&mut request, &request_id::generate());
+
+        let mut stream = client.watch_issuing_keys(request).await?.into_inner();
 
-                // TODO: Catch error or panic?
-                key_manager.update_keys().await.unwrap();
+        while let Some(response) = stream.message().await? {
+            debug!("Received key update.");
+
+            let mut key_manager = key_manager.write().await;
+
+            if let Err(e) = key_manager.apply_keys(response.keys) {
+                error!("Could not apply key update. {:?}", e);
             }
+        }
+
+        Ok(())
+    }
+
+    async fn verify_key_manager_settings(&mut self) -> Result<(), TokenIssuerError> {
+        let mut request = tonic::Request::new(HealthRequest {
+            realm: self.realm.clone(),
         });
+        request.set_timeout(self.key_request_timeout);
+
+        let call = self.key_manager_client.health(request);
+
+        let response = Self::with_deadline(self.key_request_timeout, call)
+            .await
+            .map_err(|e| KeyManagerError(format!("Could not get key manager health. {:?}", e)))?
+            .into_inner();
+
+        if response.key_lifetime != self.key_lifetime.as_secs() {
+            return Err(ConfigError(format!(
+                "Key lifetime mismatch. Configured {} seconds, key manager uses {} seconds.",
+                self.key_lifetime, response.key_lifetime
+            )));
+        }
+
+        // Older key managers do not report a scheme
+        Self::verify_key_scheme(&response.key_scheme).map_err(|e| ConfigError(e.to_string()))
+    }
+
+    fn verify_key_scheme(key_scheme: &str) -> Result<(), TokenIssuerError> {
+        if !key_scheme.is_empty() && key_scheme != KEY_SCHEME {
+            return Err(KeyManagerError(format!(
+                "Unsupported key scheme '{}'. Supported scheme is '{}'.",
+                key_scheme, KEY_SCHEME
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Retrieve the missing keys of the current and next epochs. The lock is only held to
+    // collect the epochs and to apply the keys, never while waiting for the key manager
+    async fn update_keys(key_manager: &RwLock<KeyManager>) -> Result<(), TokenIssuerError> {
+        let (current_epoch, next_epoch) = key_manager.read().await.get_key_epochs()?;
+
+        let result = Self::update_keys_of(key_manager, current_epoch, next_epoch).await;
+
+        let (fallback, previous_epoch) = {
+            let mut key_manager = key_manager.write().await;
+
+            let has_current_key =
+                matches!(&key_manager.current_key, Some(key) if key.epoch == Epoch(current_epoch));
+            key_manager.clock_drift_fallback = false;
+
+            (
+                result.is_err()
+                    && !has_current_key
+                    && key_manager.within_clock_drift_tolerance(current_epoch)?,
+                current_epoch.saturating_sub(key_manager.key_lifetime.as_secs()),
+            )
+        };
+
+        // Right after a boundary, a key manager whose clock is slightly behind may not
+        // have provisioned the current epoch yet
+        if let (true, Err(e)) = (fallback, &result) {
+            tracing::warn!(
+                epoch = current_epoch,
+                previous_epoch,
+                "Could not get the key of the current epoch, using the previous epoch \
+                 within the clock drift tolerance. {}",
+                e
+            );
+
+            Self::update_keys_of(key_manager, previous_epoch, current_epoch).await?;
+            key_manager.write().await.clock_drift_fallback = true;
+
+            return Ok(());
+        }
+
+        result
+    }
+
+    fn within_clock_drift_tolerance(&self, current_epoch: u64) -> Result<bool, TokenIssuerError> {
+        Ok(Self::now()? < current_epoch.saturating_add(self.clock_drift_tolerance))
+    }
+
+    async fn update_keys_of(
+        key_manager: &RwLock<KeyManager>,
+        current_epoch: u64,
+        next_epoch: u64,
+    ) -> Result<(), TokenIssuerError> {
+        tracing::debug!(epoch = current_epoch, "Current epoch");
+        tracing::debug!(epoch = next_epoch, "Next epoch");
+
+        let (epochs, key_retrieval) = {
+            let key_manager = key_manager.read().await;
+
+            (
+                key_manager.missing_epochs(current_epoch, next_epoch),
+                key_manager.key_retrieval(),
+            )
+        };
+
+        if epochs.is_empty() {
+            key_manager.write().await.last_successful_update = SystemTime::now();
+            return Ok(());
+        }
+
+        // Retrieve all the required keys in one call
+        let responses = key_retrieval.get_keys(&epochs).await?;
+
+        let keys = {
+            let key_manager = key_manager.read().await;
+
+            responses
+                .into_iter()
+                .map(|response| key_manager.decode_key(response, &epochs))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        key_manager
+            .write()
+            .await
+            .set_keys(keys, current_epoch, next_epoch)
     }
 
-    async fn update_keys(&mut self) -> Result<(), TokenIssuerError> {
-        let (current_epoch, next_epoch) = self.get_key_epochs();
+    // Epochs whose key is not loaded yet
+    fn missing_epochs(&self, current_epoch: u64, next_epoch: u64) -> Vec<u64> {
+        let mut epochs = Vec::with_capacity(2);
 
-        debug!("Current epoch: {}", current_epoch);
-        debug!("Next epoch: {}", next_epoch);
+        match &self.current_key {
+            Some(key) if key.epoch == Epoch(current_epoch) => {}
+            _ => epochs.push(current_epoch),
+        }
+
+        match &self.next_key {
+            Some(key) if key.epoch == Epoch(next_epoch) => {}
+            _ => epochs.push(next_epoch),
+        }
 
-        // Current key
-        if let Some(key) = &self.current_key {
-            if current_epoch != key.epoch {
-                // Update is required
-                let current_key = self.get_key(current_epoch).await?;
-                self.current_key = Some(current_key);
+        epochs
+    }
+
+    fn set_keys(
+        &mut self,
+        keys: Vec<KeyProfile>,
+        current_epoch: u64,
+        next_epoch: u64,
+    ) -> Result<(), TokenIssuerError> {
+        for key in keys {
+            self.set_key(key, current_epoch, next_epoch);
+        }
+
+        let result =
+            self.verify_key_epochs()
+                .and_then(|_| match (&self.current_key, &self.next_key) {
+                    (Some(current_key), Some(next_key))
+                        if current_key.epoch == Epoch(current_epoch)
+                            && next_key.epoch == Epoch(next_epoch) =>
+                    {
+                        Ok(())
+                    }
+                    _ => Err(KeyManagerError(format!(
+                        "Keys of epochs {} and {} are not all available.",
+                        current_epoch, next_epoch
+                    ))),
+                });
+        self.update_readiness();
+
+        if result.is_ok() {
+            self.last_successful_update = SystemTime::now();
+        }
+
+        result
+    }
+
+    fn update_readiness(&self) {
+        let ready = self.current_key.is_some() && self.next_key.is_some();
+
+        self.ready.store(ready, Ordering::Release);
+    }
+
+    // Next tokens must never be issued under a key of another epoch, so a mismatched
+    // next key is dropped
+    fn verify_key_epochs(&mut self) -> Result<(), TokenIssuerError> {
+        if let (Some(current_key), Some(next_key)) = (&self.current_key, &self.next_key) {
+            if next_key.epoch != current_key.epoch.next(self.key_lifetime) {
+                tracing::error!(
+                    current_epoch = current_key.epoch.as_secs(),
+                    next_epoch = next_key.epoch.as_secs(),
+                    key_lifetime = self.key_lifetime.as_secs(),
+                    "Next key does not follow the current key"
+                );
+
+                let error = IllegalStateError(format!(
+                    "Next key epoch {} does not follow current key epoch {}.",
+                    next_key.epoch, current_key.epoch
+                ));
+
+                self.next_key = None;
+
+                return Err(error);
             }
-        } else {
-            // Update is required
-            let current_key = self.get_key(current_epoch).await?;
-            self.current_key = Some(current_key);
         }
 
-        // Next key
-        if let Some(key) = &self.next_key {
-            if next_epoch != key.epoch {
-                // Update is required
-                let next_key = self.get_key(next_epoch).await?;
-                self.next_key = Some(next_key);
+        Ok(())
+    }
+
+    // Replace the keys of the current and next epochs, skipping any other epoch
+    fn apply_keys(&mut self, keys: Vec<GetIssuingKeyResponse>) -> Result<(), TokenIssuerError> {
+        let (current_epoch, next_epoch) = self.get_key_epochs()?;
+        let epochs = [current_epoch, next_epoch];
+
+        for key in keys {
+            if !epochs.contains(&key.epoch) {
+                continue;
             }
-        } else {
-            // Update is required
-            let next_key = self.get_key(next_epoch).await?;
-            self.next_key = Some(next_key);
+
+            let key = self.decode_key(key, &epochs)?;
+            self.set_key(key, current_epoch, next_epoch);
+        }
+
+        let result = self.verify_key_epochs();
+        self.update_readiness();
+
+        if result.is_ok() && self.is_ready() {
+            self.last_successful_update = SystemTime::now();
+            // The keys are up to date again, even if the scheduled update failed
+            self.consecutive_failures = 0;
+        }
+
+        result
+    }
+
+    fn set_key(&mut self, key: KeyProfile, current_epoch: u64, next_epoch: u64) {
+        tracing::info!(
+            epoch = key.epoch.as_secs(),
+            fingerprint = %key.key_fingerprint,
+            "Updated key"
+        );
+
+        if key.epoch == Epoch(current_epoch) {
+            match self.current_key.replace(key) {
+                // Same epoch, e.g. a revocation update
+                Some(previous_key) if previous_key.epoch == Epoch(current_epoch) => {}
+                Some(previous_key) => self.store_previous_key(previous_key),
+                None => {}
+            }
+        } else if key.epoch == Epoch(next_epoch) {
+            self.next_key = Some(key);
+        }
+    }
+
+    // Keep the key of an expired epoch for verifying older tokens
+    fn store_previous_key(&mut self, key: KeyProfile) {
+        if self.key_history_size > 0 {
+            if self.previous_keys.len() == self.key_history_size {
+                self.previous_keys.pop_front();
+            }
+
+            self.previous_keys.push_back(key);
+        }
+
+        // The signing keys of the dropped epochs are no longer issued under
+        let oldest_key = self.previous_keys.front().or(self.current_key.as_ref());
+        if let Some(oldest_key) = oldest_key {
+            self.signing_keys.retain_from(oldest_key.epoch.as_secs());
+        }
+    }
+
+    // Parameters of a key retrieval, so the retries do not hold the lock
+    fn key_retrieval(&self) -> KeyRetrieval {
+        KeyRetrieval {
+            client: self.key_manager_client.clone(),
+            realm: self.realm.clone(),
+            attempts: self.retrieve_key_attempts,
+            interval: self.retrieve_key_interval,
+            max_interval: self.retrieve_key_max_interval,
+            timeout: self.key_request_timeout,
+        }
+    }
+
+    // Missing keys stay distinguishable from an unreachable key manager
+    fn retrieval_error(attempts: u8, last_error: Option<Status>) -> TokenIssuerError {
+        match last_error {
+            Some(e) if e.code() == Code::NotFound => NotFoundError(format!(
+                "Could not get issuing keys in {} attempts. {:?}",
+                attempts, e
+            )),
+            Some(e) => KeyManagerError(format!(
+                "Could not get issuing keys in {} attempts. {:?}",
+                attempts, e
+            )),
+            None => KeyManagerError(format!("Could not get issuing keys.")),
+        }
+    }
+
+    // Gives up on a key manager request once its deadline passed, even if the key manager
+    // does not honor the deadline
+    async fn with_deadline<T>(
+        timeout: Duration,
+        call: impl Future<Output = Result<Response<T>, Status>>,
+    ) -> Result<Response<T>, Status> {
+        tokio::time::timeout(timeout, call)
+            .await
+            .unwrap_or_else(|_| Err(Status::deadline_exceeded("Key manager request timed out.")))
+    }
+
+    fn decode_key(
+        &self,
+        response: GetIssuingKeyResponse,
+        epochs: &[u64],
+    ) -> Result<KeyProfile, TokenIssuerError> {
+        // Make sure the key manager returned a requested key
+        if !epochs.contains(&response.epoch) {
+            return Err(KeyManagerError(format!(
+                "Key epoch mismatch. Requested {:?}, got {}",
+                epochs, response.epoch
+            )));
+        }
+
+        Self::verify_key_scheme(&response.key_scheme)?;
+        let key_format_version =
+            Self::verify_key_format_version(response.epoch, response.key_format_version)?;
+
+        let params = PsParams::deserialize(&response.params).map_err(|e| {
+            DeserializationError(format!(
+                "Could not deserialize the key params of epoch {}. {:?}",
+                response.epoch, e
+            ))
+        })?;
+        let signing_key = PsSigningKey::deserialize(&response.signing_key).map_err(|e| {
+            DeserializationError(format!(
+                "Could not deserialize the signing key of epoch {}. {:?}",
+                response.epoch, e
+            ))
+        })?;
+        let public_key = PsPublicKey::deserialize(&response.public_key).map_err(|e| {
+            DeserializationError(format!(
+                "Could not deserialize the public key of epoch {}. {:?}",
+                response.epoch, e
+            ))
+        })?;
+
+        // A corrupted or tampered response must never be used to sign tokens
+        Self::verify_key_pair(response.epoch, &params, &signing_key, &response.public_key)?;
+
+        let material = Arc::new(KeyMaterial {
+            params,
+            signing_key,
+            public_key,
+            message_count: response.message_count as usize,
+        });
+        self.signing_keys.insert(response.epoch, material.clone());
+
+        Ok(KeyProfile {
+            epoch: Epoch(response.epoch),
+            material,
+            key_lifetime: self.key_lifetime,
+            revocation_reason: Some(response.revocation_reason).filter(|reason| !reason.is_empty()),
+            key_fingerprint: key_fingerprint(&response.public_key),
+            key_format_version,
+        })
+    }
+
+    // Keys of another serialization format would be misread. Older key managers do not
+    // report a format
+    fn verify_key_format_version(
+        epoch: u64,
+        key_format_version: u32,
+    ) -> Result<u8, TokenIssuerError> {
+        let key_format_version = match key_format_version {
+            0 => LEGACY_KEY_FORMAT_VERSION,
+            key_format_version => u8::try_from(key_format_version).unwrap_or(u8::MAX),
+        };
+
+        if key_format_version != KEY_FORMAT_VERSION {
+            return Err(KeyManagerError(format!(
+                "Key of epoch {} has format version {}. Supported version is {}.",
+                epoch, key_format_version, KEY_FORMAT_VERSION
+            )));
+        }
+
+        Ok(key_format_version)
+    }
+
+    // The public key must be derived from the signing key under the params
+    fn verify_key_pair(
+        epoch: u64,
+        params: &PsParams,
+        signing_key: &PsSigningKey,
+        public_key: &[u8],
+    ) -> Result<(), TokenIssuerError> {
+        let derived_public_key = signing_key
+            .derive_public_key(params)
+            .serialize()
+            .map_err(|e| {
+                KeyManagerError(format!(
+                    "Could not serialize the derived public key of epoch {}. {:?}",
+                    epoch, e
+                ))
+            })?;
+
+        if derived_public_key != public_key {
+            return Err(KeyManagerError(format!(
+                "Public key of epoch {} does not match its signing key.",
+                epoch
+            )));
         }
 
         Ok(())
     }
 
-    async fn get_key(&mut self, epoch: u64) -> Result<KeyProfile, TokenIssuerError> {
-        let mut response = None;
+    // (current, next)
+    fn get_key_epochs(&self) -> Result<(u64, u64), TokenIssuerError> {
+        let (current_epoch, next_epoch) =
+            epoch::key_epochs(Self::now()?, self.key_lifetime, self.epoch_offset);
 
-        for _ in 0..RETRIEVE_KEY_ATTEMPTS {
-            debug!("Retrieving key for epoch {}", epoch);
-            let request = tonic::Request::new(GetIssuingKeyRequest { epoch });
+        Ok((current_epoch.as_secs(), next_epoch.as_secs()))
+    }
+
+    pub fn now() -> Result<u64, TokenIssuerError> {
+        epoch::now()
+            .map_err(|e| ClockError(format!("System clock is before the unix epoch. {}", e)))
+    }
+
+    fn calculate_next_key_update(
+        key_lifetime: Seconds,
+        epoch_offset: Seconds,
+    ) -> Result<Instant, TokenIssuerError> {
+        let now = Self::now()?;
+
+        Ok(epoch::next_key_update_at(now, Instant::now(), key_lifetime, epoch_offset))
+    }
 
-            let result = match self.key_manager_client.get_issuing_key(request).await {
-                Ok(response) => Some(response),
+    // Key updates are aligned to the epochs, so wait for the clock to become sane
+    async fn wait_for_next_key_update(key_lifetime: Seconds, epoch_offset: Seconds) -> Instant {
+        loop {
+            match Self::calculate_next_key_update(key_lifetime, epoch_offset) {
+                Ok(next_key_update) => return next_key_update,
                 Err(e) => {
-                    if Code::NotFound == e.code() {
-                        debug!("Key retrieval failed, trying again...");
+                    error!("Could not schedule key updates. {}", e);
+                    tokio::time::sleep(Duration::from_secs(KEY_UPDATE_RETRY_INTERVAL)).await;
+                }
+            }
+        }
+    }
+}
+
+// Retrieves keys from the key manager, retrying while they are missing or the key manager
+// is unavailable
+struct KeyRetrieval {
+    // Clones share the underlying channel
+    client: KeyManagerServiceClient<Channel>,
+
+    realm: String,
+
+    attempts: u8,
+
+    // Base and max retry intervals in milliseconds
+    interval: u64,
+
+    max_interval: u64,
+
+    timeout: Duration,
+}
+
+impl KeyRetrieval {
+    // Returns the keys found on the last attempt if some keys are still missing after all
+    // the attempts, otherwise the error of the last attempt
+    async fn get_keys(
+        mut self,
+        epochs: &[u64],
+    ) -> Result<Vec<GetIssuingKeyResponse>, TokenIssuerError> {
+        let mut response = None;
+        let mut partial_response = None;
+        // Only the last error is kept
+        let mut last_error = None;
+
+        // Correlates the retrieval with the key manager logs
+        let request_id = request_id::generate();
+
+        for attempt in 0..self.attempts {
+            tracing::debug!(epochs = ?epochs, request_id = %request_id, "Retrieving keys");
+            let mut request = tonic::Request::new(GetIssuingKeysRequest {
+                epochs: epochs.to_vec(),
+                realm: self.realm.clone(),
+            });
+            request_id::set(&mut request, &request_id);
+            request.set_timeout(self.timeout);
+
+            let call = self.client.get_issuing_keys(request);
+
+            let result = match KeyManager::with_deadline(self.timeout, call).await {
+                Ok(response) => {
+                    let response = response.into_inner();
+
+                    if response.keys.len() == epochs.len() {
+                        Some(response)
+                    } else {
+                        debug!("Some keys are missing, trying again...");
+                        partial_response = Some(response);
                         // Try again
                         None
-                    } else {
-                        return Err(KeyManagerError(format!("Could not get key. {:?}", e)));
                     }
                 }
+                Err(e) => {
+                    match e.code() {
+                        Code::NotFound => debug!("Key retrieval failed, trying again..."),
+                        // The channel reconnects on the next attempt
+                        Code::Unavailable => debug!("Key manager unavailable, trying again..."),
+                        Code::DeadlineExceeded | Code::Cancelled => {
+                            debug!("Key manager did not respond in time, trying again...")
+                        }
+                        _ => return Err(KeyManagerError(format!("Could not get keys. {:?}", e))),
+                    }
+
+                    // Try again
+                    last_error = Some(e);
+                    None
+                }
             };
 
             if let Some(r) = result {
@@ -166,70 +943,357 @@ impl KeyManager {
                 break;
             }
 
-            thread::sleep(Duration::from_secs(RETRIEVE_KEY_INTERVAL));
+            if attempt + 1 < self.attempts {
+                tokio::time::sleep(self.retry_delay(attempt)).await;
+            }
         }
 
-        if let None = response {
-            return Err(KeyManagerError(format!("Could not get issuing key.")));
+        let response = match response.or(partial_response) {
+            Some(response) => response,
+            None => return Err(KeyManager::retrieval_error(self.attempts, last_error)),
+        };
+
+        Ok(response.keys)
+    }
+
+    // Exponential backoff with jitter, in [delay / 2, delay]
+    fn retry_delay(&self, attempt: u8) -> Duration {
+        let delay = self
+            .interval
+            .saturating_mul(1 << attempt.min(32))
+            .min(self.max_interval);
+
+        let jitter = thread_rng().gen_range(0, delay / 2 + 1);
+
+        Duration::from_millis(delay - delay / 2 + jitter)
+    }
+}
+
+pub struct KeyProfile {
+    pub epoch: Epoch,
+
+    pub material: Arc<KeyMaterial>,
+
+    pub key_lifetime: Seconds,
+
+    // Set when the key manager revoked the epoch
+    pub revocation_reason: Option<String>,
+
+    // Computed from the received public key, for comparing keys with the key manager
+    pub key_fingerprint: String,
+
+    // Serialization format of the key, reported to the verifiers
+    pub key_format_version: u8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener;
+    use crate::manager::grpc::key_manager_service::key_manager_service_server::{
+        KeyManagerService, KeyManagerServiceServer,
+    };
+    use crate::manager::grpc::key_manager_service::{
+        GetIssuingKeysResponse, GetPublicKeyHistoryRequest, GetPublicKeyHistoryResponse,
+        HealthResponse, ListEpochsRequest, ListEpochsResponse, VersionRequest, VersionResponse,
+    };
+    use config::{Config, File, FileFormat};
+    use std::vec::IntoIter;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use tokio_stream::Iter;
+    use tonic::transport::Server;
+    use tonic::{Request, Response, Status};
+
+    const TEST_CONFIG: &str = "
+host: 127.0.0.1
+port: 30041
+key_lifetime: 10
+key_manager_endpoint: http://127.0.0.1:30051
+key_manager_ca: ca.pem
+key_manager_auth_cert: auth.pem
+key_manager_auth_key: auth.key
+tls_cert: server.pem
+tls_key: server.key
+auth_ca: auth_ca.pem
+retrieve_key_attempts: 3
+retrieve_key_interval: 0
+retrieve_key_max_interval: 0
+";
+
+    // Key lifetime in seconds
+    const KEY_LIFETIME: u64 = 600;
+
+    // Serves a single key for every epoch
+    struct MockKeyManager {
+        params: Vec<u8>,
+
+        signing_key: Vec<u8>,
+
+        public_key: Vec<u8>,
+    }
+
+    impl MockKeyManager {
+        fn new() -> Self {
+            let mut rng = thread_rng();
+
+            let params = PsParams::generate(&mut rng);
+            let signing_key = PsSigningKey::generate(1, &params, &mut rng);
+            let public_key = signing_key.derive_public_key(&params);
+
+            Self {
+                params: params.serialize().unwrap(),
+                signing_key: signing_key.serialize().unwrap(),
+                public_key: public_key.serialize().unwrap(),
+            }
         }
 
-        // Decode the response
-        let response = response.unwrap().into_inner();
+        fn key_response(&self, epoch: u64) -> GetIssuingKeyResponse {
+            GetIssuingKeyResponse {
+                signing_key: self.signing_key.clone(),
+                public_key: self.public_key.clone(),
+                params: self.params.clone(),
+                epoch,
+                message_count: 1,
+                revocation_reason: String::new(),
+                key_scheme: KEY_SCHEME.to_string(),
+                key_fingerprint: key_fingerprint(&self.public_key),
+                key_format_version: KEY_FORMAT_VERSION as u32,
+            }
+        }
+    }
 
-        let params = PsParams::deserialize(&response.params)
-            .map_err(|e| DeserializationError(format!("Could not deserialize {:?}", e)))?;
-        let signing_key = PsSigningKey::deserialize(&response.signing_key)
-            .map_err(|e| DeserializationError(format!("Could not deserialize {:?}", e)))?;
-        let public_key = PsPublicKey::deserialize(&response.public_key)
-            .map_err(|e| DeserializationError(format!("Could not deserialize {:?}", e)))?;
+    #[tonic::async_trait]
+    impl KeyManagerService for MockKeyManager {
+        async fn get_issuing_key(
+            &self,
+            request: Request<GetIssuingKeyRequest>,
+        ) -> Result<Response<GetIssuingKeyResponse>, Status> {
+            Ok(Response::new(self.key_response(request.into_inner().epoch)))
+        }
 
-        Ok(KeyProfile {
-            epoch,
-            params,
-            signing_key,
-            public_key,
-            key_lifetime: self.key_lifetime,
-        })
+        async fn get_issuing_keys(
+            &self,
+            request: Request<GetIssuingKeysRequest>,
+        ) -> Result<Response<GetIssuingKeysResponse>, Status> {
+            let keys = request
+                .into_inner()
+                .epochs
+                .into_iter()
+                .map(|epoch| self.key_response(epoch))
+                .collect();
+
+            Ok(Response::new(GetIssuingKeysResponse { keys }))
+        }
+
+        type WatchIssuingKeysStream = Iter<IntoIter<Result<GetIssuingKeysResponse, Status>>>;
+
+        // Sends the keys of the current and next epochs once
+        async fn watch_issuing_keys(
+            &self,
+            _: Request<WatchIssuingKeysRequest>,
+        ) -> Result<Response<Self::WatchIssuingKeysStream>, Status> {
+            let now = epoch::now().unwrap();
+            let current_epoch =
+                epoch::current_epoch(now, Seconds(KEY_LIFETIME), Seconds(0)).as_secs();
+
+            let keys = vec![
+                self.key_response(current_epoch),
+                self.key_response(current_epoch + KEY_LIFETIME),
+            ];
+
+            Ok(Response::new(tokio_stream::iter(vec![Ok(
+                GetIssuingKeysResponse { keys },
+            )])))
+        }
+
+        async fn get_public_key_history(
+            &self,
+            _: Request<GetPublicKeyHistoryRequest>,
+        ) -> Result<Response<GetPublicKeyHistoryResponse>, Status> {
+            Err(Status::unimplemented("Not used by the issuer"))
+        }
+
+        async fn list_epochs(
+            &self,
+            _: Request<ListEpochsRequest>,
+        ) -> Result<Response<ListEpochsResponse>, Status> {
+            Err(Status::unimplemented("Not used by the issuer"))
+        }
+
+        async fn health(
+            &self,
+            _: Request<HealthRequest>,
+        ) -> Result<Response<HealthResponse>, Status> {
+            Ok(Response::new(HealthResponse {
+                current_epoch: 0,
+                next_epoch: 0,
+                key_lifetime: KEY_LIFETIME,
+                ready: true,
+                tls_cert_not_after: 0,
+                key_scheme: KEY_SCHEME.to_string(),
+            }))
+        }
+
+        async fn get_version(
+            &self,
+            _: Request<VersionRequest>,
+        ) -> Result<Response<VersionResponse>, Status> {
+            Err(Status::unimplemented("Not used by the issuer"))
+        }
     }
 
-    // (current, next)
-    fn get_key_epochs(&self) -> (u64, u64) {
-        let now = SystemTime::now();
-        let now = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    fn create_config() -> TokenIssuerConfig {
+        let mut config = Config::new();
+        config
+            .merge(File::from_str(TEST_CONFIG, FileFormat::Yaml))
+            .unwrap();
+
+        config.try_into().unwrap()
+    }
+
+    // Serves the mock key manager on a bound listener, e.g. bound to "127.0.0.1:0"
+    async fn start_key_manager(listener: TcpListener) -> (oneshot::Sender<()>, JoinHandle<()>) {
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+
+        let handle = tokio::spawn(async move {
+            Server::builder()
+                .add_service(KeyManagerServiceServer::new(MockKeyManager::new()))
+                .serve_with_incoming_shutdown(
+                    listener::incoming(Arc::new(listener), None),
+                    async {
+                        shutdown_signal.await.ok();
+                    },
+                )
+                .await
+                .unwrap();
+        });
+
+        (shutdown, handle)
+    }
+
+    #[tokio::test]
+    async fn key_retrieval_recovers_after_key_manager_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown, handle) = start_key_manager(listener).await;
+
+        let endpoint = Endpoint::from_shared(format!("http://{}", address)).unwrap();
+        let key_manager = KeyManager::new(endpoint.connect_lazy(), &create_config()).unwrap();
+
+        let (current_epoch, _) = key_manager.get_key_epochs().unwrap();
 
-        let current_epoch = now - (now % self.key_lifetime);
+        assert!(key_manager
+            .key_retrieval()
+            .get_keys(&[current_epoch])
+            .await
+            .is_ok());
+
+        // Stop the key manager
+        shutdown.send(()).unwrap();
+        handle.await.unwrap();
+
+        // Reported as unreachable rather than as missing keys
+        assert!(matches!(
+            key_manager.key_retrieval().get_keys(&[current_epoch]).await,
+            Err(KeyManagerError(_))
+        ));
 
-        let next_epoch = current_epoch + self.key_lifetime;
+        // Restart it on the same address
+        let listener = TcpListener::bind(address).await.unwrap();
+        let (shutdown, handle) = start_key_manager(listener).await;
+
+        let keys = key_manager
+            .key_retrieval()
+            .get_keys(&[current_epoch])
+            .await
+            .unwrap();
+        assert_eq!(keys[0].epoch, current_epoch);
 
-        (current_epoch, next_epoch)
+        shutdown.send(()).unwrap();
+        handle.await.unwrap();
     }
 
-    fn calculate_next_key_update(key_lifetime: u64) -> Instant {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let now_instant = Instant::now();
+    #[tokio::test]
+    async fn watched_key_updates_are_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown, handle) = start_key_manager(listener).await;
 
-        let current_epoch = now - (now % key_lifetime);
-        let next_epoch = current_epoch + key_lifetime;
+        let endpoint = Endpoint::from_shared(format!("http://{}", address)).unwrap();
+        let key_manager = Arc::new(RwLock::new(
+            KeyManager::new(endpoint.connect_lazy(), &create_config()).unwrap(),
+        ));
+
+        let watcher = KeyManager::watch_key_updates(key_manager.clone());
+
+        // Give the watcher time to receive the keys
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        {
+            let key_manager = key_manager.read().await;
+            let (current_epoch, next_epoch) = key_manager.get_key_epochs().unwrap();
+
+            assert_eq!(
+                key_manager.get_current_key().as_ref().unwrap().epoch,
+                Epoch(current_epoch)
+            );
+            assert_eq!(
+                key_manager.get_next_key().as_ref().unwrap().epoch,
+                Epoch(next_epoch)
+            );
+        }
 
-        // Get next epoch as instant
-        let time_until_next_epoch = next_epoch - now;
-        let next_epoch = now_instant + Duration::from_secs(time_until_next_epoch);
+        watcher.shutdown().await;
 
-        next_epoch
+        shutdown.send(()).unwrap();
+        handle.await.unwrap();
     }
-}
 
-pub struct KeyProfile {
-    pub epoch: u64,
+    #[tokio::test]
+    async fn keys_with_a_mismatched_public_key_are_rejected() {
+        let endpoint = Endpoint::from_static("http://127.0.0.1:30051");
+        let key_manager = KeyManager::new(endpoint.connect_lazy(), &create_config()).unwrap();
+
+        let mock = MockKeyManager::new();
+        let (current_epoch, _) = key_manager.get_key_epochs().unwrap();
+
+        assert!(key_manager
+            .decode_key(mock.key_response(current_epoch), &[current_epoch])
+            .is_ok());
+
+        // Public key of another signing key
+        let mut response = mock.key_response(current_epoch);
+        response.public_key = MockKeyManager::new().public_key;
 
-    pub params: PsParams,
+        assert!(matches!(
+            key_manager.decode_key(response, &[current_epoch]),
+            Err(KeyManagerError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn keys_of_an_unsupported_format_are_rejected() {
+        let endpoint = Endpoint::from_static("http://127.0.0.1:30051");
+        let key_manager = KeyManager::new(endpoint.connect_lazy(), &create_config()).unwrap();
+
+        let mock = MockKeyManager::new();
+        let (current_epoch, _) = key_manager.get_key_epochs().unwrap();
 
-    pub signing_key: PsSigningKey,
+        // Older key managers do not report a format
+        let mut response = mock.key_response(current_epoch);
+        response.key_format_version = 0;
 
-    pub public_key: PsPublicKey,
+        assert!(key_manager.decode_key(response, &[current_epoch]).is_ok());
 
-    pub key_lifetime: u64,
+        // Serialized by an incompatible ps_signatures version
+        let mut response = mock.key_response(current_epoch);
+        response.key_format_version = KEY_FORMAT_VERSION as u32 + 1;
+
+        assert!(matches!(
+            key_manager.decode_key(response, &[current_epoch]),
+            Err(KeyManagerError(_))
+        ));
+    }
 }
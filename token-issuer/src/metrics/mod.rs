@@ -0,0 +1,160 @@
+use crate::error::TokenIssuerError;
+use crate::error::TokenIssuerError::MetricsError;
+use crate::manager::KeyManager;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+pub const ISSUE_TOKEN: &str = "issue_token";
+pub const ISSUE_NEXT_TOKEN: &str = "issue_next_token";
+pub const ISSUE_PREVIOUS_TOKEN: &str = "issue_previous_token";
+pub const ISSUE_TOKEN_FOR_EPOCH: &str = "issue_token_for_epoch";
+
+pub struct Metrics {
+    registry: Registry,
+
+    // Issued tokens by request type and epoch
+    pub issued_tokens: IntCounterVec,
+
+    // Root token issuance latency in seconds
+    pub issuance_latency: Histogram,
+
+    // Wait for the key lock before issuance in seconds
+    pub key_lock_wait: Histogram,
+
+    pub current_epoch: IntGauge,
+
+    // Seconds since the last successful key update
+    pub key_staleness: IntGauge,
+
+    // Unix timestamp after which the server TLS certificate is no longer valid
+    pub tls_cert_not_after: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, TokenIssuerError> {
+        let registry = Registry::new();
+
+        let issued_tokens = IntCounterVec::new(
+            Opts::new("veronymous_issued_tokens_total", "Number of issued tokens"),
+            &["request", "epoch"],
+        )
+        .map_err(|e| MetricsError(format!("Could not create issued tokens counter. {:?}", e)))?;
+
+        let issuance_latency = Histogram::with_opts(HistogramOpts::new(
+            "veronymous_token_issuance_seconds",
+            "Root token issuance latency",
+        ))
+        .map_err(|e| MetricsError(format!("Could not create issuance histogram. {:?}", e)))?;
+
+        let key_lock_wait = Histogram::with_opts(HistogramOpts::new(
+            "veronymous_key_lock_wait_seconds",
+            "Wait for the key lock before issuance",
+        ))
+        .map_err(|e| MetricsError(format!("Could not create lock wait histogram. {:?}", e)))?;
+
+        let current_epoch =
+            IntGauge::new("veronymous_current_epoch", "Epoch of the current issuing key")
+                .map_err(|e| MetricsError(format!("Could not create epoch gauge. {:?}", e)))?;
+
+        let key_staleness = IntGauge::new(
+            "veronymous_key_staleness_seconds",
+            "Seconds since the last successful key update",
+        )
+        .map_err(|e| MetricsError(format!("Could not create staleness gauge. {:?}", e)))?;
+
+        let tls_cert_not_after = IntGauge::new(
+            "veronymous_tls_cert_not_after_seconds",
+            "Expiry of the server TLS certificate",
+        )
+        .map_err(|e| MetricsError(format!("Could not create expiry gauge. {:?}", e)))?;
+
+        registry
+            .register(Box::new(issued_tokens.clone()))
+            .map_err(|e| MetricsError(format!("Could not register metric. {:?}", e)))?;
+        registry
+            .register(Box::new(issuance_latency.clone()))
+            .map_err(|e| MetricsError(format!("Could not register metric. {:?}", e)))?;
+        registry
+            .register(Box::new(key_lock_wait.clone()))
+            .map_err(|e| MetricsError(format!("Could not register metric. {:?}", e)))?;
+        registry
+            .register(Box::new(current_epoch.clone()))
+            .map_err(|e| MetricsError(format!("Could not register metric. {:?}", e)))?;
+        registry
+            .register(Box::new(key_staleness.clone()))
+            .map_err(|e| MetricsError(format!("Could not register metric. {:?}", e)))?;
+        registry
+            .register(Box::new(tls_cert_not_after.clone()))
+            .map_err(|e| MetricsError(format!("Could not register metric. {:?}", e)))?;
+
+        Ok(Self {
+            registry,
+            issued_tokens,
+            issuance_latency,
+            key_lock_wait,
+            current_epoch,
+            key_staleness,
+            tls_cert_not_after,
+        })
+    }
+
+    // Serve the metrics over http until the process exits
+    pub async fn serve(
+        metrics: Arc<Self>,
+        key_manager: Arc<RwLock<KeyManager>>,
+        address: SocketAddr,
+    ) -> Result<(), TokenIssuerError> {
+        let make_service = make_service_fn(move |_| {
+            let metrics = metrics.clone();
+            let key_manager = key_manager.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                    let metrics = metrics.clone();
+                    let key_manager = key_manager.clone();
+
+                    async move { Ok::<_, Infallible>(metrics.render(&key_manager).await) }
+                }))
+            }
+        });
+
+        info!("Serving metrics on {}", address);
+
+        Server::bind(&address)
+            .serve(make_service)
+            .await
+            .map_err(|e| MetricsError(format!("Metrics server failed. {:?}", e)))
+    }
+
+    async fn render(&self, key_manager: &RwLock<KeyManager>) -> Response<Body> {
+        {
+            let key_manager = key_manager.read().await;
+
+            if let Some(key) = key_manager.get_current_key() {
+                self.current_epoch.set(key.epoch.as_secs() as i64);
+            }
+
+            self.key_staleness.set(key_manager.get_staleness() as i64);
+        }
+
+        let mut buffer = Vec::new();
+
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Could not encode metrics. {:?}", e);
+
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+
+            return response;
+        }
+
+        Response::new(Body::from(buffer))
+    }
+}
@@ -0,0 +1,93 @@
+use crate::error::TokenIssuerError;
+use crate::error::TokenIssuerError::{NotFoundError, TokenError};
+use ps_signatures::keys::{PsParams, PsPublicKey};
+use rand::thread_rng;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use veronymous_token::root_exchange::{issue_root_token, RootTokenRequest};
+use veronymous_token::serde::Serializable;
+use vt_common::key::KeyMaterial;
+
+// Signs root tokens under the issuing key of an epoch. Returns the serialized token response.
+// The signing key is looked up by the epoch, so a backend that keeps it outside of the
+// process (e.g. an HSM) never has to export it
+pub trait Signer: Send + Sync {
+    fn issue(
+        &self,
+        epoch: u64,
+        token_request: &RootTokenRequest,
+        params: &PsParams,
+        public_key: &PsPublicKey,
+    ) -> Result<Vec<u8>, TokenIssuerError>;
+}
+
+// Key material of the epochs loaded or fetched by the key manager, shared with the
+// in-memory signer
+#[derive(Default)]
+pub struct SigningKeys {
+    keys: RwLock<BTreeMap<u64, Arc<KeyMaterial>>>,
+}
+
+impl SigningKeys {
+    pub fn insert(&self, epoch: u64, material: Arc<KeyMaterial>) {
+        self.keys
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(epoch, material);
+    }
+
+    pub fn get(&self, epoch: u64) -> Option<Arc<KeyMaterial>> {
+        self.keys
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&epoch)
+            .cloned()
+    }
+
+    // Forget the keys of the epochs before the oldest epoch
+    pub fn retain_from(&self, oldest_epoch: u64) {
+        self.keys
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|epoch, _| *epoch >= oldest_epoch);
+    }
+}
+
+// Signs with the signing keys fetched from the key manager
+pub struct InMemorySigner {
+    signing_keys: Arc<SigningKeys>,
+}
+
+impl InMemorySigner {
+    pub fn new(signing_keys: Arc<SigningKeys>) -> Self {
+        Self { signing_keys }
+    }
+}
+
+impl Signer for InMemorySigner {
+    fn issue(
+        &self,
+        epoch: u64,
+        token_request: &RootTokenRequest,
+        params: &PsParams,
+        public_key: &PsPublicKey,
+    ) -> Result<Vec<u8>, TokenIssuerError> {
+        let material = self
+            .signing_keys
+            .get(epoch)
+            .ok_or_else(|| NotFoundError(format!("No signing key for epoch {}.", epoch)))?;
+
+        let mut rng = thread_rng();
+
+        let token_response = issue_root_token(
+            token_request,
+            &material.signing_key,
+            public_key,
+            params,
+            &mut rng,
+        )
+        .map_err(|e| TokenError(format!("Could not issue root token. {:?}", e)))?;
+
+        Ok(token_response.serialize())
+    }
+}
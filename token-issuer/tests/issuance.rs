@@ -0,0 +1,322 @@
+// Issues tokens through the token service with the keys of a real key manager, served over
+// TLS on an in-memory key store
+use config::{Config, File, FileFormat};
+use ff_zeroize::Field;
+use pairing_plus::bls12_381::Fr;
+use rand::thread_rng;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, watch, RwLock};
+use tokio::task::JoinHandle;
+use tonic::transport::Server;
+use uuid::Uuid;
+use veronymous_token::root_exchange::{
+    complete_root_token, create_root_token_request, RootTokenResponse,
+};
+use veronymous_token::serde::Serializable;
+use vt_issuer::config::TokenIssuerConfig;
+use vt_issuer::controller::token_issuer_controller::TokenIssuerController;
+use vt_issuer::grpc::veronymous_token_service::veronymous_token_service_client::VeronymousTokenServiceClient;
+use vt_issuer::grpc::veronymous_token_service::veronymous_token_service_server::VeronymousTokenServiceServer;
+use vt_issuer::grpc::veronymous_token_service::TokenRequest;
+use vt_issuer::issuer::TokenIssuer;
+use vt_issuer::manager::KeyManager;
+use vt_issuer::metrics::Metrics;
+use vt_issuer::signer::InMemorySigner;
+use vt_key_manager::audit::AuditLog;
+use vt_key_manager::config::KeyManagerConfig;
+use vt_key_manager::manager::{read_lock, Realms};
+use vt_key_manager::server;
+use vt_key_manager::store::MemoryKeyStore;
+use vt_key_manager::tls;
+
+const KEY_MANAGER_CONFIG: &str = "
+host: 127.0.0.1
+port: 0
+key_file: keys.db
+key_lifetime: 10
+retention_epochs: 2
+";
+
+const TOKEN_ISSUER_CONFIG: &str = "
+host: 127.0.0.1
+port: 0
+key_lifetime: 10
+tls_cert: server.pem
+tls_key: server.key
+retrieve_key_attempts: 3
+retrieve_key_interval: 0
+retrieve_key_max_interval: 0
+";
+
+// Key lifetime in seconds
+const KEY_LIFETIME: u64 = 600;
+
+// Certificates of a test CA, written to a temporary directory
+struct TestCerts {
+    dir: PathBuf,
+}
+
+impl TestCerts {
+    fn generate() -> Self {
+        let dir = std::env::temp_dir().join(format!("vt-issuer-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "vt-test-ca");
+        let ca = Certificate::from_params(ca_params).unwrap();
+
+        fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+        let certs = Self { dir };
+        certs.write_signed("key-manager", "localhost", &ca);
+        certs.write_signed("token-issuer", "token-issuer", &ca);
+
+        certs
+    }
+
+    // Certificate and key of the name, signed by the CA
+    fn write_signed(&self, name: &str, subject: &str, ca: &Certificate) {
+        let mut params = CertificateParams::new(vec![subject.to_string()]);
+        params.distinguished_name.push(DnType::CommonName, subject);
+        let cert = Certificate::from_params(params).unwrap();
+
+        let pem = cert.serialize_pem_with_signer(ca).unwrap();
+
+        fs::write(self.path(&format!("{}.pem", name)), pem).unwrap();
+        fs::write(self.path(&format!("{}.key", name)), cert.serialize_private_key_pem()).unwrap();
+    }
+
+    fn path(&self, file: &str) -> String {
+        self.dir.join(file).to_string_lossy().to_string()
+    }
+}
+
+impl Drop for TestCerts {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Key manager served in-process with the server stack of the key manager binary
+struct TestKeyManager {
+    realms: Arc<Realms>,
+
+    address: SocketAddr,
+
+    shutdown: oneshot::Sender<()>,
+
+    // Ends the key watches
+    watch_shutdown: watch::Sender<bool>,
+
+    handle: JoinHandle<()>,
+}
+
+impl TestKeyManager {
+    async fn start(certs: &TestCerts) -> Self {
+        let config: KeyManagerConfig = parse_config(&format!(
+            "{}tls_cert: {}\ntls_key: {}\nclient_ca: {}\n",
+            KEY_MANAGER_CONFIG,
+            certs.path("key-manager.pem"),
+            certs.path("key-manager.key"),
+            certs.path("ca.pem"),
+        ));
+
+        let realms =
+            Realms::create_with_key_store(Arc::new(MemoryKeyStore::default()), &config).unwrap();
+
+        // Keys of the current and next epochs, as provisioned by the key update scheduler
+        realms.update_keys().unwrap();
+
+        let (watch_shutdown, watch_shutdown_receiver) = watch::channel(false);
+        let key_manager_service = server::key_manager_service(
+            &config,
+            realms.clone(),
+            Arc::new(AtomicI64::new(0)),
+            Arc::new(AuditLog::create(&None).unwrap()),
+            watch_shutdown_receiver,
+        );
+        let (_, health_service) = tonic_health::server::health_reporter();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+
+        let server = server::serve(
+            &config,
+            Some(tls::build_tls_config(&config).unwrap()),
+            Arc::new(listener),
+            health_service,
+            key_manager_service,
+            async {
+                shutdown_signal.await.ok();
+            },
+        )
+        .unwrap();
+
+        let handle = tokio::spawn(async move {
+            server.await.unwrap();
+        });
+
+        Self {
+            realms,
+            address,
+            shutdown,
+            watch_shutdown,
+            handle,
+        }
+    }
+
+    async fn stop(self) {
+        let _ = self.watch_shutdown.send(true);
+        self.shutdown.send(()).unwrap();
+        self.handle.await.unwrap();
+    }
+}
+
+fn parse_config<T: DeserializeOwned>(yaml: &str) -> T {
+    let mut config = Config::new();
+    config
+        .merge(File::from_str(yaml, FileFormat::Yaml))
+        .unwrap();
+
+    config.try_into().unwrap()
+}
+
+// Serves the token service on a bound listener
+async fn start_token_issuer(
+    config: &TokenIssuerConfig,
+    key_manager: Arc<RwLock<KeyManager>>,
+) -> (SocketAddr, oneshot::Sender<()>, JoinHandle<()>) {
+    let ready = key_manager.read().await.readiness();
+    let signer = InMemorySigner::new(key_manager.read().await.signing_keys());
+    let token_issuer = TokenIssuer::new(
+        key_manager,
+        Box::new(signer),
+        Arc::new(Metrics::new().unwrap()),
+        config,
+        None,
+    );
+    let controller = TokenIssuerController::new(
+        token_issuer,
+        config.max_token_request_bytes,
+        Vec::new(),
+        ready,
+        Arc::new(AtomicBool::new(false)),
+        tonic_health::server::health_reporter().0,
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+
+    let handle = tokio::spawn(async move {
+        Server::builder()
+            .add_service(VeronymousTokenServiceServer::new(controller))
+            .serve_with_incoming_shutdown(
+                vt_issuer::listener::incoming(Arc::new(listener), None),
+                async {
+                    shutdown_signal.await.ok();
+                },
+            )
+            .await
+            .unwrap();
+    });
+
+    (address, shutdown, handle)
+}
+
+// Drives a token request from a client through the token service and the key manager
+// client, and verifies the token with the public key served by the key manager
+#[tokio::test]
+async fn issued_tokens_verify_with_the_key_manager_public_key() {
+    let certs = TestCerts::generate();
+    let test_key_manager = TestKeyManager::start(&certs).await;
+
+    let config: TokenIssuerConfig = parse_config(&format!(
+        "{}key_manager_endpoint: https://localhost:{}\nkey_manager_ca: {}\n\
+         key_manager_auth_cert: {}\nkey_manager_auth_key: {}\n",
+        TOKEN_ISSUER_CONFIG,
+        test_key_manager.address.port(),
+        certs.path("ca.pem"),
+        certs.path("token-issuer.pem"),
+        certs.path("token-issuer.key"),
+    ));
+
+    let key_manager = KeyManager::create(&config).await.unwrap();
+
+    let (issuer_address, issuer_shutdown, issuer_handle) =
+        start_token_issuer(&config, key_manager.clone()).await;
+
+    // The key the issuer signs with. Keys are not updated in the test, so the epoch stays
+    // the same even if the clock crosses an epoch boundary
+    let (epoch, material) = {
+        let key_manager = key_manager.read().await;
+        let key = key_manager.get_current_key().as_ref().unwrap();
+
+        (key.epoch.as_secs(), key.material.clone())
+    };
+
+    // Client
+    let mut rng = thread_rng();
+    let token_id = Fr::random(&mut rng);
+    let blinding = Fr::random(&mut rng);
+
+    let token_request = create_root_token_request(
+        &token_id,
+        &blinding,
+        &material.public_key,
+        &material.params,
+        &mut rng,
+    )
+    .unwrap();
+
+    let mut client = VeronymousTokenServiceClient::connect(format!("http://{}", issuer_address))
+        .await
+        .unwrap();
+
+    let response = client
+        .issue_token(TokenRequest {
+            token_request: token_request.serialize(),
+            previous_epoch: false,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(response.epoch, epoch);
+    assert_eq!(response.expires_at, epoch + KEY_LIFETIME);
+
+    // Public key of the epoch of the token, as served by the key manager
+    let key_profile = read_lock(test_key_manager.realms.default_realm())
+        .get_key_profile(response.epoch)
+        .unwrap();
+
+    let token_response = RootTokenResponse::deserialize(&response.token_response).unwrap();
+    let token = complete_root_token(
+        &token_response,
+        &token_id,
+        &blinding,
+        &key_profile.material.public_key,
+        &key_profile.material.params,
+    )
+    .unwrap();
+
+    assert!(token
+        .verify(&key_profile.material.public_key, &key_profile.material.params)
+        .unwrap());
+
+    issuer_shutdown.send(()).unwrap();
+    issuer_handle.await.unwrap();
+
+    test_key_manager.stop().await;
+}